@@ -1,4 +1,8 @@
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    ffi::{c_void, CString},
+    ptr::null,
+};
 
 pub use ffmpeg_sys as ffi;
 
@@ -27,6 +31,19 @@ impl Codec {
         }
     }
 
+    /// Sample formats supported by an audio codec, empty if unknown.
+    pub fn sample_formats(&self) -> SampleFormats {
+        SampleFormats {
+            raw: self.raw,
+            index: 0,
+        }
+    }
+
+    /// Whether this is an audio codec.
+    pub fn is_audio(&self) -> bool {
+        unsafe { (*self.raw).type_ == ffi::AVMediaType_AVMEDIA_TYPE_AUDIO }
+    }
+
     /// Retrieve supported hardware configurations for a codec.
     pub fn hw_configs(&self) -> HwConfigs {
         HwConfigs {
@@ -35,6 +52,32 @@ impl Codec {
         }
     }
 
+    /// Whether `name` is a private option of the codec or a generic `AVCodecContext` option.
+    ///
+    /// Names containing a NUL byte are not options.
+    pub fn has_option(&self, name: &str) -> bool {
+        let name = match CString::new(name) {
+            Ok(name) => name,
+            Err(_) => return false,
+        };
+
+        unsafe {
+            let classes = [(*self.raw).priv_class, ffi::avcodec_get_class()];
+            classes.iter().any(|class| {
+                // Without an object, options are searched in the class it points to
+                !class.is_null()
+                    && !ffi::av_opt_find(
+                        class as *const *const ffi::AVClass as *mut c_void,
+                        name.as_ptr(),
+                        null(),
+                        0,
+                        ffi::AV_OPT_SEARCH_FAKE_OBJ as _,
+                    )
+                    .is_null()
+            })
+        }
+    }
+
     pub fn name(&self) -> &'static str {
         unsafe { std::ffi::CStr::from_ptr((*self.raw).name).to_str().unwrap() }
     }
@@ -70,6 +113,33 @@ impl Iterator for PixelFormats {
     }
 }
 
+pub struct SampleFormats {
+    raw: *const ffi::AVCodec,
+    index: usize,
+}
+
+impl Iterator for SampleFormats {
+    type Item = ffi::AVSampleFormat;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            let formats = (*self.raw).sample_fmts;
+            if formats.is_null() {
+                return None;
+            }
+
+            let item = *formats.add(self.index);
+
+            if item == ffi::AVSampleFormat_AV_SAMPLE_FMT_NONE {
+                None
+            } else {
+                self.index += 1;
+                Some(item)
+            }
+        }
+    }
+}
+
 bitflags::bitflags! {
     /// Possible setup methods which can be used with a configuration.
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -111,16 +181,19 @@ impl Iterator for HwConfigs {
                 Some(HwConfig {
                     methods: HwCodecSetupMethod::from_bits_truncate((*item).methods as u32),
                     device_type: (*item).device_type,
+                    pix_fmt: (*item).pix_fmt,
                 })
             }
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct HwConfig {
     pub methods: HwCodecSetupMethod,
     pub device_type: ffi::AVHWDeviceType,
+    /// Hardware pixel format used with this configuration, e.g. `AV_PIX_FMT_CUDA`.
+    pub pix_fmt: ffi::AVPixelFormat,
 }
 
 impl HwConfig {
@@ -0,0 +1,115 @@
+use std::{
+    ffi::{CStr, CString},
+    ptr::{null, null_mut},
+};
+
+use crate::{error::check_error, error::Result, ffi};
+
+/// A wrapper around `AVDictionary`, used to pass options to FFmpeg.
+#[derive(Debug)]
+pub struct Dictionary {
+    raw: *mut ffi::AVDictionary,
+}
+
+impl Dictionary {
+    pub fn new() -> Self {
+        Self { raw: null_mut() }
+    }
+
+    /// Set `key` to `value`, overwriting any existing entry.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<&mut Self> {
+        let key = CString::new(key).unwrap();
+        let value = CString::new(value).unwrap();
+
+        unsafe {
+            check_error(ffi::av_dict_set(
+                &mut self.raw,
+                key.as_ptr(),
+                value.as_ptr(),
+                0,
+            ))?;
+        }
+
+        Ok(self)
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let key = CString::new(key).unwrap();
+
+        unsafe {
+            let entry = ffi::av_dict_get(self.raw, key.as_ptr(), null(), 0);
+            if entry.is_null() {
+                None
+            } else {
+                Some(
+                    CStr::from_ptr((*entry).value)
+                        .to_string_lossy()
+                        .into_owned(),
+                )
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        unsafe { ffi::av_dict_count(self.raw) as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copy all entries out of the dictionary.
+    pub fn entries(&self) -> Vec<(String, String)> {
+        let mut entries = Vec::with_capacity(self.len());
+        let empty = CString::default();
+
+        unsafe {
+            let mut entry: *const ffi::AVDictionaryEntry = null();
+            loop {
+                entry = ffi::av_dict_get(
+                    self.raw,
+                    empty.as_ptr(),
+                    entry,
+                    ffi::AV_DICT_IGNORE_SUFFIX as _,
+                );
+                if entry.is_null() {
+                    break;
+                }
+
+                entries.push((
+                    CStr::from_ptr((*entry).key).to_string_lossy().into_owned(),
+                    CStr::from_ptr((*entry).value)
+                        .to_string_lossy()
+                        .into_owned(),
+                ));
+            }
+        }
+
+        entries
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut *mut ffi::AVDictionary {
+        &mut self.raw
+    }
+}
+
+impl<'a> FromIterator<(&'a str, &'a str)> for Dictionary {
+    fn from_iter<T: IntoIterator<Item = (&'a str, &'a str)>>(iter: T) -> Self {
+        let mut dict = Dictionary::new();
+        for (key, value) in iter {
+            // Only fails on allocation failure.
+            dict.set(key, value).unwrap();
+        }
+        dict
+    }
+}
+
+impl Drop for Dictionary {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::av_dict_free(&mut self.raw);
+        }
+    }
+}
+
+unsafe impl Send for Dictionary {}
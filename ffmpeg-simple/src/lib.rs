@@ -1,6 +1,9 @@
 #![allow(clippy::new_without_default)]
 
-use std::ptr::{null, null_mut};
+use std::{
+    ffi::c_int,
+    ptr::{null, null_mut},
+};
 
 use error::check_error;
 pub use ffmpeg_sys as ffi;
@@ -11,12 +14,17 @@ use error::Result;
 pub mod codec;
 pub use codec::Codec;
 
-pub fn init_logging() {
-    unsafe {
-        ffi::av_log_set_callback(Some(ffi::av_log_default_callback));
-        ffi::av_log_set_level(ffi::AV_LOG_VERBOSE as i32);
-    }
-}
+pub mod scale;
+pub use scale::Scaler;
+
+pub mod dict;
+pub use dict::Dictionary;
+
+pub mod logging;
+pub use logging::init_logging;
+
+/// Samples per channel in the frames of audio encoders accepting any frame size.
+const DEFAULT_AUDIO_FRAME_SIZE: usize = 1024;
 
 pub struct Plane<'data> {
     data: &'data [u8],
@@ -55,9 +63,96 @@ pub struct Frame {
 }
 
 impl Frame {
+    /// Allocate a frame with buffers for the given size and pixel format.
+    pub fn new(width: u32, height: u32, format: ffi::AVPixelFormat) -> Result<Self> {
+        unsafe {
+            let mut frame = ffi::av_frame_alloc();
+            if frame.is_null() {
+                return Err(error::FfmpegError::Other("Failed to allocate frame".into()));
+            }
+
+            (*frame).width = width as _;
+            (*frame).height = height as _;
+            (*frame).format = format;
+
+            if let Err(e) = check_error(ffi::av_frame_get_buffer(frame, 0)) {
+                ffi::av_frame_free(&mut frame);
+                return Err(e);
+            }
+
+            Ok(Self::from_raw(frame))
+        }
+    }
+
+    /// Allocate an audio frame with buffers for `nb_samples` samples per channel.
+    ///
+    /// The channels use the default layout for their count, e.g. FL, FR, FC, LFE, BL, BR for 5.1.
+    pub fn new_audio(
+        nb_samples: usize,
+        format: ffi::AVSampleFormat,
+        channels: u32,
+        sample_rate: u32,
+    ) -> Result<Self> {
+        unsafe {
+            let mut frame = ffi::av_frame_alloc();
+            if frame.is_null() {
+                return Err(error::FfmpegError::Other("Failed to allocate frame".into()));
+            }
+
+            (*frame).nb_samples = nb_samples as _;
+            (*frame).format = format;
+            (*frame).sample_rate = sample_rate as _;
+            ffi::av_channel_layout_default(&mut (*frame).ch_layout, channels as _);
+
+            if let Err(e) = check_error(ffi::av_frame_get_buffer(frame, 0)) {
+                ffi::av_frame_free(&mut frame);
+                return Err(e);
+            }
+
+            Ok(Self::from_raw(frame))
+        }
+    }
+
+    /// Wrap an allocated frame, taking ownership of it.
+    unsafe fn from_raw(frame: *mut ffi::AVFrame) -> Self {
+        let mut line_sizes = [0; 4];
+        let mut plane_sizes = [0; 4];
+
+        for (i, line_size) in line_sizes.iter_mut().enumerate() {
+            *line_size = (*frame).linesize[i] as usize;
+        }
+
+        if (*frame).nb_samples > 0 {
+            // Only the first line size is set for audio, all planes have the same size
+            for (i, plane_size) in plane_sizes.iter_mut().enumerate() {
+                if !(*frame).data[i].is_null() {
+                    *plane_size = line_sizes[0];
+                }
+            }
+        } else {
+            ffi::av_image_fill_plane_sizes(
+                plane_sizes.as_mut_ptr(),
+                (*frame).format,
+                (*frame).height,
+                line_sizes.as_ptr() as *const _,
+            );
+        }
+
+        Frame {
+            raw: frame,
+            line_sizes,
+            plane_sizes,
+        }
+    }
+
+    /// Planes of a frame in system memory, or all `None` for hardware frames.
     pub fn planes(&self) -> [Option<Plane>; 4] {
         let mut planes = [None, None, None, None];
 
+        if self.is_hw() {
+            return planes;
+        }
+
         for (i, plane) in planes.iter_mut().enumerate() {
             unsafe {
                 if !(*self.raw).data[i].is_null() {
@@ -75,6 +170,10 @@ impl Frame {
     pub fn planes_mut(&mut self) -> [Option<PlaneMut>; 4] {
         let mut planes = [None, None, None, None];
 
+        if self.is_hw() {
+            return planes;
+        }
+
         for (i, plane) in planes.iter_mut().enumerate() {
             unsafe {
                 if !(*self.raw).data[i].is_null() {
@@ -108,6 +207,89 @@ impl Frame {
         unsafe { (*self.raw).width as usize }
     }
 
+    pub fn format(&self) -> ffi::AVPixelFormat {
+        unsafe { (*self.raw).format }
+    }
+
+    /// Samples per channel of an audio frame.
+    pub fn nb_samples(&self) -> usize {
+        unsafe { (*self.raw).nb_samples as usize }
+    }
+
+    /// Samples of an audio frame, in one buffer per channel for planar formats,
+    /// or in a single buffer of interleaved samples.
+    ///
+    /// Unlike `planes_mut`, this covers every channel of planar formats.
+    pub fn audio_planes_mut(&mut self) -> Vec<&mut [u8]> {
+        unsafe {
+            let raw = &*self.raw;
+            let channels = raw.ch_layout.nb_channels as usize;
+            let (count, plane_channels) = if ffi::av_sample_fmt_is_planar(raw.format) != 0 {
+                (channels, 1)
+            } else {
+                (1, channels)
+            };
+            let size = raw.nb_samples as usize
+                * plane_channels
+                * ffi::av_get_bytes_per_sample(raw.format) as usize;
+
+            (0..count)
+                .map(|i| std::slice::from_raw_parts_mut(*raw.extended_data.add(i), size))
+                .collect()
+        }
+    }
+
+    /// Whether the frame data lives in a hardware frames context.
+    pub fn is_hw(&self) -> bool {
+        unsafe { !(*self.raw).hw_frames_ctx.is_null() }
+    }
+
+    /// Copy an image stored in raw planes into this frame, without any conversion.
+    ///
+    /// `src` and `src_strides` contain one entry per plane of the frame format.
+    pub fn copy_from_planes(&mut self, src: &[&[u8]], src_strides: &[usize]) -> Result<()> {
+        if self.is_hw() {
+            return Err(error::FfmpegError::Other(
+                "Cannot copy into a hardware frame, use `transfer_from`".into(),
+            ));
+        }
+
+        let spec = scale::ImageSpec::new(self.width() as _, self.height() as _, self.format());
+        let (src_ptrs, src_linesizes) = scale::check_planes(spec, src, src_strides)?;
+
+        unsafe {
+            ffi::av_image_copy(
+                (*self.raw).data.as_mut_ptr(),
+                (*self.raw).linesize.as_mut_ptr(),
+                src_ptrs.as_ptr() as _,
+                src_linesizes.as_ptr(),
+                self.format(),
+                self.width() as _,
+                self.height() as _,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Ensure the frame data is not shared with anyone else (e.g. an encoder
+    /// still holding a reference), copying it if necessary.
+    pub fn make_writable(&mut self) -> Result<()> {
+        unsafe {
+            check_error(ffi::av_frame_make_writable(self.raw))?;
+        }
+        Ok(())
+    }
+
+    /// Upload `src` into this hardware frame, or download `src` into this
+    /// system memory frame.
+    pub fn transfer_from(&mut self, src: &Frame) -> Result<()> {
+        unsafe {
+            check_error(ffi::av_hwframe_transfer_data(self.raw, src.raw, 0))?;
+        }
+        Ok(())
+    }
+
     pub fn as_ptr(&self) -> *const ffi::AVFrame {
         self.raw
     }
@@ -125,16 +307,66 @@ impl Drop for Frame {
     }
 }
 
+// Frame buffers are reference counted atomically, so frames can be handed over to other threads.
+unsafe impl Send for Frame {}
+
 #[derive(Debug)]
 pub struct Packet {
     raw: *mut ffi::AVPacket,
 }
 
 impl Packet {
+    /// Presentation timestamp, in the codec time base.
     pub fn pts(&self) -> i64 {
         unsafe { (*self.raw).pts }
     }
 
+    /// Decompression timestamp, in the codec time base.
+    pub fn dts(&self) -> i64 {
+        unsafe { (*self.raw).dts }
+    }
+
+    /// Duration of this packet, in the codec time base, or 0 if unknown.
+    pub fn duration(&self) -> i64 {
+        unsafe { (*self.raw).duration }
+    }
+
+    /// Whether the packet contains a keyframe.
+    pub fn is_keyframe(&self) -> bool {
+        unsafe { (*self.raw).flags & ffi::AV_PKT_FLAG_KEY as c_int != 0 }
+    }
+
+    /// Retrieve the side data of the given type, if present.
+    pub fn side_data(&self, type_: ffi::AVPacketSideDataType) -> Option<&[u8]> {
+        unsafe {
+            let mut size = 0;
+            let data = ffi::av_packet_get_side_data(self.raw, type_, &mut size);
+
+            if data.is_null() {
+                None
+            } else {
+                Some(std::slice::from_raw_parts(data, size))
+            }
+        }
+    }
+
+    /// Iterate over all side data attached to the packet.
+    pub fn side_data_iter(&self) -> impl Iterator<Item = PacketSideData<'_>> {
+        let side_data = unsafe {
+            let raw = &*self.raw;
+            if raw.side_data.is_null() {
+                &[]
+            } else {
+                std::slice::from_raw_parts(raw.side_data, raw.side_data_elems as usize)
+            }
+        };
+
+        side_data.iter().map(|item| PacketSideData {
+            type_: item.type_,
+            data: unsafe { std::slice::from_raw_parts(item.data, item.size) },
+        })
+    }
+
     pub fn data(&self) -> Option<&[u8]> {
         unsafe {
             if (*self.raw).data.is_null() {
@@ -162,6 +394,12 @@ impl Packet {
     }
 }
 
+#[derive(Debug)]
+pub struct PacketSideData<'data> {
+    pub type_: ffi::AVPacketSideDataType,
+    pub data: &'data [u8],
+}
+
 impl Drop for Packet {
     fn drop(&mut self) {
         unsafe {
@@ -189,6 +427,38 @@ impl HwDeviceContext {
             Ok(Self { raw })
         }
     }
+
+    /// Software pixel formats that frames on this device can be created with.
+    pub fn valid_sw_formats(&self) -> Vec<ffi::AVPixelFormat> {
+        let mut formats = vec![];
+
+        unsafe {
+            let mut constraints = ffi::av_hwdevice_get_hwframe_constraints(self.raw, null());
+            if constraints.is_null() {
+                return formats;
+            }
+
+            let mut format = (*constraints).valid_sw_formats;
+            if !format.is_null() {
+                while *format != ffi::AVPixelFormat_AV_PIX_FMT_NONE {
+                    formats.push(*format);
+                    format = format.add(1);
+                }
+            }
+
+            ffi::av_hwframe_constraints_free(&mut constraints);
+        }
+
+        formats
+    }
+}
+
+impl Clone for HwDeviceContext {
+    fn clone(&self) -> Self {
+        Self {
+            raw: unsafe { ffi::av_buffer_ref(self.raw) },
+        }
+    }
 }
 
 impl Drop for HwDeviceContext {
@@ -199,9 +469,97 @@ impl Drop for HwDeviceContext {
     }
 }
 
+/// A pool of frames allocated on a hardware device.
+#[derive(Debug)]
+pub struct HwFramesContext {
+    raw: *mut ffi::AVBufferRef,
+}
+
+impl HwFramesContext {
+    /// Create a pool of `hw_format` frames on `device`, uploaded from `sw_format`.
+    ///
+    /// `hw_format` is the pixel format of the device, e.g. `AV_PIX_FMT_CUDA`,
+    /// see [`codec::HwConfig::pix_fmt`].
+    pub fn new(
+        device: &HwDeviceContext,
+        hw_format: ffi::AVPixelFormat,
+        sw_format: ffi::AVPixelFormat,
+        width: u32,
+        height: u32,
+        initial_pool_size: u32,
+    ) -> Result<Self> {
+        unsafe {
+            let raw = ffi::av_hwframe_ctx_alloc(device.raw);
+            if raw.is_null() {
+                return Err(error::FfmpegError::Other(
+                    "Failed to allocate hardware frames context".into(),
+                ));
+            }
+            let ctx = Self { raw };
+
+            let frames = (*raw).data as *mut ffi::AVHWFramesContext;
+            (*frames).format = hw_format;
+            (*frames).sw_format = sw_format;
+            (*frames).width = width as _;
+            (*frames).height = height as _;
+            (*frames).initial_pool_size = initial_pool_size as _;
+
+            check_error(ffi::av_hwframe_ctx_init(raw))?;
+
+            Ok(ctx)
+        }
+    }
+
+    fn frames(&self) -> &ffi::AVHWFramesContext {
+        unsafe { &*((*self.raw).data as *const ffi::AVHWFramesContext) }
+    }
+
+    pub fn hw_format(&self) -> ffi::AVPixelFormat {
+        self.frames().format
+    }
+
+    pub fn sw_format(&self) -> ffi::AVPixelFormat {
+        self.frames().sw_format
+    }
+
+    pub fn width(&self) -> u32 {
+        self.frames().width as u32
+    }
+
+    pub fn height(&self) -> u32 {
+        self.frames().height as u32
+    }
+
+    /// Take a frame from the pool.
+    pub fn get_buffer(&self) -> Result<Frame> {
+        unsafe {
+            let mut frame = ffi::av_frame_alloc();
+            if frame.is_null() {
+                return Err(error::FfmpegError::Other("Failed to allocate frame".into()));
+            }
+
+            if let Err(e) = check_error(ffi::av_hwframe_get_buffer(self.raw, frame, 0)) {
+                ffi::av_frame_free(&mut frame);
+                return Err(e);
+            }
+
+            Ok(Frame::from_raw(frame))
+        }
+    }
+}
+
+impl Drop for HwFramesContext {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::av_buffer_unref(&mut self.raw);
+        }
+    }
+}
+
 pub struct CodecContext {
     raw: *mut ffi::AVCodecContext,
     hw_device_ctx: Option<HwDeviceContext>,
+    hw_frames_ctx: Option<HwFramesContext>,
 }
 
 impl CodecContext {
@@ -209,6 +567,7 @@ impl CodecContext {
         CodecContext {
             raw: unsafe { ffi::avcodec_alloc_context3(codec.raw) },
             hw_device_ctx: None,
+            hw_frames_ctx: None,
         }
     }
 
@@ -220,6 +579,22 @@ impl CodecContext {
         self
     }
 
+    /// Encode frames from a hardware frames context.
+    ///
+    /// This also sets the pixel format to the hardware format. Frames returned by
+    /// [`OpenedCodecContext::request_frame`] are then in the software format of the
+    /// pool and uploaded to the device in [`OpenedCodecContext::send_frame`], leaving
+    /// any colour conversion to the device.
+    pub fn set_hw_frames_ctx(&mut self, hw_frames_ctx: HwFramesContext) -> &mut Self {
+        unsafe {
+            (*self.raw).hw_frames_ctx = ffi::av_buffer_ref(hw_frames_ctx.raw);
+            (*self.raw).pix_fmt = hw_frames_ctx.hw_format();
+            (*self.raw).sw_pix_fmt = hw_frames_ctx.sw_format();
+        }
+        self.hw_frames_ctx = Some(hw_frames_ctx);
+        self
+    }
+
     pub fn set_size(&mut self, width: u32, height: u32) -> &mut Self {
         unsafe {
             (*self.raw).width = width as _;
@@ -258,6 +633,46 @@ impl CodecContext {
         self
     }
 
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> &mut Self {
+        unsafe {
+            (*self.raw).sample_rate = sample_rate as _;
+        }
+        self
+    }
+
+    /// Use the default channel layout for `channels` channels, see `Frame::new_audio`.
+    pub fn set_channels(&mut self, channels: u32) -> &mut Self {
+        unsafe {
+            ffi::av_channel_layout_uninit(&mut (*self.raw).ch_layout);
+            ffi::av_channel_layout_default(&mut (*self.raw).ch_layout, channels as _);
+        }
+        self
+    }
+
+    pub fn set_sample_fmt(&mut self, sample_fmt: ffi::AVSampleFormat) -> &mut Self {
+        unsafe {
+            (*self.raw).sample_fmt = sample_fmt;
+        }
+        self
+    }
+
+    /// Average bits per second.
+    pub fn set_bit_rate(&mut self, bit_rate: u32) -> &mut Self {
+        unsafe {
+            (*self.raw).bit_rate = bit_rate as _;
+        }
+        self
+    }
+
+    /// Put the global headers of the stream in [`OpenedCodecContext::extradata`],
+    /// e.g. the `AudioSpecificConfig` of AAC, instead of repeating them in packets.
+    pub fn set_global_header(&mut self) -> &mut Self {
+        unsafe {
+            (*self.raw).flags |= ffi::AV_CODEC_FLAG_GLOBAL_HEADER as c_int;
+        }
+        self
+    }
+
     pub fn set_option(&mut self, key: &str, value: &str) -> Result<&mut Self> {
         let key = std::ffi::CString::new(key).unwrap();
         let value = std::ffi::CString::new(value).unwrap();
@@ -275,41 +690,78 @@ impl CodecContext {
     }
 
     pub fn open(self) -> error::Result<OpenedCodecContext> {
+        self.open_with_options(Dictionary::new())
+            .map(|(opened, _)| opened)
+    }
+
+    /// Open the codec with the given options, which may contain both generic
+    /// `AVCodecContext` and codec private options.
+    ///
+    /// Returns the options that were not recognized by the codec.
+    pub fn open_with_options(
+        self,
+        mut options: Dictionary,
+    ) -> error::Result<(OpenedCodecContext, Dictionary)> {
         unsafe {
-            check_error(ffi::avcodec_open2(self.raw, std::ptr::null(), null_mut()))?;
-
-            let frame = ffi::av_frame_alloc();
-            (*frame).width = (*self.raw).width;
-            (*frame).height = (*self.raw).height;
-            (*frame).format = (*self.raw).pix_fmt;
-
-            check_error(ffi::av_frame_get_buffer(frame, 0))?;
-
-            let mut line_sizes = [0; 4];
-            let mut plane_sizes = [0; 4];
-
-            for (i, line_size) in line_sizes.iter_mut().enumerate() {
-                *line_size = (*frame).linesize[i] as usize;
-            }
+            check_error(ffi::avcodec_open2(
+                self.raw,
+                std::ptr::null(),
+                options.as_mut_ptr(),
+            ))?;
 
-            ffi::av_image_fill_plane_sizes(
-                plane_sizes.as_mut_ptr(),
-                (*frame).format,
-                (*frame).height,
-                line_sizes.as_ptr() as *const _,
-            );
+            let codec = Codec {
+                raw: (*self.raw).codec,
+            };
+
+            // With a frames context, video frames are filled in system memory and uploaded later.
+            let (frame, hw_frame) = match self.hw_frames_ctx.as_ref() {
+                _ if codec.is_audio() => {
+                    // Encoders with a fixed frame size require it for all frames but the last
+                    let frame_size = match (*self.raw).frame_size {
+                        0 => DEFAULT_AUDIO_FRAME_SIZE,
+                        frame_size => frame_size as usize,
+                    };
+                    (
+                        Frame::new_audio(
+                            frame_size,
+                            (*self.raw).sample_fmt,
+                            (*self.raw).ch_layout.nb_channels as _,
+                            (*self.raw).sample_rate as _,
+                        )?,
+                        None,
+                    )
+                }
+                Some(hw_frames_ctx) => (
+                    Frame::new(
+                        (*self.raw).width as _,
+                        (*self.raw).height as _,
+                        hw_frames_ctx.sw_format(),
+                    )?,
+                    Some(hw_frames_ctx.get_buffer()?),
+                ),
+                None => (
+                    Frame::new(
+                        (*self.raw).width as _,
+                        (*self.raw).height as _,
+                        (*self.raw).pix_fmt,
+                    )?,
+                    None,
+                ),
+            };
 
             let packet = ffi::av_packet_alloc();
 
-            Ok(OpenedCodecContext {
-                inner: self,
-                frame: Frame {
-                    raw: frame,
-                    line_sizes,
-                    plane_sizes,
+            Ok((
+                OpenedCodecContext {
+                    inner: self,
+                    frame,
+                    hw_frame,
+                    packet: Packet { raw: packet },
+                    eof_sent: false,
+                    force_keyframe: false,
                 },
-                packet: Packet { raw: packet },
-            })
+                options,
+            ))
         }
     }
 }
@@ -325,7 +777,13 @@ impl Drop for CodecContext {
 pub struct OpenedCodecContext {
     inner: CodecContext,
     frame: Frame,
+    /// Device frame the software frame is uploaded to, if a frames context is used.
+    hw_frame: Option<Frame>,
     packet: Packet,
+    /// Whether the encoder is draining.
+    eof_sent: bool,
+    /// Whether the next frame should be encoded as a keyframe.
+    force_keyframe: bool,
 }
 
 impl OpenedCodecContext {
@@ -337,20 +795,171 @@ impl OpenedCodecContext {
     }
 
     pub fn request_frame(&mut self) -> Result<&mut Frame> {
-        unsafe {
-            check_error(ffi::av_frame_make_writable(self.frame.raw))?;
-        }
+        self.frame.make_writable()?;
         Ok(&mut self.frame)
     }
 
+    /// Send the frame returned by `request_frame` to the encoder.
     pub fn send_frame(&mut self, pts: i64) -> Result<()> {
+        let force_keyframe = std::mem::take(&mut self.force_keyframe);
+        Self::send(
+            &self.inner,
+            self.hw_frame.as_mut(),
+            &mut self.frame,
+            pts,
+            force_keyframe,
+        )
+    }
+
+    /// Encode the next frame sent as an intra frame.
+    ///
+    /// Depending on the encoder options, this produces an IDR frame or starts
+    /// an intra refresh cycle (e.g. `libx264` with `intra-refresh`).
+    pub fn request_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+
+    /// Send a frame allocated by the caller, in the format returned by `input_format`.
+    ///
+    /// The encoder may keep a reference to the frame data, so the frame must be
+    /// made writable with `Frame::make_writable` before it is written to again.
+    pub fn send_frame_from(&mut self, frame: &mut Frame, pts: i64) -> Result<()> {
+        if frame.format() != self.frame.format()
+            || frame.width() != self.frame.width()
+            || frame.height() != self.frame.height()
+        {
+            return Err(error::FfmpegError::Other(format!(
+                "Frame ({}x{}, format {}) does not match the encoder input",
+                frame.width(),
+                frame.height(),
+                frame.format()
+            )));
+        }
+
+        let force_keyframe = std::mem::take(&mut self.force_keyframe);
+        Self::send(
+            &self.inner,
+            self.hw_frame.as_mut(),
+            frame,
+            pts,
+            force_keyframe,
+        )
+    }
+
+    fn send(
+        inner: &CodecContext,
+        hw_frame: Option<&mut Frame>,
+        frame: &mut Frame,
+        pts: i64,
+        force_keyframe: bool,
+    ) -> Result<()> {
+        let pict_type = if force_keyframe {
+            ffi::AVPictureType_AV_PICTURE_TYPE_I
+        } else {
+            // Frames are reused, so the type has to be reset explicitly.
+            ffi::AVPictureType_AV_PICTURE_TYPE_NONE
+        };
+
+        unsafe {
+            (*frame.raw).pts = pts;
+            (*frame.raw).pict_type = pict_type;
+
+            match (hw_frame, inner.hw_frames_ctx.as_ref()) {
+                (Some(hw_frame), Some(hw_frames_ctx)) => {
+                    // The encoder may still reference the previous surface, take a fresh one.
+                    ffi::av_frame_unref(hw_frame.raw);
+                    check_error(ffi::av_hwframe_get_buffer(
+                        hw_frames_ctx.raw,
+                        hw_frame.raw,
+                        0,
+                    ))?;
+                    hw_frame.transfer_from(frame)?;
+                    (*hw_frame.raw).pts = pts;
+                    (*hw_frame.raw).pict_type = pict_type;
+
+                    check_error(ffi::avcodec_send_frame(inner.raw, hw_frame.raw))?;
+                }
+                _ => {
+                    check_error(ffi::avcodec_send_frame(inner.raw, frame.raw))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Pixel format of the frames returned by `request_frame`.
+    pub fn input_format(&self) -> ffi::AVPixelFormat {
+        self.frame.format()
+    }
+
+    /// Samples per channel of the frames returned by `request_frame`, for audio encoders.
+    pub fn frame_size(&self) -> usize {
+        self.frame.nb_samples()
+    }
+
+    /// Samples the decoder has to skip at the start of an audio stream.
+    pub fn initial_padding(&self) -> u32 {
+        unsafe { (*self.inner.raw).initial_padding as u32 }
+    }
+
+    /// Global headers of the stream, see [`CodecContext::set_global_header`].
+    pub fn extradata(&self) -> &[u8] {
         unsafe {
-            (*self.frame.raw).pts = pts;
-            check_error(ffi::avcodec_send_frame(self.inner.raw, self.frame.raw))?;
+            let raw = &*self.inner.raw;
+            if raw.extradata.is_null() {
+                &[]
+            } else {
+                std::slice::from_raw_parts(raw.extradata, raw.extradata_size as usize)
+            }
         }
+    }
+
+    /// Signal the end of stream to the encoder.
+    ///
+    /// After this, `receive_packet` returns the remaining delayed packets
+    /// followed by `None`, and no more frames can be sent until `flush` is called.
+    pub fn send_eof(&mut self) -> Result<()> {
+        if self.eof_sent {
+            return Ok(());
+        }
+
+        unsafe {
+            check_error(ffi::avcodec_send_frame(self.inner.raw, null()))?;
+        }
+        self.eof_sent = true;
+        Ok(())
+    }
+
+    /// Drain all delayed packets, passing them to `on_packet`, and reset the encoder.
+    ///
+    /// If the encoder does not support being flushed (`AV_CODEC_CAP_ENCODER_FLUSH`),
+    /// it stays in the draining state and has to be reopened to encode more frames.
+    pub fn flush<F>(&mut self, mut on_packet: F) -> Result<()>
+    where
+        F: FnMut(&mut Packet) -> Result<()>,
+    {
+        self.send_eof()?;
+
+        while let Some(packet) = self.receive_packet()? {
+            on_packet(packet)?;
+        }
+
+        unsafe {
+            let codec = (*self.inner.raw).codec;
+            if (*codec).capabilities & ffi::AV_CODEC_CAP_ENCODER_FLUSH as c_int != 0 {
+                ffi::avcodec_flush_buffers(self.inner.raw);
+                self.eof_sent = false;
+            }
+        }
+
         Ok(())
     }
 
+    /// Whether `send_eof` has been called and the encoder has not been reset since.
+    pub fn is_draining(&self) -> bool {
+        self.eof_sent
+    }
+
     pub fn receive_packet(&mut self) -> Result<Option<&mut Packet>> {
         unsafe {
             // This always calls `unref` before doing anything.
@@ -0,0 +1,156 @@
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CStr},
+};
+
+use tracing::{level_filters::LevelFilter, Level};
+
+use crate::ffi;
+
+/// Target of all events emitted for FFmpeg log messages, usable in filters.
+pub const LOG_TARGET: &str = "ffmpeg";
+
+const LINE_SIZE: usize = 1024;
+
+thread_local! {
+    /// Message fragments logged without a trailing newline.
+    static PENDING_LINE: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// Route FFmpeg log messages into `tracing`.
+///
+/// The FFmpeg log level is derived from the currently active `tracing` filter,
+/// so this should be called after the subscriber has been installed.
+pub fn init_logging() {
+    let level = match LevelFilter::current().into_level() {
+        Some(level) => level_to_av(level),
+        None => ffi::AV_LOG_QUIET,
+    };
+
+    unsafe {
+        ffi::av_log_set_callback(Some(log_callback));
+        ffi::av_log_set_level(level);
+    }
+}
+
+fn map_level(level: c_int) -> Option<Level> {
+    if level <= ffi::AV_LOG_QUIET {
+        None
+    } else if level <= ffi::AV_LOG_ERROR as c_int {
+        Some(Level::ERROR)
+    } else if level <= ffi::AV_LOG_WARNING as c_int {
+        Some(Level::WARN)
+    } else if level <= ffi::AV_LOG_INFO as c_int {
+        Some(Level::INFO)
+    } else if level <= ffi::AV_LOG_VERBOSE as c_int {
+        Some(Level::DEBUG)
+    } else {
+        Some(Level::TRACE)
+    }
+}
+
+fn is_enabled(level: Level) -> bool {
+    match level {
+        Level::ERROR => tracing::enabled!(target: LOG_TARGET, Level::ERROR),
+        Level::WARN => tracing::enabled!(target: LOG_TARGET, Level::WARN),
+        Level::INFO => tracing::enabled!(target: LOG_TARGET, Level::INFO),
+        Level::DEBUG => tracing::enabled!(target: LOG_TARGET, Level::DEBUG),
+        Level::TRACE => tracing::enabled!(target: LOG_TARGET, Level::TRACE),
+    }
+}
+
+fn emit(level: Level, context: &str, message: &str) {
+    match level {
+        Level::ERROR => tracing::error!(target: LOG_TARGET, context, "{}", message),
+        Level::WARN => tracing::warn!(target: LOG_TARGET, context, "{}", message),
+        Level::INFO => tracing::info!(target: LOG_TARGET, context, "{}", message),
+        Level::DEBUG => tracing::debug!(target: LOG_TARGET, context, "{}", message),
+        Level::TRACE => tracing::trace!(target: LOG_TARGET, context, "{}", message),
+    }
+}
+
+/// Name of the logging context, e.g. `h264_nvenc` for a codec context.
+///
+/// `ptr` is either null or a pointer to a struct whose first member is an `AVClass` pointer.
+unsafe fn context_name(ptr: *mut c_void) -> Option<String> {
+    if ptr.is_null() {
+        return None;
+    }
+
+    let class = *(ptr as *const *const ffi::AVClass);
+    if class.is_null() {
+        return None;
+    }
+
+    let name = match (*class).item_name {
+        Some(item_name) => item_name(ptr),
+        None => (*class).class_name,
+    };
+
+    if name.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(name).to_string_lossy().into_owned())
+    }
+}
+
+unsafe extern "C" fn log_callback(
+    ptr: *mut c_void,
+    av_level: c_int,
+    fmt: *const c_char,
+    vl: ffi::va_list,
+) {
+    let level = match map_level(av_level) {
+        Some(level) if is_enabled(level) => level,
+        _ => return,
+    };
+
+    let mut line: [c_char; LINE_SIZE] = [0; LINE_SIZE];
+    // The prefix is replaced by the `context` field.
+    let mut print_prefix = 0;
+    let ret = ffi::av_log_format_line2(
+        ptr,
+        av_level,
+        fmt,
+        vl,
+        line.as_mut_ptr(),
+        LINE_SIZE as c_int,
+        &mut print_prefix,
+    );
+    if ret < 0 {
+        return;
+    }
+
+    let fragment = CStr::from_ptr(line.as_ptr()).to_string_lossy();
+
+    // FFmpeg sometimes builds a single line out of multiple calls.
+    let message = PENDING_LINE.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        pending.push_str(&fragment);
+
+        if pending.ends_with('\n') {
+            let message = pending.trim_end().to_owned();
+            pending.clear();
+            Some(message)
+        } else {
+            None
+        }
+    });
+
+    if let Some(message) = message {
+        if !message.is_empty() {
+            let context = context_name(ptr).unwrap_or_default();
+            emit(level, &context, &message);
+        }
+    }
+}
+
+fn level_to_av(level: Level) -> c_int {
+    match level {
+        Level::ERROR => ffi::AV_LOG_ERROR as c_int,
+        Level::WARN => ffi::AV_LOG_WARNING as c_int,
+        Level::INFO => ffi::AV_LOG_INFO as c_int,
+        Level::DEBUG => ffi::AV_LOG_VERBOSE as c_int,
+        Level::TRACE => ffi::AV_LOG_TRACE as c_int,
+    }
+}
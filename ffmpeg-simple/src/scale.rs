@@ -0,0 +1,338 @@
+use std::{
+    ffi::c_int,
+    ptr::{null, null_mut},
+};
+
+use crate::{
+    error::{check_error, FfmpegError, Result},
+    ffi, Frame,
+};
+
+/// Dimensions and pixel format of one side of a conversion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageSpec {
+    pub width: u32,
+    pub height: u32,
+    pub format: ffi::AVPixelFormat,
+}
+
+impl ImageSpec {
+    pub fn new(width: u32, height: u32, format: ffi::AVPixelFormat) -> Self {
+        Self {
+            width,
+            height,
+            format,
+        }
+    }
+
+    /// Size in bytes of each plane of an image with this spec and the given strides.
+    fn plane_sizes(&self, strides: &[usize; 4]) -> Result<[usize; 4]> {
+        let mut plane_sizes = [0usize; 4];
+        unsafe {
+            check_error(ffi::av_image_fill_plane_sizes(
+                plane_sizes.as_mut_ptr(),
+                self.format,
+                self.height as _,
+                strides.as_ptr() as *const _,
+            ))?;
+        }
+        Ok(plane_sizes)
+    }
+}
+
+/// Scaling algorithm, only relevant when the source and destination sizes differ
+/// or when chroma has to be resampled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScaleAlgorithm {
+    FastBilinear = ffi::SWS_FAST_BILINEAR as isize,
+    Bilinear = ffi::SWS_BILINEAR as isize,
+    Bicubic = ffi::SWS_BICUBIC as isize,
+    /// Nearest neighbor.
+    Point = ffi::SWS_POINT as isize,
+    Area = ffi::SWS_AREA as isize,
+    Lanczos = ffi::SWS_LANCZOS as isize,
+    Spline = ffi::SWS_SPLINE as isize,
+}
+
+bitflags::bitflags! {
+    /// Additional flags passed to `libswscale` alongside the algorithm.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ScaleFlags: u32 {
+        /// Use more accurate (and slower) rounding.
+        const AccurateRnd = ffi::SWS_ACCURATE_RND as _;
+        /// Produce bit-exact output across platforms.
+        const BitExact = ffi::SWS_BITEXACT as _;
+        /// Perform full chroma interpolation when upsampling chroma.
+        const FullChrHInt = ffi::SWS_FULL_CHR_H_INT as _;
+        /// Perform full chroma interpolation when reading the input.
+        const FullChrHInp = ffi::SWS_FULL_CHR_H_INP as _;
+    }
+}
+
+/// YUV colour matrix used to convert from/to RGB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMatrix {
+    Bt601 = ffi::SWS_CS_ITU601 as isize,
+    Bt709 = ffi::SWS_CS_ITU709 as isize,
+    Bt2020 = ffi::SWS_CS_BT2020 as isize,
+    Fcc = ffi::SWS_CS_FCC as isize,
+    Smpte240m = ffi::SWS_CS_SMPTE240M as isize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorRange {
+    /// Limited (TV/MPEG) range, 16-235 for 8-bit luma.
+    Limited = 0,
+    /// Full (PC/JPEG) range, 0-255 for 8-bit luma.
+    Full = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Colorspace {
+    src_matrix: ColorMatrix,
+    src_range: ColorRange,
+    dst_matrix: ColorMatrix,
+    dst_range: ColorRange,
+}
+
+/// A wrapper around `SwsContext`, converting images between sizes and pixel formats.
+///
+/// The underlying context is cached and only re-created when the configuration changes.
+pub struct Scaler {
+    raw: *mut ffi::SwsContext,
+    src: Option<ImageSpec>,
+    dst: Option<ImageSpec>,
+    colorspace: Option<Colorspace>,
+}
+
+impl Scaler {
+    pub fn new() -> Self {
+        Self {
+            raw: null_mut(),
+            src: None,
+            dst: None,
+            colorspace: None,
+        }
+    }
+
+    /// Configure the conversion, reusing the existing context if nothing has changed.
+    pub fn configure(
+        &mut self,
+        src: ImageSpec,
+        dst: ImageSpec,
+        algorithm: ScaleAlgorithm,
+        flags: ScaleFlags,
+    ) -> Result<&mut Self> {
+        let raw = unsafe {
+            ffi::sws_getCachedContext(
+                self.raw,
+                src.width as _,
+                src.height as _,
+                src.format,
+                dst.width as _,
+                dst.height as _,
+                dst.format,
+                (algorithm as u32 | flags.bits()) as c_int,
+                null_mut(),
+                null_mut(),
+                null(),
+            )
+        };
+
+        if raw.is_null() {
+            // `sws_getCachedContext` frees the old context on failure.
+            self.raw = null_mut();
+            self.src = None;
+            self.dst = None;
+            return Err(FfmpegError::Other(format!(
+                "Unsupported conversion from {:?} to {:?}",
+                src, dst
+            )));
+        }
+
+        let changed = raw != self.raw;
+        self.raw = raw;
+        self.src = Some(src);
+        self.dst = Some(dst);
+
+        if changed {
+            if let Some(colorspace) = self.colorspace {
+                self.apply_colorspace(colorspace)?;
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Set the colour matrices and ranges of the source and destination.
+    ///
+    /// Only the YUV side(s) of a conversion are affected, so for RGB to YUV
+    /// conversions the `dst_*` parameters are the relevant ones.
+    pub fn set_colorspace(
+        &mut self,
+        src_matrix: ColorMatrix,
+        src_range: ColorRange,
+        dst_matrix: ColorMatrix,
+        dst_range: ColorRange,
+    ) -> Result<&mut Self> {
+        let colorspace = Colorspace {
+            src_matrix,
+            src_range,
+            dst_matrix,
+            dst_range,
+        };
+        self.colorspace = Some(colorspace);
+
+        if !self.raw.is_null() {
+            self.apply_colorspace(colorspace)?;
+        }
+
+        Ok(self)
+    }
+
+    fn apply_colorspace(&mut self, colorspace: Colorspace) -> Result<()> {
+        unsafe {
+            let ret = ffi::sws_setColorspaceDetails(
+                self.raw,
+                ffi::sws_getCoefficients(colorspace.src_matrix as c_int),
+                colorspace.src_range as c_int,
+                ffi::sws_getCoefficients(colorspace.dst_matrix as c_int),
+                colorspace.dst_range as c_int,
+                0,
+                1 << 16,
+                1 << 16,
+            );
+
+            // Returns -1 if the conversion does not support colour details, e.g. RGB to RGB.
+            if ret < 0 {
+                return Err(FfmpegError::Other(
+                    "Colorspace details are not supported by this conversion".into(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn src(&self) -> Option<ImageSpec> {
+        self.src
+    }
+
+    pub fn dst(&self) -> Option<ImageSpec> {
+        self.dst
+    }
+
+    /// Convert an image stored in raw planes into `dst`.
+    ///
+    /// `src` and `src_strides` contain one entry per plane of the source format.
+    pub fn scale(&mut self, src: &[&[u8]], src_strides: &[usize], dst: &mut Frame) -> Result<()> {
+        let (src_spec, dst_spec) = match (self.src, self.dst) {
+            (Some(src), Some(dst)) if !self.raw.is_null() => (src, dst),
+            _ => return Err(FfmpegError::Other("Scaler is not configured".into())),
+        };
+
+        let (src_ptrs, src_linesizes) = check_planes(src_spec, src, src_strides)?;
+        check_frame(dst, dst_spec)?;
+
+        unsafe {
+            check_error(ffi::sws_scale(
+                self.raw,
+                src_ptrs.as_ptr(),
+                src_linesizes.as_ptr(),
+                0,
+                src_spec.height as c_int,
+                (*dst.raw).data.as_ptr(),
+                (*dst.raw).linesize.as_ptr(),
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    /// Convert `src` into `dst`.
+    pub fn scale_frame(&mut self, src: &Frame, dst: &mut Frame) -> Result<()> {
+        let (src_spec, dst_spec) = match (self.src, self.dst) {
+            (Some(src), Some(dst)) if !self.raw.is_null() => (src, dst),
+            _ => return Err(FfmpegError::Other("Scaler is not configured".into())),
+        };
+
+        check_frame(src, src_spec)?;
+        check_frame(dst, dst_spec)?;
+
+        unsafe {
+            check_error(ffi::sws_scale(
+                self.raw,
+                (*src.raw).data.as_ptr() as *const *const u8,
+                (*src.raw).linesize.as_ptr(),
+                0,
+                src_spec.height as c_int,
+                (*dst.raw).data.as_ptr(),
+                (*dst.raw).linesize.as_ptr(),
+            ))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Validate raw planes against `spec`, returning the pointers and strides to pass to FFmpeg.
+pub(crate) fn check_planes(
+    spec: ImageSpec,
+    src: &[&[u8]],
+    src_strides: &[usize],
+) -> Result<([*const u8; 4], [c_int; 4])> {
+    if src.is_empty() || src.len() > 4 || src.len() != src_strides.len() {
+        return Err(FfmpegError::Other("Invalid source planes".into()));
+    }
+
+    let mut strides = [0usize; 4];
+    strides[..src_strides.len()].copy_from_slice(src_strides);
+
+    let plane_sizes = spec.plane_sizes(&strides)?;
+
+    let mut src_ptrs = [null(); 4];
+    let mut src_linesizes: [c_int; 4] = [0; 4];
+    for (i, plane) in src.iter().enumerate() {
+        if plane.len() < plane_sizes[i] {
+            return Err(FfmpegError::Other(format!(
+                "Source plane {} too small: {} < {}",
+                i,
+                plane.len(),
+                plane_sizes[i]
+            )));
+        }
+
+        src_ptrs[i] = plane.as_ptr();
+        src_linesizes[i] = strides[i] as c_int;
+    }
+
+    Ok((src_ptrs, src_linesizes))
+}
+
+fn check_frame(frame: &Frame, spec: ImageSpec) -> Result<()> {
+    if frame.width() != spec.width as usize
+        || frame.height() != spec.height as usize
+        || frame.format() != spec.format
+    {
+        return Err(FfmpegError::Other(format!(
+            "Frame ({}x{}, format {}) does not match {:?}",
+            frame.width(),
+            frame.height(),
+            frame.format(),
+            spec
+        )));
+    }
+
+    Ok(())
+}
+
+impl Drop for Scaler {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::sws_freeContext(self.raw);
+        }
+    }
+}
+
+// The context is only ever used through `&mut self`.
+unsafe impl Send for Scaler {}
@@ -20,9 +20,12 @@ fn main() -> Result<(), Box<dyn Error>> {
         .allowlist_function("av_.*")
         .allowlist_function("avcodec_.*")
         .allowlist_function("av_image_.*")
+        .allowlist_function("sws_.*")
         .allowlist_var("FF_PROFILE.*")
         .allowlist_var("AV_.*")
         .allowlist_var("AVERROR_.*")
+        .allowlist_var("SWS_.*")
+        .allowlist_type("AVHWFramesContext")
         .parse_callbacks(Box::new(bindgen::CargoCallbacks));
 
     for include_path in &library.include_paths {
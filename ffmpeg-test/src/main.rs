@@ -1,18 +1,35 @@
 use std::io::Write;
 
-use ffmpeg_simple::{Codec, CodecContext, HwDeviceContext};
+use ffmpeg_simple::{Codec, CodecContext, Dictionary, HwDeviceContext};
 
 use anyhow::Result;
 
 fn main() -> Result<()> {
     println!("Hello, world!");
 
+    tracing_subscriber::fmt()
+        .with_max_level(tracing_subscriber::filter::LevelFilter::DEBUG)
+        .init();
     ffmpeg_simple::init_logging();
 
+    // Usage: ffmpeg-test [ENCODER]... [KEY=VALUE]...
+    let (option_args, encoder_args): (Vec<String>, Vec<String>) =
+        std::env::args().skip(1).partition(|arg| arg.contains('='));
+
+    let hw_codec_names = if encoder_args.is_empty() {
+        vec![
+            "h264_qsv".to_string(),
+            "h264_nvenc".to_string(),
+            "h264_amf".to_string(),
+        ]
+    } else {
+        encoder_args
+    };
+
     let mut device_context = None;
     let mut codec = Codec::find_by_name("libx264").unwrap();
 
-    for hw_codec_name in &["h264_qsv", "h264_nvenc", "h264_amf"] {
+    for hw_codec_name in &hw_codec_names {
         let hw_codec = if let Some(codec) = Codec::find_by_name(hw_codec_name) {
             codec
         } else {
@@ -52,15 +69,23 @@ fn main() -> Result<()> {
     ctx.set_size(1920, 1080)
         .set_framerate(60, 1)
         .set_time_base(1, 60)
-        .set_pix_fmt(ffmpeg_simple::ffi::AVPixelFormat_AV_PIX_FMT_NV12)
-        .set_option("profile", "baseline")?;
-    // .set_option("tune", "ll")?
-    // .set_option("preset", "medium")?;
+        .set_pix_fmt(ffmpeg_simple::ffi::AVPixelFormat_AV_PIX_FMT_NV12);
     if let Some(device_context) = device_context {
         ctx.set_hw_device_ctx(device_context);
     }
 
-    let mut ctx = ctx.open()?;
+    let options = if option_args.is_empty() {
+        Dictionary::from_iter([("profile", "baseline")])
+    } else {
+        option_args
+            .iter()
+            .filter_map(|arg| arg.split_once('='))
+            .collect()
+    };
+    let (mut ctx, unused_options) = ctx.open_with_options(options)?;
+    for (key, value) in unused_options.entries() {
+        println!("Unused option {}={}", key, value);
+    }
 
     for i in 0..100 {
         println!("{}", i);
@@ -79,6 +104,21 @@ fn main() -> Result<()> {
         }
     }
 
+    let mut remaining = 0;
+    ctx.flush(|packet| {
+        if let Some(data) = packet.data() {
+            file.write_all(data).map_err(|e| {
+                ffmpeg_simple::error::FfmpegError::Other(format!("Failed to write packet: {}", e))
+            })?;
+        }
+        if packet.is_keyframe() {
+            println!("Keyframe at pts {} (dts {})", packet.pts(), packet.dts());
+        }
+        remaining += 1;
+        Ok(())
+    })?;
+    println!("Drained {} delayed packets", remaining);
+
     println!("Done");
 
     Ok(())
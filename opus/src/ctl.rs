@@ -0,0 +1,227 @@
+use opus_sys as ffi;
+
+use crate::{Error, Result};
+
+/// Target bitrate of an encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bitrate {
+    /// Chosen from the sample rate and the channel count.
+    Auto,
+    /// As high as the packet size allows.
+    Max,
+    /// Bits per second.
+    Bits(i32),
+}
+
+impl Bitrate {
+    pub(crate) fn to_raw(self) -> i32 {
+        match self {
+            Bitrate::Auto => ffi::OPUS_AUTO,
+            Bitrate::Max => ffi::OPUS_BITRATE_MAX,
+            Bitrate::Bits(bits) => bits,
+        }
+    }
+}
+
+/// Type of the encoded signal, biasing the mode decision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Auto,
+    Voice,
+    Music,
+}
+
+impl Signal {
+    pub(crate) fn to_raw(self) -> i32 {
+        match self {
+            Signal::Auto => ffi::OPUS_AUTO,
+            Signal::Voice => ffi::OPUS_SIGNAL_VOICE as i32,
+            Signal::Music => ffi::OPUS_SIGNAL_MUSIC as i32,
+        }
+    }
+
+    pub(crate) fn from_raw(raw: i32) -> Result<Self> {
+        const VOICE: i32 = ffi::OPUS_SIGNAL_VOICE as i32;
+        const MUSIC: i32 = ffi::OPUS_SIGNAL_MUSIC as i32;
+        match raw {
+            ffi::OPUS_AUTO => Ok(Signal::Auto),
+            VOICE => Ok(Signal::Voice),
+            MUSIC => Ok(Signal::Music),
+            _ => Err(Error::Unknown(raw)),
+        }
+    }
+}
+
+/// Audio bandwidth, the highest frequency that is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Bandwidth {
+    Auto,
+    /// 4 kHz
+    Narrowband,
+    /// 6 kHz
+    Mediumband,
+    /// 8 kHz
+    Wideband,
+    /// 12 kHz
+    Superwideband,
+    /// 20 kHz
+    Fullband,
+}
+
+impl Bandwidth {
+    pub(crate) fn to_raw(self) -> i32 {
+        match self {
+            Bandwidth::Auto => ffi::OPUS_AUTO,
+            Bandwidth::Narrowband => ffi::OPUS_BANDWIDTH_NARROWBAND as i32,
+            Bandwidth::Mediumband => ffi::OPUS_BANDWIDTH_MEDIUMBAND as i32,
+            Bandwidth::Wideband => ffi::OPUS_BANDWIDTH_WIDEBAND as i32,
+            Bandwidth::Superwideband => ffi::OPUS_BANDWIDTH_SUPERWIDEBAND as i32,
+            Bandwidth::Fullband => ffi::OPUS_BANDWIDTH_FULLBAND as i32,
+        }
+    }
+
+    pub(crate) fn from_raw(raw: i32) -> Result<Self> {
+        const NARROWBAND: i32 = ffi::OPUS_BANDWIDTH_NARROWBAND as i32;
+        const MEDIUMBAND: i32 = ffi::OPUS_BANDWIDTH_MEDIUMBAND as i32;
+        const WIDEBAND: i32 = ffi::OPUS_BANDWIDTH_WIDEBAND as i32;
+        const SUPERWIDEBAND: i32 = ffi::OPUS_BANDWIDTH_SUPERWIDEBAND as i32;
+        const FULLBAND: i32 = ffi::OPUS_BANDWIDTH_FULLBAND as i32;
+        match raw {
+            ffi::OPUS_AUTO => Ok(Bandwidth::Auto),
+            NARROWBAND => Ok(Bandwidth::Narrowband),
+            MEDIUMBAND => Ok(Bandwidth::Mediumband),
+            WIDEBAND => Ok(Bandwidth::Wideband),
+            SUPERWIDEBAND => Ok(Bandwidth::Superwideband),
+            FULLBAND => Ok(Bandwidth::Fullband),
+            _ => Err(Error::Unknown(raw)),
+        }
+    }
+}
+
+/// Typed encoder CTLs, for types with `ctl(request, value)` and `ctl_get(request)`
+/// methods calling their variadic CTL function.
+macro_rules! impl_encoder_ctls {
+    ($ty:ty) => {
+        impl $ty {
+            pub fn set_bitrate(&mut self, bitrate: $crate::Bitrate) -> $crate::Result<()> {
+                self.ctl(opus_sys::OPUS_SET_BITRATE_REQUEST, bitrate.to_raw())
+            }
+
+            /// Bits per second, resolved if the bitrate is automatic.
+            pub fn bitrate(&self) -> $crate::Result<i32> {
+                self.ctl_get(opus_sys::OPUS_GET_BITRATE_REQUEST)
+            }
+
+            /// Enable variable bitrate, the default.
+            pub fn set_vbr(&mut self, vbr: bool) -> $crate::Result<()> {
+                self.ctl(opus_sys::OPUS_SET_VBR_REQUEST, vbr as i32)
+            }
+
+            pub fn vbr(&self) -> $crate::Result<bool> {
+                Ok(self.ctl_get(opus_sys::OPUS_GET_VBR_REQUEST)? != 0)
+            }
+
+            /// Keep the variable bitrate close to the target, the default.
+            pub fn set_vbr_constraint(&mut self, constrained: bool) -> $crate::Result<()> {
+                self.ctl(
+                    opus_sys::OPUS_SET_VBR_CONSTRAINT_REQUEST,
+                    constrained as i32,
+                )
+            }
+
+            pub fn vbr_constraint(&self) -> $crate::Result<bool> {
+                Ok(self.ctl_get(opus_sys::OPUS_GET_VBR_CONSTRAINT_REQUEST)? != 0)
+            }
+
+            /// Trade CPU time for quality, from 0 to 10.
+            pub fn set_complexity(&mut self, complexity: i32) -> $crate::Result<()> {
+                self.ctl(opus_sys::OPUS_SET_COMPLEXITY_REQUEST, complexity)
+            }
+
+            pub fn complexity(&self) -> $crate::Result<i32> {
+                self.ctl_get(opus_sys::OPUS_GET_COMPLEXITY_REQUEST)
+            }
+
+            pub fn set_signal(&mut self, signal: $crate::Signal) -> $crate::Result<()> {
+                self.ctl(opus_sys::OPUS_SET_SIGNAL_REQUEST, signal.to_raw())
+            }
+
+            pub fn signal(&self) -> $crate::Result<$crate::Signal> {
+                $crate::Signal::from_raw(self.ctl_get(opus_sys::OPUS_GET_SIGNAL_REQUEST)?)
+            }
+
+            /// Force the bandwidth instead of choosing it from the bitrate.
+            pub fn set_bandwidth(&mut self, bandwidth: $crate::Bandwidth) -> $crate::Result<()> {
+                self.ctl(opus_sys::OPUS_SET_BANDWIDTH_REQUEST, bandwidth.to_raw())
+            }
+
+            /// Bandwidth of the last encoded packet.
+            pub fn bandwidth(&self) -> $crate::Result<$crate::Bandwidth> {
+                $crate::Bandwidth::from_raw(self.ctl_get(opus_sys::OPUS_GET_BANDWIDTH_REQUEST)?)
+            }
+
+            /// Upper limit of the bandwidth chosen from the bitrate.
+            pub fn set_max_bandwidth(
+                &mut self,
+                bandwidth: $crate::Bandwidth,
+            ) -> $crate::Result<()> {
+                self.ctl(opus_sys::OPUS_SET_MAX_BANDWIDTH_REQUEST, bandwidth.to_raw())
+            }
+
+            pub fn max_bandwidth(&self) -> $crate::Result<$crate::Bandwidth> {
+                $crate::Bandwidth::from_raw(self.ctl_get(opus_sys::OPUS_GET_MAX_BANDWIDTH_REQUEST)?)
+            }
+
+            /// Add redundancy so that decoders can recover a lost packet from the next one.
+            ///
+            /// Only used in the SILK modes, when the expected packet loss is not zero.
+            pub fn set_inband_fec(&mut self, fec: bool) -> $crate::Result<()> {
+                self.ctl(opus_sys::OPUS_SET_INBAND_FEC_REQUEST, fec as i32)
+            }
+
+            pub fn inband_fec(&self) -> $crate::Result<bool> {
+                Ok(self.ctl_get(opus_sys::OPUS_GET_INBAND_FEC_REQUEST)? != 0)
+            }
+
+            /// Expected packet loss in percent, from 0 to 100.
+            pub fn set_packet_loss_perc(&mut self, percent: i32) -> $crate::Result<()> {
+                self.ctl(opus_sys::OPUS_SET_PACKET_LOSS_PERC_REQUEST, percent)
+            }
+
+            pub fn packet_loss_perc(&self) -> $crate::Result<i32> {
+                self.ctl_get(opus_sys::OPUS_GET_PACKET_LOSS_PERC_REQUEST)
+            }
+
+            /// Send almost nothing during silence.
+            pub fn set_dtx(&mut self, dtx: bool) -> $crate::Result<()> {
+                self.ctl(opus_sys::OPUS_SET_DTX_REQUEST, dtx as i32)
+            }
+
+            pub fn dtx(&self) -> $crate::Result<bool> {
+                Ok(self.ctl_get(opus_sys::OPUS_GET_DTX_REQUEST)? != 0)
+            }
+
+            /// Bit depth of the input, from 8 to 24, below which the encoder does not
+            /// spend bits.
+            pub fn set_lsb_depth(&mut self, depth: i32) -> $crate::Result<()> {
+                self.ctl(opus_sys::OPUS_SET_LSB_DEPTH_REQUEST, depth)
+            }
+
+            pub fn lsb_depth(&self) -> $crate::Result<i32> {
+                self.ctl_get(opus_sys::OPUS_GET_LSB_DEPTH_REQUEST)
+            }
+
+            /// Forget the previous audio, as if the encoder was just created.
+            pub fn reset_state(&mut self) -> $crate::Result<()> {
+                // The request has no argument, the value is ignored
+                self.ctl(opus_sys::OPUS_RESET_STATE, 0)
+            }
+
+            /// Delay added by the encoder in samples, which decoders should skip
+            /// (the pre-skip of the Ogg identification header at 48 kHz).
+            pub fn lookahead(&self) -> $crate::Result<i32> {
+                self.ctl_get(opus_sys::OPUS_GET_LOOKAHEAD_REQUEST)
+            }
+        }
+    };
+}
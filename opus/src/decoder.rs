@@ -0,0 +1,241 @@
+use std::ffi::c_int;
+
+use opus_sys as ffi;
+
+use crate::{check_error, Channels, Error, Result};
+
+pub struct Decoder {
+    raw: *mut ffi::OpusDecoder,
+    channels: Channels,
+}
+
+impl Decoder {
+    /// Create a decoder outputting `sample_rate`, one of 8000, 12000, 16000, 24000 or 48000.
+    pub fn new(sample_rate: u32, channels: Channels) -> Result<Self> {
+        let mut error = 0;
+        let raw =
+            unsafe { ffi::opus_decoder_create(sample_rate as i32, channels as c_int, &mut error) };
+
+        check_error(error)?;
+
+        if raw.is_null() {
+            return Err(Error::Unknown(0));
+        }
+
+        Ok(Self { raw, channels })
+    }
+
+    /// Decode `packet` into `pcm`, returning the number of samples per channel.
+    ///
+    /// Without a packet, the audio of a lost packet is concealed, the length of `pcm`
+    /// giving its duration. With `fec`, the redundant data of `packet` is decoded instead,
+    /// recovering the packet lost before it, which must also be as long as `pcm`.
+    pub fn decode(&mut self, packet: Option<&[u8]>, pcm: &mut [i16], fec: bool) -> Result<usize> {
+        let (data, len) = Self::packet_ptr(packet);
+        let frame_size = pcm.len() / self.channels as usize;
+        let samples = unsafe {
+            ffi::opus_decode(
+                self.raw,
+                data,
+                len,
+                // frame_size * channels * sizeof(opus_int16)
+                pcm.as_mut_ptr(),
+                frame_size as c_int,
+                fec as c_int,
+            )
+        };
+
+        if samples < 0 {
+            check_error(samples)?;
+        }
+
+        Ok(samples as usize)
+    }
+
+    /// Same as `decode`, with floating point samples.
+    pub fn decode_f32(
+        &mut self,
+        packet: Option<&[u8]>,
+        pcm: &mut [f32],
+        fec: bool,
+    ) -> Result<usize> {
+        let (data, len) = Self::packet_ptr(packet);
+        let frame_size = pcm.len() / self.channels as usize;
+        let samples = unsafe {
+            ffi::opus_decode_float(
+                self.raw,
+                data,
+                len,
+                // frame_size * channels * sizeof(float)
+                pcm.as_mut_ptr(),
+                frame_size as c_int,
+                fec as c_int,
+            )
+        };
+
+        if samples < 0 {
+            check_error(samples)?;
+        }
+
+        Ok(samples as usize)
+    }
+
+    /// Number of samples per channel `packet` decodes to.
+    pub fn nb_samples(&self, packet: &[u8]) -> Result<usize> {
+        let samples = unsafe {
+            ffi::opus_decoder_get_nb_samples(self.raw, packet.as_ptr(), packet.len() as i32)
+        };
+
+        if samples < 0 {
+            check_error(samples)?;
+        }
+
+        Ok(samples as usize)
+    }
+
+    fn packet_ptr(packet: Option<&[u8]>) -> (*const u8, i32) {
+        match packet {
+            Some(packet) => (packet.as_ptr(), packet.len() as i32),
+            None => (std::ptr::null(), 0),
+        }
+    }
+}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::opus_decoder_destroy(self.raw);
+        }
+    }
+}
+
+// Same as `Encoder`, the state is only used from one thread at a time.
+unsafe impl Send for Decoder {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Application, Encoder, Signal};
+
+    const SAMPLE_RATE: u32 = 48000;
+    /// 20ms
+    const FRAME_SIZE: usize = 960;
+
+    /// Stereo 440 Hz tone, starting at `frame`.
+    fn tone(frame: usize) -> Vec<f32> {
+        (frame..frame + FRAME_SIZE)
+            .flat_map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                let s = (t * 440.0 * std::f32::consts::TAU).sin() * 0.5;
+                [s, s]
+            })
+            .collect()
+    }
+
+    fn encode_tone(encoder: &mut Encoder, frames: usize) -> Vec<Vec<u8>> {
+        (0..frames)
+            .map(|i| {
+                let mut packet = vec![0u8; 4000];
+                let len = encoder
+                    .encode_f32(&tone(i * FRAME_SIZE), &mut packet)
+                    .unwrap();
+                packet.truncate(len);
+                packet
+            })
+            .collect()
+    }
+
+    fn energy(pcm: &[f32]) -> f32 {
+        pcm.iter().map(|s| s * s).sum::<f32>() / pcm.len() as f32
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut encoder = Encoder::new(SAMPLE_RATE, Channels::Stereo, Application::Audio).unwrap();
+        let mut decoder = Decoder::new(SAMPLE_RATE, Channels::Stereo).unwrap();
+        let pre_skip = encoder.lookahead().unwrap() as usize;
+
+        let packets = encode_tone(&mut encoder, 10);
+        let mut decoded = vec![];
+        for packet in &packets {
+            assert_eq!(decoder.nb_samples(packet).unwrap(), FRAME_SIZE);
+
+            let mut pcm = vec![0f32; FRAME_SIZE * 2];
+            let samples = decoder.decode_f32(Some(packet), &mut pcm, false).unwrap();
+            assert_eq!(samples, FRAME_SIZE);
+            decoded.extend_from_slice(&pcm);
+        }
+
+        // Compare after the start of the signal, delayed by the lookahead
+        let start = FRAME_SIZE * 2;
+        let original: Vec<f32> = (0..8).flat_map(|i| tone(start + i * FRAME_SIZE)).collect();
+        let decoded = &decoded[(start + pre_skip) * 2..][..original.len()];
+        let error: Vec<f32> = original.iter().zip(decoded).map(|(a, b)| a - b).collect();
+        assert!(energy(&error) < energy(&original) / 100.0);
+    }
+
+    #[test]
+    fn test_decode_i16() {
+        let mut encoder = Encoder::new(SAMPLE_RATE, Channels::Stereo, Application::Audio).unwrap();
+        let mut decoder = Decoder::new(SAMPLE_RATE, Channels::Stereo).unwrap();
+
+        let mut loud = false;
+        for packet in encode_tone(&mut encoder, 5) {
+            let mut pcm = vec![0i16; FRAME_SIZE * 2];
+            let samples = decoder.decode(Some(&packet), &mut pcm, false).unwrap();
+            assert_eq!(samples, FRAME_SIZE);
+            loud |= pcm.iter().any(|s| s.unsigned_abs() > 8000);
+        }
+        assert!(loud);
+    }
+
+    #[test]
+    fn test_packet_loss_concealment() {
+        let mut encoder = Encoder::new(SAMPLE_RATE, Channels::Stereo, Application::Audio).unwrap();
+        let mut decoder = Decoder::new(SAMPLE_RATE, Channels::Stereo).unwrap();
+
+        let packets = encode_tone(&mut encoder, 5);
+        let mut pcm = vec![0f32; FRAME_SIZE * 2];
+        for packet in &packets[..4] {
+            decoder.decode_f32(Some(packet), &mut pcm, false).unwrap();
+        }
+
+        // The last packet is lost
+        let samples = decoder.decode_f32(None, &mut pcm, false).unwrap();
+        assert_eq!(samples, FRAME_SIZE);
+        assert!(energy(&pcm) > 0.0);
+    }
+
+    #[test]
+    fn test_fec() {
+        let mut encoder = Encoder::new(SAMPLE_RATE, Channels::Stereo, Application::Voip).unwrap();
+        encoder.set_signal(Signal::Voice).unwrap();
+        encoder.set_bitrate(crate::Bitrate::Bits(32000)).unwrap();
+        encoder.set_inband_fec(true).unwrap();
+        encoder.set_packet_loss_perc(20).unwrap();
+        assert!(encoder.inband_fec().unwrap());
+        let mut decoder = Decoder::new(SAMPLE_RATE, Channels::Stereo).unwrap();
+
+        let packets = encode_tone(&mut encoder, 10);
+        let mut pcm = vec![0f32; FRAME_SIZE * 2];
+        for packet in &packets[..8] {
+            decoder.decode_f32(Some(packet), &mut pcm, false).unwrap();
+        }
+
+        // Packet 8 is lost, recover it from packet 9
+        let samples = decoder
+            .decode_f32(Some(&packets[9]), &mut pcm, true)
+            .unwrap();
+        assert_eq!(samples, FRAME_SIZE);
+        let samples = decoder
+            .decode_f32(Some(&packets[9]), &mut pcm, false)
+            .unwrap();
+        assert_eq!(samples, FRAME_SIZE);
+    }
+
+    #[test]
+    fn test_invalid_packet() {
+        let decoder = Decoder::new(SAMPLE_RATE, Channels::Mono).unwrap();
+        assert!(matches!(decoder.nb_samples(&[]), Err(Error::BadArgument)));
+    }
+}
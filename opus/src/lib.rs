@@ -2,12 +2,25 @@ use std::ffi::c_int;
 
 use opus_sys as ffi;
 
+#[macro_use]
+mod ctl;
+mod decoder;
+mod multistream;
+
+pub use ctl::{Bandwidth, Bitrate, Signal};
+pub use decoder::Decoder;
+pub use multistream::MultistreamEncoder;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Unknown error: {0}")]
     Unknown(i32),
     #[error("One or more invalid/out of range arguments.")]
     BadArgument,
+    #[error("Not enough bytes allocated in the buffer.")]
+    BufferTooSmall,
+    #[error("The compressed data passed is corrupted.")]
+    InvalidPacket,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -35,6 +48,8 @@ fn check_error(code: i32) -> Result<()> {
     match code {
         OPUS_OK => Ok(()),
         ffi::OPUS_BAD_ARG => Err(Error::BadArgument),
+        ffi::OPUS_BUFFER_TOO_SMALL => Err(Error::BufferTooSmall),
+        ffi::OPUS_INVALID_PACKET => Err(Error::InvalidPacket),
         _ => Err(Error::Unknown(code)),
     }
 }
@@ -70,11 +85,17 @@ impl Encoder {
         })
     }
 
-    // pub fn set_bitrate(&mut self, bitrate: u32) -> Result<()> {
-    //     let ret =
-    //         unsafe { ffi::opus_encoder_ctl(self.raw, ffi::OPUS_SET_BITRATE_REQUEST, bitrate) };
-    //     check_error(ret)
-    // }
+    fn ctl(&mut self, request: u32, value: i32) -> Result<()> {
+        check_error(unsafe { ffi::opus_encoder_ctl(self.raw, request as c_int, value) })
+    }
+
+    fn ctl_get(&self, request: u32) -> Result<i32> {
+        let mut value: i32 = 0;
+        check_error(unsafe {
+            ffi::opus_encoder_ctl(self.raw, request as c_int, &mut value as *mut i32)
+        })?;
+        Ok(value)
+    }
 
     pub fn encode(&mut self, pcm: &[i16], data: &mut [u8]) -> Result<usize> {
         let frame_size = pcm.len() / self.channels as usize;
@@ -117,6 +138,8 @@ impl Encoder {
     }
 }
 
+impl_encoder_ctls!(Encoder);
+
 impl Drop for Encoder {
     fn drop(&mut self) {
         unsafe {
@@ -0,0 +1,118 @@
+use std::ffi::c_int;
+
+use opus_sys as ffi;
+
+use crate::{check_error, Application, Error, Result};
+
+/// Encoder splitting up to 255 channels into several mono or stereo Opus streams.
+pub struct MultistreamEncoder {
+    raw: *mut ffi::OpusMSEncoder,
+    channels: u8,
+    streams: u8,
+    coupled_streams: u8,
+    mapping: Vec<u8>,
+}
+
+impl MultistreamEncoder {
+    /// Create an encoder with the standard stream layout of `mapping_family`.
+    ///
+    /// Family 0 supports mono and stereo, family 1 up to 8 channels in the Vorbis order.
+    pub fn new_surround(
+        sample_rate: u32,
+        channels: u8,
+        mapping_family: u8,
+        application: Application,
+    ) -> Result<Self> {
+        let mut error = 0;
+        let mut streams = 0;
+        let mut coupled_streams = 0;
+        let mut mapping = vec![0u8; channels as usize];
+        let raw = unsafe {
+            ffi::opus_multistream_surround_encoder_create(
+                sample_rate as i32,
+                channels as c_int,
+                mapping_family as c_int,
+                &mut streams,
+                &mut coupled_streams,
+                mapping.as_mut_ptr(),
+                application as c_int,
+                &mut error,
+            )
+        };
+
+        check_error(error)?;
+
+        if raw.is_null() {
+            return Err(Error::Unknown(0));
+        }
+
+        Ok(Self {
+            raw,
+            channels,
+            streams: streams as u8,
+            coupled_streams: coupled_streams as u8,
+            mapping,
+        })
+    }
+
+    /// Number of streams in each packet.
+    pub fn streams(&self) -> u8 {
+        self.streams
+    }
+
+    /// Number of streams that carry two channels.
+    pub fn coupled_streams(&self) -> u8 {
+        self.coupled_streams
+    }
+
+    /// Index of the decoded channel of each input channel.
+    pub fn mapping(&self) -> &[u8] {
+        &self.mapping
+    }
+
+    fn ctl(&mut self, request: u32, value: i32) -> Result<()> {
+        check_error(unsafe { ffi::opus_multistream_encoder_ctl(self.raw, request as c_int, value) })
+    }
+
+    /// Getters return the value of the first stream.
+    fn ctl_get(&self, request: u32) -> Result<i32> {
+        let mut value: i32 = 0;
+        check_error(unsafe {
+            ffi::opus_multistream_encoder_ctl(self.raw, request as c_int, &mut value as *mut i32)
+        })?;
+        Ok(value)
+    }
+
+    pub fn encode_f32(&mut self, pcm: &[f32], data: &mut [u8]) -> Result<usize> {
+        let frame_size = pcm.len() / self.channels as usize;
+        let len = unsafe {
+            ffi::opus_multistream_encode_float(
+                self.raw,
+                // frame_size * channels * sizeof(float)
+                pcm.as_ptr(),
+                frame_size as c_int,
+                data.as_mut_ptr(),
+                data.len() as i32,
+            )
+        };
+
+        if len < 0 {
+            check_error(len)?;
+        }
+
+        Ok(len as usize)
+    }
+}
+
+impl_encoder_ctls!(MultistreamEncoder);
+
+impl Drop for MultistreamEncoder {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::opus_multistream_encoder_destroy(self.raw);
+        }
+    }
+}
+
+// Same as `Encoder`, the state is only used from one thread at a time.
+unsafe impl Send for MultistreamEncoder {}
@@ -1,21 +1,38 @@
 use std::{collections::HashMap, sync::Arc};
 
+use crossbeam::channel;
 use tokio::sync::{broadcast, watch};
 
-use crate::{audio::AudioCodecData, monitor::MonitorHandle, utils::Sample};
+use crate::{
+    audio::{AudioCodecData, UplinkFrames},
+    monitor::MonitorHandle,
+    utils::Sample,
+};
 
 pub struct Application {
     pub monitors: std::sync::RwLock<HashMap<u32, MonitorHandle>>,
     pub audio_data_tx: broadcast::Sender<Sample>,
     audio_codec_data_rx: watch::Receiver<Option<AudioCodecData>>,
+    /// AAC encoded audio, only produced while there are receivers and AAC is enabled.
+    pub aac_data_tx: broadcast::Sender<Sample>,
+    aac_codec_data_rx: watch::Receiver<Option<AudioCodecData>>,
+    /// Audio sent by clients, `None` if it is not accepted.
+    pub uplink_tx: Option<channel::Sender<UplinkFrames>>,
 }
 
 impl Application {
-    pub fn new(audio_codec_data_rx: watch::Receiver<Option<AudioCodecData>>) -> Self {
+    pub fn new(
+        audio_codec_data_rx: watch::Receiver<Option<AudioCodecData>>,
+        aac_codec_data_rx: watch::Receiver<Option<AudioCodecData>>,
+        uplink_tx: Option<channel::Sender<UplinkFrames>>,
+    ) -> Self {
         Self {
             monitors: std::sync::RwLock::new(HashMap::new()),
             audio_data_tx: tokio::sync::broadcast::channel(8).0,
-            audio_codec_data_rx
+            audio_codec_data_rx,
+            aac_data_tx: tokio::sync::broadcast::channel(8).0,
+            aac_codec_data_rx,
+            uplink_tx,
         }
     }
 
@@ -40,6 +57,10 @@ impl Application {
     pub fn audio_codec_data(&self) -> watch::Receiver<Option<AudioCodecData>> {
         self.audio_codec_data_rx.clone()
     }
+
+    pub fn aac_codec_data(&self) -> watch::Receiver<Option<AudioCodecData>> {
+        self.aac_codec_data_rx.clone()
+    }
 }
 
 impl std::fmt::Debug for Application {
@@ -52,8 +73,16 @@ impl std::fmt::Debug for Application {
 pub struct ApplicationHandle(Arc<Application>);
 
 impl ApplicationHandle {
-    pub fn new(audio_codec_data_rx: watch::Receiver<Option<AudioCodecData>>) -> Self {
-        Self(Arc::new(Application::new(audio_codec_data_rx)))
+    pub fn new(
+        audio_codec_data_rx: watch::Receiver<Option<AudioCodecData>>,
+        aac_codec_data_rx: watch::Receiver<Option<AudioCodecData>>,
+        uplink_tx: Option<channel::Sender<UplinkFrames>>,
+    ) -> Self {
+        Self(Arc::new(Application::new(
+            audio_codec_data_rx,
+            aac_codec_data_rx,
+            uplink_tx,
+        )))
     }
 }
 
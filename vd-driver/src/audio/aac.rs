@@ -0,0 +1,250 @@
+//! AAC encoding through FFmpeg, for players and containers without Opus support.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use ffmpeg_simple::{ffi, Codec, CodecContext, Frame, OpenedCodecContext};
+use tokio::sync::broadcast;
+
+use super::{resample::vorbis_order, source::AudioFormat, AudioCodecData};
+use crate::{config::AacConfig, utils::Sample};
+
+/// Bits per second for each channel when no bitrate is configured.
+const DEFAULT_BITRATE_PER_CHANNEL: u32 = 64000;
+
+/// Sample formats the frames can be converted to, in order of preference.
+/// `aac` only supports planar floats, `libfdk_aac` interleaved 16-bit integers.
+const SAMPLE_FORMATS: &[ffi::AVSampleFormat] = &[
+    ffi::AVSampleFormat_AV_SAMPLE_FMT_FLTP,
+    ffi::AVSampleFormat_AV_SAMPLE_FMT_FLT,
+    ffi::AVSampleFormat_AV_SAMPLE_FMT_S16P,
+    ffi::AVSampleFormat_AV_SAMPLE_FMT_S16,
+];
+
+/// AAC encoder for one format, cutting the frames into encoder frames.
+///
+/// Channels are passed in the capture order, which matches the default layouts of FFmpeg
+/// for stereo, 5.1 and 7.1.
+pub struct AacEncoder {
+    encoder: OpenedCodecContext,
+    format: AudioFormat,
+    /// Channel of the converted frames, which are in the Vorbis order, for each encoder channel.
+    order: Vec<usize>,
+    /// Samples per channel in a frame.
+    frame_size: usize,
+
+    /// Interleaved samples of the next frame, in the encoder order.
+    buffer: Vec<f32>,
+    /// Capture time of the first frame in `buffer`.
+    buffer_start: Instant,
+    next_pts: i64,
+    /// Pts and capture time of the frames sent to the encoder, whose packets are not out yet.
+    pending: VecDeque<(i64, Instant)>,
+}
+
+impl AacEncoder {
+    /// Create an encoder for frames converted to `format`.
+    pub fn new(format: AudioFormat, config: &AacConfig) -> Result<Self> {
+        let codec = Codec::find_by_name(&config.encoder)
+            .with_context(|| format!("Encoder {} not found", config.encoder))?;
+        let sample_fmt = codec
+            .sample_formats()
+            .find(|f| SAMPLE_FORMATS.contains(f))
+            .with_context(|| format!("No supported sample format for {}", codec.name()))?;
+        let bitrate = config
+            .bitrate
+            .unwrap_or(DEFAULT_BITRATE_PER_CHANNEL * format.channels as u32);
+
+        let mut ctx = CodecContext::new(codec);
+        ctx.set_sample_rate(format.sample_rate)
+            .set_channels(format.channels as u32)
+            .set_sample_fmt(sample_fmt)
+            .set_bit_rate(bitrate)
+            .set_time_base(1, format.sample_rate)
+            .set_global_header();
+        let encoder = ctx.open().context("Open AAC encoder")?;
+        let frame_size = encoder.frame_size();
+        tracing::info!(
+            codec = codec.name(),
+            bitrate,
+            frame_size,
+            "Opened AAC encoder"
+        );
+
+        let channels = format.channels as usize;
+        let mut order: Vec<usize> = (0..channels).collect();
+        if let Some(vorbis) = vorbis_order(channels) {
+            for (vorbis_channel, &channel) in vorbis.iter().enumerate() {
+                order[channel] = vorbis_channel;
+            }
+        }
+
+        Ok(Self {
+            encoder,
+            format,
+            order,
+            frame_size,
+            buffer: Vec::with_capacity(frame_size * channels),
+            buffer_start: Instant::now(),
+            next_pts: 0,
+            pending: VecDeque::new(),
+        })
+    }
+
+    pub fn codec_data(&self) -> AudioCodecData {
+        AudioCodecData::Aac {
+            audio_specific_config: Bytes::copy_from_slice(self.encoder.extradata()),
+            channels: self.format.channels as u8,
+            sample_rate: self.format.sample_rate,
+        }
+    }
+
+    /// Drop the incomplete frame, before frames that do not follow it.
+    ///
+    /// Frames already sent to the encoder keep their capture time.
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+    }
+
+    /// Encode converted `data`, captured at `pts`, publishing every packet.
+    pub fn push(&mut self, data: &[f32], pts: Instant, data_tx: &broadcast::Sender<Sample>) {
+        let channels = self.format.channels as usize;
+
+        for (i, frame) in data.chunks_exact(channels).enumerate() {
+            if self.buffer.is_empty() {
+                // Frames are timed from their first sample
+                self.buffer_start =
+                    pts + Duration::from_secs_f64(i as f64 / self.format.sample_rate as f64);
+            }
+
+            self.buffer.extend(self.order.iter().map(|&c| frame[c]));
+
+            if self.buffer.len() == self.frame_size * channels {
+                if let Err(e) = self.encode(data_tx) {
+                    tracing::error!(?e, "Failed to encode AAC audio");
+                }
+                self.buffer.clear();
+            }
+        }
+    }
+
+    fn encode(&mut self, data_tx: &broadcast::Sender<Sample>) -> Result<()> {
+        let frame = self.encoder.request_frame()?;
+        fill_frame(frame, &self.buffer, self.format.channels as usize);
+        self.encoder.send_frame(self.next_pts)?;
+        self.pending.push_back((self.next_pts, self.buffer_start));
+        self.next_pts += self.frame_size as i64;
+
+        while let Some(packet) = self.encoder.receive_packet()? {
+            let data = if let Some(data) = packet.data() {
+                Bytes::copy_from_slice(data)
+            } else {
+                continue;
+            };
+
+            let samples = match packet.duration() {
+                0 => self.frame_size as i64,
+                duration => duration,
+            };
+            let sample = Sample::new(
+                data,
+                capture_time(&mut self.pending, packet.pts(), self.format.sample_rate),
+                Duration::from_secs_f64(samples as f64 / self.format.sample_rate as f64),
+            );
+            data_tx.send(sample).ok();
+        }
+
+        Ok(())
+    }
+}
+
+/// Capture time of the sample at `pts`, relative to the frame it was encoded from.
+///
+/// Packets come out delayed by the encoder, starting before the first frame.
+fn capture_time(pending: &mut VecDeque<(i64, Instant)>, pts: i64, sample_rate: u32) -> Instant {
+    // Earlier frames are done
+    while pending.len() > 1 && pending[1].0 <= pts {
+        pending.pop_front();
+    }
+
+    let (frame_pts, frame_start) = match pending.front() {
+        Some(frame) => *frame,
+        None => return Instant::now(),
+    };
+
+    let offset =
+        Duration::from_secs_f64((pts - frame_pts).unsigned_abs() as f64 / sample_rate as f64);
+    if pts >= frame_pts {
+        frame_start + offset
+    } else {
+        frame_start.checked_sub(offset).unwrap_or(frame_start)
+    }
+}
+
+/// Write interleaved `data` to `frame`, in one of `SAMPLE_FORMATS`.
+fn fill_frame(frame: &mut Frame, data: &[f32], channels: usize) {
+    let format = frame.format();
+    let planar = format == ffi::AVSampleFormat_AV_SAMPLE_FMT_FLTP
+        || format == ffi::AVSampleFormat_AV_SAMPLE_FMT_S16P;
+    let float = format == ffi::AVSampleFormat_AV_SAMPLE_FMT_FLTP
+        || format == ffi::AVSampleFormat_AV_SAMPLE_FMT_FLT;
+
+    for (index, plane) in frame.audio_planes_mut().into_iter().enumerate() {
+        let (skip, step) = if planar { (index, channels) } else { (0, 1) };
+        let samples = data.iter().skip(skip).step_by(step);
+
+        if float {
+            for (dst, sample) in plane.chunks_exact_mut(4).zip(samples) {
+                dst.copy_from_slice(&sample.to_ne_bytes());
+            }
+        } else {
+            for (dst, sample) in plane.chunks_exact_mut(2).zip(samples) {
+                let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+                dst.copy_from_slice(&sample.to_ne_bytes());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    fn samples(count: u32) -> Duration {
+        Duration::from_secs_f64(count as f64 / SAMPLE_RATE as f64)
+    }
+
+    #[test]
+    fn test_capture_time() {
+        let first = Instant::now() + Duration::from_secs(1);
+        // Captured after a gap, the frames are not contiguous
+        let second = first + Duration::from_millis(100);
+        let mut pending = VecDeque::from([(0, first), (1024, second)]);
+
+        // Priming samples come before the first frame
+        assert_eq!(
+            capture_time(&mut pending, -1024, SAMPLE_RATE),
+            first - samples(1024)
+        );
+        assert_eq!(capture_time(&mut pending, 0, SAMPLE_RATE), first);
+        assert_eq!(
+            capture_time(&mut pending, 480, SAMPLE_RATE),
+            first + samples(480)
+        );
+        assert_eq!(pending.len(), 2);
+
+        // Timed from the second frame once its samples are out
+        assert_eq!(capture_time(&mut pending, 1024, SAMPLE_RATE), second);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(
+            capture_time(&mut pending, 1984, SAMPLE_RATE),
+            second + samples(960)
+        );
+    }
+}
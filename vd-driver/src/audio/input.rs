@@ -0,0 +1,50 @@
+use anyhow::{anyhow, Context, Result};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use crossbeam::channel;
+
+use super::source::{build_input_stream, AudioEvent, AudioSource, StreamEnd};
+
+/// Captures an input device, such as a microphone or a virtual cable.
+pub struct InputSource {
+    /// Name of the device, the default input device if `None`.
+    device: Option<String>,
+}
+
+impl InputSource {
+    pub fn new(device: Option<String>) -> Self {
+        Self { device }
+    }
+
+    fn find_device(&self, host: &cpal::Host) -> Result<cpal::Device> {
+        match &self.device {
+            Some(name) => host
+                .input_devices()?
+                .find(|d| d.name().map(|n| &n == name).unwrap_or(false))
+                .ok_or_else(|| anyhow!("Input device {} not found", name)),
+            None => host
+                .default_input_device()
+                .ok_or_else(|| anyhow!("No input device found")),
+        }
+    }
+}
+
+impl AudioSource for InputSource {
+    fn run(&mut self, events_tx: channel::Sender<AudioEvent>) -> Result<()> {
+        let host = cpal::default_host();
+        let device = self.find_device(&host)?;
+        tracing::info!(name = ?device.name(), "Using audio input device");
+
+        let config = device.default_input_config()?;
+        tracing::info!(?config, "Using default input config");
+
+        let (stream, end_rx) = build_input_stream(&device, &config, &events_tx)?;
+        stream.play().context("Start audio stream")?;
+        tracing::info!("Audio stream started");
+
+        // Frames are sent from the callbacks of the stream
+        match end_rx.recv() {
+            Ok(StreamEnd::Error(e)) => Err(e.into()),
+            Ok(StreamEnd::Disconnected) | Err(_) => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::{anyhow, Context, Result};
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    Stream,
+};
+use crossbeam::channel;
+use windows::{
+    core::PCWSTR,
+    Win32::{
+        Media::Audio::{
+            eRender, EDataFlow, ERole, IMMDeviceEnumerator, IMMNotificationClient,
+            IMMNotificationClient_Impl, MMDeviceEnumerator,
+        },
+        System::Com::{
+            CoCreateInstance, CoInitializeEx, CLSCTX_INPROC_SERVER, COINIT_DISABLE_OLE1DDE,
+            COINIT_MULTITHREADED,
+        },
+    },
+};
+
+use super::source::{build_input_stream, AudioEvent, AudioSource, StreamEnd};
+
+#[windows::core::implement(IMMNotificationClient)]
+struct AudioNotificationClient {
+    default_device_changed_tx: channel::Sender<()>,
+}
+
+impl IMMNotificationClient_Impl for AudioNotificationClient {
+    fn OnDeviceStateChanged(
+        &self,
+        _pwstrdeviceid: &PCWSTR,
+        _dwnewstate: u32,
+    ) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnDeviceAdded(&self, _pwstrdeviceid: &PCWSTR) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnDeviceRemoved(&self, _pwstrdeviceid: &PCWSTR) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnDefaultDeviceChanged(
+        &self,
+        flow: EDataFlow,
+        _role: ERole,
+        _pwstrdefaultdeviceid: &PCWSTR,
+    ) -> windows::core::Result<()> {
+        if flow == eRender {
+            self.default_device_changed_tx.try_send(()).ok();
+        }
+        Ok(())
+    }
+
+    fn OnPropertyValueChanged(
+        &self,
+        _pwstrdeviceid: &PCWSTR,
+        _key: &windows::Win32::UI::Shell::PropertiesSystem::PROPERTYKEY,
+    ) -> windows::core::Result<()> {
+        Ok(())
+    }
+}
+
+/// Captures what is played on the default output device with WASAPI loopback,
+/// switching devices when the default one changes.
+pub struct LoopbackSource;
+
+impl AudioSource for LoopbackSource {
+    fn run(&mut self, events_tx: channel::Sender<AudioEvent>) -> Result<()> {
+        let enumerator: IMMDeviceEnumerator = unsafe {
+            CoInitializeEx(None, COINIT_MULTITHREADED | COINIT_DISABLE_OLE1DDE)?;
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_INPROC_SERVER)?
+        };
+
+        let (default_device_changed_tx, default_device_changed_rx) = channel::bounded::<()>(1);
+        default_device_changed_tx.send(()).ok(); // Trigger initial device change
+        let callback: IMMNotificationClient = AudioNotificationClient {
+            default_device_changed_tx,
+        }
+        .into();
+        unsafe {
+            enumerator.RegisterEndpointNotificationCallback(&callback)?;
+        }
+
+        let host = cpal::default_host();
+
+        let mut current_stream: Option<Stream> = None;
+        let mut end_rx = channel::never();
+
+        loop {
+            channel::select! {
+                recv(default_device_changed_rx) -> changed => {
+                    if changed.is_err() {
+                        break;
+                    }
+                }
+                recv(end_rx) -> end => {
+                    match end {
+                        Ok(StreamEnd::Disconnected) => break,
+                        // Wait for the next default device
+                        _ => {
+                            end_rx = channel::never();
+                            continue;
+                        }
+                    }
+                }
+            }
+
+            let _ = current_stream.take(); // Drop the old stream
+
+            let device = host
+                .default_output_device()
+                .ok_or_else(|| anyhow!("No output device found"))?;
+
+            tracing::info!(name = ?device.name(), "Using audio device");
+
+            let audio_cfg = device.default_output_config()?;
+            tracing::info!(?audio_cfg, "Using default output config");
+
+            // Capturing an output device records what it plays
+            let (stream, end_rx_) = build_input_stream(&device, &audio_cfg, &events_tx)?;
+            stream.play().context("Start audio stream")?;
+            tracing::info!("Audio stream started");
+
+            current_stream = Some(stream);
+            end_rx = end_rx_;
+        }
+
+        Ok(())
+    }
+}
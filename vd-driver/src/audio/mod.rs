@@ -0,0 +1,632 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+use crossbeam::channel;
+use tokio::sync::{broadcast, watch};
+
+use crate::{
+    config::{
+        AudioConfig, AudioSinkConfig, AudioSourceConfig, OpusBandwidth, OpusConfig, OpusSignal,
+    },
+    utils::Sample,
+};
+
+pub use self::uplink::{UplinkDecoder, UplinkFrames};
+
+use self::{
+    aac::AacEncoder,
+    input::InputSource,
+    output::OutputSink,
+    resample::Converter,
+    sine::SineSource,
+    sink::AudioSink,
+    source::{AudioEvent, AudioFormat, AudioSource},
+    wav::{WavSink, WavSource},
+};
+
+mod aac;
+mod input;
+#[cfg(windows)]
+mod loopback;
+mod output;
+mod resample;
+mod sine;
+mod sink;
+mod source;
+mod uplink;
+mod wav;
+
+/// Chunks of frames queued between the source and the encoder.
+const EVENT_QUEUE_SIZE: usize = 16;
+/// Chunks of frames queued between the clients and the sink.
+const UPLINK_QUEUE_SIZE: usize = 16;
+/// Time without audio from the client being played after which another one is played.
+const UPLINK_SWITCH_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone)]
+pub enum AudioCodecData {
+    Opus {
+        ident_header: Bytes,
+        channels: u8,
+        sample_rate: u32,
+        /// Streams in each packet, for more than two channels.
+        streams: u8,
+        /// Streams carrying two channels.
+        coupled_streams: u8,
+        /// Stream channel of each output channel, in the Vorbis order.
+        mapping: Bytes,
+        /// Samples at 48 kHz to discard at the start of the decoded audio.
+        pre_skip: u16,
+    },
+    /// AAC-LC, each sample being one raw access unit without ADTS header.
+    Aac {
+        /// `AudioSpecificConfig` of ISO/IEC 14496-3.
+        audio_specific_config: Bytes,
+        channels: u8,
+        sample_rate: u32,
+    },
+}
+
+impl AudioCodecData {
+    pub fn mime(&self) -> &'static str {
+        match self {
+            AudioCodecData::Opus { .. } => "audio/opus",
+            AudioCodecData::Aac { .. } => "audio/mp4a-latm",
+        }
+    }
+
+    pub fn channels(&self) -> u8 {
+        match self {
+            AudioCodecData::Opus { channels, .. } | AudioCodecData::Aac { channels, .. } => {
+                *channels
+            }
+        }
+    }
+
+    /// Layout of surround Opus, as the `a=fmtp` parameters of `multiopus`.
+    pub fn multiopus_fmtp(&self) -> Option<String> {
+        match self {
+            AudioCodecData::Opus {
+                channels,
+                streams,
+                coupled_streams,
+                mapping,
+                ..
+            } if *channels > 2 => {
+                let mapping = mapping
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",");
+
+                Some(format!(
+                    "channel_mapping={};num_streams={};coupled_streams={}",
+                    mapping, streams, coupled_streams
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        match self {
+            AudioCodecData::Opus { sample_rate, .. } | AudioCodecData::Aac { sample_rate, .. } => {
+                *sample_rate
+            }
+        }
+    }
+}
+
+/// Opus encoder for one format, cutting the frames into packets.
+struct AudioEncoder {
+    format: AudioFormat,
+    encoder: opus::MultistreamEncoder,
+    pre_skip: u16,
+    /// Interleaved samples in a packet.
+    packet_size: usize,
+    packet_duration: Duration,
+    /// Capture time of the first frame in `buffer`.
+    buffer_start: Instant,
+
+    buffer: Vec<f32>,
+    buffer_filled: usize,
+    encoded_buffer: Vec<u8>,
+}
+
+impl AudioEncoder {
+    /// Create an encoder for frames converted to `format`.
+    fn new(format: AudioFormat, config: &OpusConfig) -> Result<Self> {
+        let channel_count = format.channels;
+        let sample_rate = format.sample_rate;
+
+        let packet_duration = Duration::from_secs_f32(config.frame_duration_ms / 1000.0);
+        let frame_size = (sample_rate as f32 * config.frame_duration_ms / 1000.0) as usize;
+        let packet_size = frame_size * channel_count as usize;
+
+        // Surround layouts need channel mapping family 1
+        let mut encoder = opus::MultistreamEncoder::new_surround(
+            sample_rate,
+            channel_count as u8,
+            Self::mapping_family(channel_count),
+            opus::Application::Audio,
+        )?;
+        Self::configure(&mut encoder, config)?;
+        let pre_skip = encoder.lookahead()? as u16;
+
+        Ok(Self {
+            format,
+            encoder,
+            pre_skip,
+            packet_size,
+            packet_duration,
+            buffer_start: Instant::now(),
+            buffer: vec![0.0f32; packet_size],
+            buffer_filled: 0,
+            encoded_buffer: vec![0u8; packet_size * std::mem::size_of::<f32>()],
+        })
+    }
+
+    fn configure(encoder: &mut opus::MultistreamEncoder, config: &OpusConfig) -> Result<()> {
+        encoder.set_bitrate(match config.bitrate {
+            Some(bitrate) => opus::Bitrate::Bits(bitrate as i32),
+            None => opus::Bitrate::Auto,
+        })?;
+        encoder.set_vbr(config.vbr)?;
+        encoder.set_vbr_constraint(config.constrained_vbr)?;
+        encoder.set_complexity(config.complexity as i32)?;
+        encoder.set_signal(match config.signal {
+            OpusSignal::Auto => opus::Signal::Auto,
+            OpusSignal::Voice => opus::Signal::Voice,
+            OpusSignal::Music => opus::Signal::Music,
+        })?;
+        encoder.set_max_bandwidth(match config.max_bandwidth {
+            Some(OpusBandwidth::Narrowband) => opus::Bandwidth::Narrowband,
+            Some(OpusBandwidth::Mediumband) => opus::Bandwidth::Mediumband,
+            Some(OpusBandwidth::Wideband) => opus::Bandwidth::Wideband,
+            Some(OpusBandwidth::Superwideband) => opus::Bandwidth::Superwideband,
+            Some(OpusBandwidth::Fullband) | None => opus::Bandwidth::Fullband,
+        })?;
+        encoder.set_inband_fec(config.fec)?;
+        encoder.set_packet_loss_perc(config.expected_packet_loss as i32)?;
+        encoder.set_dtx(config.dtx)?;
+        if let Some(depth) = config.lsb_depth {
+            encoder.set_lsb_depth(depth as i32)?;
+        }
+
+        Ok(())
+    }
+
+    fn mapping_family(channels: u16) -> u8 {
+        if channels > 2 {
+            1
+        } else {
+            0
+        }
+    }
+
+    fn codec_data(&self) -> AudioCodecData {
+        let format = self.format;
+        let mapping_family = Self::mapping_family(format.channels);
+
+        let mut header = BytesMut::new();
+        header.put_slice(b"OpusHead");
+        header.put_u8(1); // Version
+        header.put_u8(format.channels as u8); // Channel count
+        header.put_u16_le(self.pre_skip); // Pre-skip
+        header.put_u32_le(format.sample_rate); // Sample rate
+        header.put_u16(0); // Gain
+        header.put_u8(mapping_family); // Channel mapping family
+        if mapping_family != 0 {
+            header.put_u8(self.encoder.streams()); // Stream count
+            header.put_u8(self.encoder.coupled_streams()); // Coupled count
+            header.put_slice(self.encoder.mapping()); // Channel mapping
+        }
+
+        AudioCodecData::Opus {
+            ident_header: header.freeze(),
+            channels: format.channels as u8,
+            sample_rate: format.sample_rate,
+            streams: self.encoder.streams(),
+            coupled_streams: self.encoder.coupled_streams(),
+            mapping: Bytes::copy_from_slice(self.encoder.mapping()),
+            pre_skip: self.pre_skip,
+        }
+    }
+
+    /// Drop the incomplete packet and the encoder history, before frames that do not
+    /// follow them.
+    fn reset(&mut self) {
+        self.buffer_filled = 0;
+        if let Err(e) = self.encoder.reset_state() {
+            tracing::error!(?e, "Failed to reset audio encoder");
+        }
+    }
+
+    /// Encode converted `data`, captured at `pts`, publishing every completed packet.
+    fn push(&mut self, converted: &[f32], pts: Instant, data_tx: &broadcast::Sender<Sample>) {
+        let format = self.format;
+        let mut data = converted;
+        while !data.is_empty() {
+            if self.buffer_filled == 0 {
+                // Packets are timed from their first frame
+                let frames = (converted.len() - data.len()) / format.channels as usize;
+                self.buffer_start =
+                    pts + Duration::from_secs_f64(frames as f64 / format.sample_rate as f64);
+            }
+
+            let to_copy = std::cmp::min(data.len(), self.packet_size - self.buffer_filled);
+            self.buffer[self.buffer_filled..self.buffer_filled + to_copy]
+                .copy_from_slice(&data[..to_copy]);
+            self.buffer_filled += to_copy;
+            data = &data[to_copy..];
+
+            if self.buffer_filled == self.packet_size {
+                tracing::trace!("Got a full audio packet, encoding it");
+                match self
+                    .encoder
+                    .encode_f32(&self.buffer, &mut self.encoded_buffer)
+                {
+                    Ok(len) => {
+                        let sample = Sample::new(
+                            Bytes::copy_from_slice(&self.encoded_buffer[..len]),
+                            self.buffer_start,
+                            self.packet_duration,
+                        );
+                        data_tx.send(sample).ok();
+                    }
+                    Err(e) => {
+                        tracing::error!(?e, "Failed to encode audio");
+                    }
+                }
+                self.buffer_filled = 0;
+            }
+        }
+    }
+}
+
+/// Encode the audio of a source to Opus, and to AAC if it is configured.
+fn audio_thread(
+    events_rx: channel::Receiver<AudioEvent>,
+    config: AudioConfig,
+    data_tx: broadcast::Sender<Sample>,
+    aac_data_tx: broadcast::Sender<Sample>,
+    audio_codec_data_tx: watch::Sender<Option<AudioCodecData>>,
+    aac_codec_data_tx: watch::Sender<Option<AudioCodecData>>,
+) -> Result<()> {
+    let mut converter: Option<Converter> = None;
+    let mut converted = vec![];
+    let mut encoder: Option<AudioEncoder> = None;
+    let mut aac_encoder: Option<AacEncoder> = None;
+    // Whether Opus and AAC were encoded from the previous frames
+    let mut encoding = (false, false);
+
+    for event in events_rx {
+        match event {
+            AudioEvent::Format(format) => {
+                tracing::info!(?format, "Audio format changed");
+
+                let new_converter = Converter::new(format);
+                let output_format = new_converter.output_format();
+                converter = Some(new_converter);
+
+                encoder = match AudioEncoder::new(output_format, &config.opus) {
+                    Ok(encoder) => {
+                        audio_codec_data_tx.send(Some(encoder.codec_data())).ok();
+                        Some(encoder)
+                    }
+                    Err(e) => {
+                        tracing::error!(?e, "Failed to create audio encoder");
+                        None
+                    }
+                };
+
+                if let Some(config) = config.aac.as_ref() {
+                    aac_encoder = match AacEncoder::new(output_format, config) {
+                        Ok(encoder) => {
+                            aac_codec_data_tx.send(Some(encoder.codec_data())).ok();
+                            Some(encoder)
+                        }
+                        Err(e) => {
+                            tracing::error!(?e, "Failed to create AAC encoder");
+                            None
+                        }
+                    };
+                }
+            }
+            AudioEvent::Frames { data, timestamp } => {
+                // Each codec is only encoded while someone receives it
+                let opus = data_tx.receiver_count() > 0;
+                let aac = aac_data_tx.receiver_count() > 0;
+                let (was_opus, was_aac) = std::mem::replace(&mut encoding, (opus, aac));
+                if !opus && !aac {
+                    continue;
+                }
+
+                let converter = if let Some(converter) = converter.as_mut() {
+                    converter
+                } else {
+                    continue;
+                };
+                // Frames were skipped while nobody was listening, the buffered ones
+                // would be joined to frames that do not follow them
+                if !was_opus && !was_aac {
+                    converter.reset();
+                }
+                converted.clear();
+                converter.process(&data, &mut converted);
+
+                match encoder.as_mut() {
+                    Some(encoder) if opus => {
+                        if !was_opus {
+                            encoder.reset();
+                        }
+                        encoder.push(&converted, timestamp, &data_tx);
+                    }
+                    _ => {}
+                }
+                match aac_encoder.as_mut() {
+                    Some(encoder) if aac => {
+                        if !was_aac {
+                            encoder.reset();
+                        }
+                        encoder.push(&converted, timestamp, &aac_data_tx);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn create_source(config: &AudioSourceConfig) -> Result<Box<dyn AudioSource>> {
+    let source: Box<dyn AudioSource> = match config.clone() {
+        #[cfg(windows)]
+        AudioSourceConfig::Loopback => Box::new(loopback::LoopbackSource),
+        #[cfg(not(windows))]
+        AudioSourceConfig::Loopback => {
+            anyhow::bail!("Loopback capture is only supported on Windows")
+        }
+        AudioSourceConfig::Input { device } => Box::new(InputSource::new(device)),
+        AudioSourceConfig::Sine {
+            frequency,
+            sample_rate,
+            channels,
+        } => Box::new(SineSource::new(
+            frequency,
+            AudioFormat {
+                channels,
+                sample_rate,
+            },
+        )),
+        AudioSourceConfig::Wav { path, looping } => Box::new(WavSource::new(path, looping)),
+    };
+
+    Ok(source)
+}
+
+pub fn setup_audio(
+    audio_codec_data_tx: watch::Sender<Option<AudioCodecData>>,
+    aac_codec_data_tx: watch::Sender<Option<AudioCodecData>>,
+) -> Result<()> {
+    let mut source = create_source(&crate::config::get_config().audio.source)?;
+    let (events_tx, events_rx) = channel::bounded(EVENT_QUEUE_SIZE);
+
+    std::thread::spawn(move || {
+        if let Err(e) = source.run(events_tx) {
+            tracing::error!(?e, "Audio source failed");
+        }
+    });
+
+    let config = crate::config::get_config().audio.clone();
+    let data_tx = crate::get_app().audio_data_tx.clone();
+    let aac_data_tx = crate::get_app().aac_data_tx.clone();
+    std::thread::spawn(move || {
+        if let Err(e) = audio_thread(
+            events_rx,
+            config,
+            data_tx,
+            aac_data_tx,
+            audio_codec_data_tx,
+            aac_codec_data_tx,
+        ) {
+            tracing::error!(?e, "Audio thread failed");
+        }
+    });
+
+    Ok(())
+}
+
+fn create_sink(config: &AudioSinkConfig) -> Box<dyn AudioSink> {
+    match config.clone() {
+        AudioSinkConfig::Output { device } => Box::new(OutputSink::new(device)),
+        AudioSinkConfig::Wav { path } => Box::new(WavSink::new(path)),
+    }
+}
+
+/// Play the audio of one client at a time, the chunks of several clients cannot be
+/// interleaved.
+fn uplink_thread(config: AudioSinkConfig, frames_rx: channel::Receiver<UplinkFrames>) {
+    let mut sink = create_sink(&config);
+    // Client being played and when it last sent audio
+    let mut active: Option<(u64, Instant)> = None;
+
+    for frames in frames_rx {
+        let now = Instant::now();
+        match active {
+            Some((client, _)) if client == frames.client => {}
+            Some((_, last)) if now.duration_since(last) < UPLINK_SWITCH_DELAY => continue,
+            _ => tracing::info!(client = frames.client, "Playing the audio of a client"),
+        }
+        active = Some((frames.client, now));
+
+        if let Err(e) = sink.write(frames.format, &frames.data) {
+            tracing::error!(?e, "Failed to play client audio");
+        }
+    }
+}
+
+/// Start playing the audio sent by clients, returning where to send it if it is enabled.
+pub fn setup_uplink() -> Option<channel::Sender<UplinkFrames>> {
+    let config = crate::config::get_config().audio.uplink.clone()?;
+    let (frames_tx, frames_rx) = channel::bounded(UPLINK_QUEUE_SIZE);
+
+    std::thread::spawn(move || uplink_thread(config, frames_rx));
+
+    Some(frames_tx)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        resample::{MAX_OUTPUT_CHANNELS, OUTPUT_SAMPLE_RATE},
+        *,
+    };
+
+    /// Run `source` through the audio thread, returning the Opus codec data and the
+    /// first `count` packets, fewer if the source ends before.
+    fn encode(
+        mut source: impl AudioSource + 'static,
+        count: usize,
+    ) -> (AudioCodecData, Vec<Sample>) {
+        let (events_tx, events_rx) = channel::bounded(EVENT_QUEUE_SIZE);
+        // Large enough to never lag
+        let (data_tx, mut data_rx) = broadcast::channel(count + 1);
+        let (aac_data_tx, _) = broadcast::channel(1);
+        let (codec_data_tx, codec_data_rx) = watch::channel(None);
+        let (aac_codec_data_tx, _) = watch::channel(None);
+
+        std::thread::spawn(move || source.run(events_tx));
+        std::thread::spawn(move || {
+            audio_thread(
+                events_rx,
+                AudioConfig::default(),
+                data_tx,
+                aac_data_tx,
+                codec_data_tx,
+                aac_codec_data_tx,
+            )
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut samples = vec![];
+        while samples.len() < count {
+            match data_rx.try_recv() {
+                Ok(sample) => samples.push(sample),
+                Err(broadcast::error::TryRecvError::Empty) => {
+                    assert!(Instant::now() < deadline, "Timed out waiting for audio");
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+                Err(broadcast::error::TryRecvError::Closed) => break,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        let codec_data = codec_data_rx.borrow().clone().expect("No codec data");
+        (codec_data, samples)
+    }
+
+    /// Decode stereo Opus packets, dropping the first `skip` frames.
+    fn decode(samples: &[Sample], skip: usize) -> Vec<f32> {
+        let mut decoder = opus::Decoder::new(OUTPUT_SAMPLE_RATE, opus::Channels::Stereo).unwrap();
+        let mut decoded = vec![];
+        for sample in samples {
+            let mut pcm = vec![0f32; 5760 * 2];
+            let frames = decoder
+                .decode_f32(Some(&sample.data), &mut pcm, false)
+                .unwrap();
+            decoded.extend_from_slice(&pcm[..frames * 2]);
+        }
+        decoded.split_off(skip * 2)
+    }
+
+    fn rms(pcm: &[f32]) -> f32 {
+        (pcm.iter().map(|s| s * s).sum::<f32>() / pcm.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn test_sine_source() {
+        let format = AudioFormat {
+            channels: 2,
+            sample_rate: 44100,
+        };
+        let (codec_data, samples) = encode(SineSource::new(1000.0, format), 20);
+
+        // Resampled to the rate of Opus
+        assert_eq!(codec_data.channels(), 2);
+        assert_eq!(codec_data.sample_rate(), OUTPUT_SAMPLE_RATE);
+        assert_eq!(samples.len(), 20);
+
+        let duration = Duration::from_millis(10);
+        for pair in samples.windows(2) {
+            assert_eq!(pair[0].duration, duration);
+            let gap = pair[1].timestamp - pair[0].timestamp;
+            let error = gap.max(duration) - gap.min(duration);
+            assert!(error < Duration::from_millis(1), "{:?}", gap);
+        }
+
+        // Skip the start of the filters, the sine has an RMS of 0.25 / sqrt(2)
+        let decoded = decode(&samples, OUTPUT_SAMPLE_RATE as usize / 20);
+        let level = rms(&decoded);
+        assert!((0.15..0.2).contains(&level), "{}", level);
+    }
+
+    #[test]
+    fn test_sine_source_surround() {
+        let format = AudioFormat {
+            channels: 6,
+            sample_rate: OUTPUT_SAMPLE_RATE,
+        };
+        let (codec_data, samples) = encode(SineSource::new(1000.0, format), 5);
+
+        assert_eq!(codec_data.channels(), 6);
+        assert_eq!(
+            codec_data.multiopus_fmtp().unwrap(),
+            "channel_mapping=0,4,1,2,3,5;num_streams=4;coupled_streams=2"
+        );
+        assert_eq!(samples.len(), 5);
+    }
+
+    #[test]
+    fn test_sine_source_downmix() {
+        let format = AudioFormat {
+            channels: MAX_OUTPUT_CHANNELS + 2,
+            sample_rate: OUTPUT_SAMPLE_RATE,
+        };
+        let (codec_data, samples) = encode(SineSource::new(1000.0, format), 5);
+
+        assert_eq!(codec_data.channels(), 2);
+        assert_eq!(samples.len(), 5);
+    }
+
+    #[test]
+    fn test_wav_source() {
+        let path = std::env::temp_dir().join(format!("vd-wav-source-{}.wav", std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 16000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        // 200 ms of a 500 Hz tone at half scale
+        for i in 0..3200 {
+            let t = i as f32 / 16000.0;
+            let value = (t * 500.0 * std::f32::consts::TAU).sin() * 0.5;
+            writer
+                .write_sample((value * i16::MAX as f32) as i16)
+                .unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let (codec_data, samples) = encode(WavSource::new(path.clone(), false), 100);
+        std::fs::remove_file(&path).ok();
+
+        // Mono is kept, the file ends after its 20 packets, the last one being incomplete
+        assert_eq!(codec_data.channels(), 1);
+        assert_eq!(codec_data.sample_rate(), OUTPUT_SAMPLE_RATE);
+        assert!((19..=20).contains(&samples.len()), "{}", samples.len());
+    }
+}
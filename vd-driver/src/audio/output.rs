@@ -0,0 +1,212 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    FromSample, SampleFormat, SizedSample, Stream, SupportedStreamConfig,
+};
+
+use super::{resample::Converter, sink::AudioSink, source::AudioFormat};
+
+/// Most audio queued for the device, older frames are dropped to bound the latency.
+const MAX_QUEUED: Duration = Duration::from_millis(200);
+const MAX_CHANNELS: usize = 8;
+
+/// Interleaved samples shared with the callback of the stream.
+type Queue = Arc<Mutex<VecDeque<f32>>>;
+
+/// Plays into an output device, such as a virtual cable.
+pub struct OutputSink {
+    /// Name of the device, the default output device if `None`.
+    device: Option<String>,
+    /// Format of the client and its stream, which is `None` if it could not be opened.
+    stream: Option<(AudioFormat, Option<OutputStream>)>,
+    queue: Queue,
+    converted: Vec<f32>,
+}
+
+struct OutputStream {
+    /// Kept alive while playing.
+    _stream: Stream,
+    /// Format of the frames in the queue.
+    format: AudioFormat,
+    /// Resamples the frames of the client if the device does not support its rate.
+    converter: Option<Converter>,
+}
+
+impl OutputSink {
+    pub fn new(device: Option<String>) -> Self {
+        Self {
+            device,
+            stream: None,
+            queue: Default::default(),
+            converted: vec![],
+        }
+    }
+
+    fn find_device(&self, host: &cpal::Host) -> Result<cpal::Device> {
+        match &self.device {
+            Some(name) => host
+                .output_devices()?
+                .find(|d| d.name().map(|n| &n == name).unwrap_or(false))
+                .ok_or_else(|| anyhow!("Output device {} not found", name)),
+            None => host
+                .default_output_device()
+                .ok_or_else(|| anyhow!("No output device found")),
+        }
+    }
+
+    fn open(&self, format: AudioFormat) -> Result<OutputStream> {
+        if format.channels as usize > MAX_CHANNELS {
+            anyhow::bail!("Unsupported channel count: {}", format.channels);
+        }
+
+        let host = cpal::default_host();
+        let device = self.find_device(&host)?;
+        tracing::info!(name = ?device.name(), "Using audio output device");
+
+        // Prefer the rate of the client, which avoids resampling, then its channel count
+        let sample_rate = cpal::SampleRate(format.sample_rate);
+        let supports_rate = |c: &cpal::SupportedStreamConfigRange| {
+            c.min_sample_rate() <= sample_rate && c.max_sample_rate() >= sample_rate
+        };
+        let config = device
+            .supported_output_configs()?
+            .min_by_key(|c| {
+                (
+                    !supports_rate(c),
+                    c.channels() != format.channels,
+                    c.sample_format() != SampleFormat::F32,
+                )
+            })
+            .ok_or_else(|| anyhow!("Output device has no supported config"))?;
+        let config = if supports_rate(&config) {
+            config.with_sample_rate(sample_rate)
+        } else {
+            config.with_max_sample_rate()
+        };
+        tracing::info!(?config, "Using output config");
+
+        let converter = (config.sample_rate() != sample_rate).then(|| {
+            tracing::info!(
+                from = sample_rate.0,
+                to = config.sample_rate().0,
+                "Resampling client audio"
+            );
+            Converter::with_sample_rate(format, config.sample_rate().0)
+        });
+        let format = converter
+            .as_ref()
+            .map_or(format, |converter| converter.output_format());
+
+        self.queue.lock().unwrap().clear();
+        let queue = self.queue.clone();
+        let stream = match config.sample_format() {
+            SampleFormat::F32 => build_output_stream::<f32>(&device, &config, format, queue),
+            SampleFormat::I16 => build_output_stream::<i16>(&device, &config, format, queue),
+            SampleFormat::U16 => build_output_stream::<u16>(&device, &config, format, queue),
+            SampleFormat::I32 => build_output_stream::<i32>(&device, &config, format, queue),
+            sample_format => anyhow::bail!("Unsupported sample format {:?}", sample_format),
+        }?;
+        stream.play().context("Start audio stream")?;
+        tracing::info!("Audio output stream started");
+
+        Ok(OutputStream {
+            _stream: stream,
+            format,
+            converter,
+        })
+    }
+}
+
+impl AudioSink for OutputSink {
+    fn write(&mut self, format: AudioFormat, data: &[f32]) -> Result<()> {
+        if self.stream.as_ref().map(|(f, _)| *f) != Some(format) {
+            // Drop the old stream first, the device may not support two of them
+            self.stream = None;
+            match self.open(format) {
+                Ok(stream) => self.stream = Some((format, Some(stream))),
+                Err(e) => {
+                    // Frames are dropped until the format changes
+                    self.stream = Some((format, None));
+                    return Err(e);
+                }
+            }
+        }
+
+        let stream = match &mut self.stream {
+            Some((_, Some(stream))) => stream,
+            _ => return Ok(()),
+        };
+        let data = match stream.converter.as_mut() {
+            Some(converter) => {
+                self.converted.clear();
+                converter.process(data, &mut self.converted);
+                &self.converted[..]
+            }
+            None => data,
+        };
+
+        let format = stream.format;
+        let max_len = (MAX_QUEUED.as_secs_f64() * format.sample_rate as f64) as usize
+            * format.channels as usize;
+        let mut queue = self.queue.lock().unwrap();
+        queue.extend(data);
+        let excess = queue.len().saturating_sub(max_len);
+        queue.drain(..excess);
+
+        Ok(())
+    }
+}
+
+fn build_output_stream<T>(
+    device: &cpal::Device,
+    config: &SupportedStreamConfig,
+    format: AudioFormat,
+    queue: Queue,
+) -> Result<Stream>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let in_channels = format.channels as usize;
+    let out_channels = config.channels() as usize;
+
+    let stream = device.build_output_stream(
+        &config.config(),
+        move |data: &mut [T], _callback_info| {
+            let mut queue = queue.lock().unwrap();
+            for frame in data.chunks_exact_mut(out_channels) {
+                if queue.len() < in_channels {
+                    // Nothing was received in time
+                    frame.fill(T::EQUILIBRIUM);
+                    continue;
+                }
+
+                let mut input = [0.0f32; MAX_CHANNELS];
+                for value in &mut input[..in_channels] {
+                    *value = queue.pop_front().unwrap_or_default();
+                }
+
+                // Mono is played on every channel, extra channels are silent
+                for (c, sample) in frame.iter_mut().enumerate() {
+                    let value = match in_channels {
+                        1 => input[0],
+                        _ if c < in_channels => input[c],
+                        _ => 0.0,
+                    };
+                    *sample = T::from_sample(value);
+                }
+            }
+        },
+        move |err| {
+            tracing::error!(?err, "Audio output stream error");
+        },
+        None,
+    )?;
+
+    Ok(stream)
+}
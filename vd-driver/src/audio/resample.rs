@@ -0,0 +1,390 @@
+//! Conversion of captured audio to a format supported by the encoder.
+
+use std::f64::consts::PI;
+
+use super::source::AudioFormat;
+
+/// Rate of the encoded audio, the native rate of Opus.
+pub const OUTPUT_SAMPLE_RATE: u32 = 48000;
+/// Most channels Opus has a surround layout for, more are down-mixed to stereo.
+pub const MAX_OUTPUT_CHANNELS: u16 = 8;
+
+/// Input frames on each side of an output frame used by the resampling filter.
+const FILTER_HALF_LENGTH: usize = 16;
+const FILTER_LENGTH: usize = FILTER_HALF_LENGTH * 2;
+/// Cutoff of the anti-aliasing filter, relative to the lowest Nyquist frequency.
+const FILTER_CUTOFF: f64 = 0.97;
+/// Most filter phases computed in advance. Rates with a larger ratio use the nearest phase.
+const MAX_PHASES: usize = 1024;
+
+/// Down-mixes, reorders and resamples interleaved frames to the format of the encoder.
+///
+/// Surround frames are delivered in the Vorbis channel order used by Opus.
+pub struct Converter {
+    input: AudioFormat,
+    output: AudioFormat,
+    resampler: Option<Resampler>,
+    mixed: Vec<f32>,
+}
+
+impl Converter {
+    pub fn new(input: AudioFormat) -> Self {
+        Self::with_sample_rate(input, OUTPUT_SAMPLE_RATE)
+    }
+
+    /// Converter to `sample_rate` instead of the rate of the encoder.
+    pub fn with_sample_rate(input: AudioFormat, sample_rate: u32) -> Self {
+        let output = AudioFormat {
+            channels: if input.channels > MAX_OUTPUT_CHANNELS {
+                2
+            } else {
+                input.channels
+            },
+            sample_rate,
+        };
+
+        let resampler = (input.sample_rate != output.sample_rate).then(|| {
+            Resampler::new(
+                output.channels as usize,
+                input.sample_rate,
+                output.sample_rate,
+            )
+        });
+
+        Self {
+            input,
+            output,
+            resampler,
+            mixed: vec![],
+        }
+    }
+
+    /// Forget the frames held back by the resampler, before frames that do not follow them.
+    pub fn reset(&mut self) {
+        *self = Self::with_sample_rate(self.input, self.output.sample_rate);
+    }
+
+    pub fn output_format(&self) -> AudioFormat {
+        self.output
+    }
+
+    /// Convert `data`, appending the converted frames to `out`.
+    pub fn process(&mut self, data: &[f32], out: &mut Vec<f32>) {
+        let channels = self.input.channels as usize;
+        let mixed = if self.input.channels != self.output.channels {
+            self.mixed.clear();
+            downmix_to_stereo(data, channels, &mut self.mixed);
+            &self.mixed[..]
+        } else if let Some(order) = vorbis_order(channels) {
+            self.mixed.clear();
+            self.mixed.reserve(data.len());
+            for frame in data.chunks_exact(channels) {
+                self.mixed.extend(order.iter().map(|&i| frame[i]));
+            }
+            &self.mixed[..]
+        } else {
+            data
+        };
+
+        match self.resampler.as_mut() {
+            Some(resampler) => resampler.process(mixed, out),
+            None => out.extend_from_slice(mixed),
+        }
+    }
+}
+
+/// Input channel of each Vorbis channel, for layouts whose order differs.
+///
+/// Input channels are expected in the usual order: front left and right, center, LFE,
+/// then back and side pairs.
+pub fn vorbis_order(channels: usize) -> Option<&'static [usize]> {
+    match channels {
+        // L, C, R
+        3 => Some(&[0, 2, 1]),
+        // FL, C, FR, RL, RR
+        5 => Some(&[0, 2, 1, 3, 4]),
+        // FL, C, FR, RL, RR, LFE
+        6 => Some(&[0, 2, 1, 4, 5, 3]),
+        // FL, C, FR, SL, SR, RC, LFE
+        7 => Some(&[0, 2, 1, 5, 6, 4, 3]),
+        // FL, C, FR, SL, SR, RL, RR, LFE
+        8 => Some(&[0, 2, 1, 6, 7, 4, 5, 3]),
+        _ => None,
+    }
+}
+
+/// Down-mix frames with `channels` channels to stereo, appending them to `out`.
+///
+/// Channels are expected in the same order as for `vorbis_order`. The coefficients are
+/// those of ITU-R BS.775: the center and surround channels are mixed at -3 dB and the
+/// LFE channel is dropped. Loud surround content can exceed full scale, which Opus keeps.
+fn downmix_to_stereo(data: &[f32], channels: usize, out: &mut Vec<f32>) {
+    const SURROUND_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+    // Gains to the left and right output of each channel
+    let gains: Vec<(f32, f32)> = (0..channels)
+        .map(|i| match i {
+            0 => (1.0, 0.0),
+            1 => (0.0, 1.0),
+            2 => (SURROUND_GAIN, SURROUND_GAIN),
+            3 => (0.0, 0.0),
+            i if i % 2 == 0 => (SURROUND_GAIN, 0.0),
+            _ => (0.0, SURROUND_GAIN),
+        })
+        .collect();
+
+    out.reserve(data.len() / channels * 2);
+    for frame in data.chunks_exact(channels) {
+        let (mut left, mut right) = (0.0, 0.0);
+        for (sample, (left_gain, right_gain)) in frame.iter().zip(&gains) {
+            left += sample * left_gain;
+            right += sample * right_gain;
+        }
+        out.push(left);
+        out.push(right);
+    }
+}
+
+/// Windowed sinc resampler for arbitrary rates.
+///
+/// Output frames fall on `up` phases between two input frames, whose filters are
+/// computed once.
+struct Resampler {
+    channels: usize,
+    /// The output rate is `up / down` times the input rate, reduced to lowest terms.
+    up: usize,
+    down: usize,
+    /// Position of the next output frame in `history`: `center + phase / up` input frames.
+    center: usize,
+    phase: usize,
+    /// Input frames that are still needed, interleaved.
+    history: Vec<f32>,
+    /// Number of filters between two input frames, `up` unless limited by `MAX_PHASES`.
+    phases: usize,
+    /// Filter of each phase, `FILTER_LENGTH` weights each.
+    filters: Vec<f32>,
+}
+
+impl Resampler {
+    fn new(channels: usize, input_rate: u32, output_rate: u32) -> Self {
+        let divisor = gcd(input_rate, output_rate);
+        let up = (output_rate / divisor) as usize;
+        let down = (input_rate / divisor) as usize;
+
+        let cutoff = (output_rate as f64 / input_rate as f64).min(1.0) * FILTER_CUTOFF;
+        let phases = up.min(MAX_PHASES);
+        // With fewer phases than output positions, the nearest filter can be the one
+        // at the next input frame
+        let filter_count = if phases < up { phases + 1 } else { phases };
+        let mut filters = Vec::with_capacity(filter_count * FILTER_LENGTH);
+        for phase in 0..filter_count {
+            let fraction = phase as f64 / phases as f64;
+            let weights: Vec<f64> = (0..FILTER_LENGTH)
+                .map(|i| {
+                    let x = i as f64 + 1.0 - FILTER_HALF_LENGTH as f64 - fraction;
+                    cutoff * sinc(x * cutoff) * blackman(x)
+                })
+                .collect();
+            // Unity gain at DC, whatever the phase
+            let sum: f64 = weights.iter().sum();
+            filters.extend(weights.iter().map(|w| (w / sum) as f32));
+        }
+
+        Self {
+            channels,
+            up,
+            down,
+            // Silence before the first frame
+            center: FILTER_HALF_LENGTH - 1,
+            phase: 0,
+            history: vec![0.0; (FILTER_HALF_LENGTH - 1) * channels],
+            phases,
+            filters,
+        }
+    }
+
+    fn process(&mut self, data: &[f32], out: &mut Vec<f32>) {
+        self.history.extend_from_slice(data);
+        let frames = self.history.len() / self.channels;
+
+        // The filter covers the frames from `center + 1 - FILTER_HALF_LENGTH`
+        // to `center + FILTER_HALF_LENGTH`.
+        while self.center + FILTER_HALF_LENGTH < frames {
+            // Nearest phase, exact unless limited by `MAX_PHASES`
+            let phase = (self.phase * self.phases + self.up / 2) / self.up;
+            let weights = &self.filters[phase * FILTER_LENGTH..][..FILTER_LENGTH];
+
+            let first = self.center + 1 - FILTER_HALF_LENGTH;
+            for channel in 0..self.channels {
+                let mut value = 0.0;
+                for (i, weight) in weights.iter().enumerate() {
+                    value += self.history[(first + i) * self.channels + channel] * weight;
+                }
+                out.push(value);
+            }
+
+            self.phase += self.down;
+            self.center += self.phase / self.up;
+            self.phase %= self.up;
+        }
+
+        // Drop the frames no output frame depends on anymore
+        let consumed = (self.center + 1).saturating_sub(FILTER_HALF_LENGTH);
+        let consumed = consumed.min(frames);
+        self.history.drain(..consumed * self.channels);
+        self.center -= consumed;
+    }
+}
+
+fn gcd(mut a: u32, mut b: u32) -> u32 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Blackman window over the length of the filter, centered on 0.
+fn blackman(x: f64) -> f64 {
+    let t = x / FILTER_HALF_LENGTH as f64;
+    if t.abs() >= 1.0 {
+        0.0
+    } else {
+        0.42 + 0.5 * (PI * t).cos() + 0.08 * (2.0 * PI * t).cos()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Resample one second of `input_rate` audio, fed in 10 ms chunks.
+    fn resample(channels: usize, input_rate: u32, signal: impl Fn(f64) -> f32) -> Vec<f32> {
+        let mut resampler = Resampler::new(channels, input_rate, OUTPUT_SAMPLE_RATE);
+        let input: Vec<f32> = (0..input_rate)
+            .flat_map(|i| {
+                let sample = signal(i as f64 / input_rate as f64);
+                vec![sample; channels]
+            })
+            .collect();
+
+        let mut out = vec![];
+        for chunk in input.chunks(input_rate as usize / 100 * channels) {
+            resampler.process(chunk, &mut out);
+        }
+        out
+    }
+
+    fn assert_output_length(input_rate: u32) {
+        let out = resample(2, input_rate, |_| 0.0);
+        let frames = out.len() / 2;
+        // Up to the filter length is held back for the next input
+        let latency = FILTER_HALF_LENGTH * OUTPUT_SAMPLE_RATE as usize / input_rate as usize;
+        assert!(frames <= OUTPUT_SAMPLE_RATE as usize, "{} frames", frames);
+        assert!(
+            frames + latency + 1 >= OUTPUT_SAMPLE_RATE as usize,
+            "{} frames",
+            frames
+        );
+    }
+
+    #[test]
+    fn test_output_length_upsample() {
+        assert_output_length(44100);
+    }
+
+    #[test]
+    fn test_output_length_downsample() {
+        assert_output_length(96000);
+    }
+
+    #[test]
+    fn test_chunking() {
+        let signal = |t: f64| (2.0 * PI * 440.0 * t).sin() as f32;
+        let chunked = resample(1, 44100, signal);
+
+        let mut resampler = Resampler::new(1, 44100, OUTPUT_SAMPLE_RATE);
+        let input: Vec<f32> = (0..44100).map(|i| signal(i as f64 / 44100.0)).collect();
+        let mut whole = vec![];
+        resampler.process(&input, &mut whole);
+
+        assert_eq!(chunked, whole);
+    }
+
+    #[test]
+    fn test_dc_gain() {
+        for input_rate in [22050, 44100, 96000] {
+            let out = resample(2, input_rate, |_| 0.5);
+            // Skip the frames mixed with the silence before the input
+            for sample in &out[FILTER_LENGTH * 4..] {
+                assert!((sample - 0.5).abs() < 1e-4, "{} at {}", sample, input_rate);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tone() {
+        const FREQUENCY: f64 = 1000.0;
+        let signal = |t: f64| (0.5 * (2.0 * PI * FREQUENCY * t).sin()) as f32;
+
+        for input_rate in [44100, 96000] {
+            let out = resample(1, input_rate, signal);
+            // Output frames are aligned with the input, without delay
+            for (i, sample) in out.iter().enumerate().skip(FILTER_LENGTH * 4) {
+                let expected = signal(i as f64 / OUTPUT_SAMPLE_RATE as f64);
+                assert!(
+                    (sample - expected).abs() < 1e-2,
+                    "{} instead of {} at {}",
+                    sample,
+                    expected,
+                    input_rate
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_nearest_phase() {
+        // Coprime with the output rate, so that there are more positions than phases
+        const INPUT_RATE: u32 = 44101;
+        const FREQUENCY: f64 = 10000.0;
+        let signal = |t: f64| (0.5 * (2.0 * PI * FREQUENCY * t).sin()) as f32;
+        assert_eq!(gcd(INPUT_RATE, OUTPUT_SAMPLE_RATE), 1);
+
+        let out = resample(1, INPUT_RATE, signal);
+        // Off by at most half a phase, a whole one would double the error
+        for (i, sample) in out.iter().enumerate().skip(FILTER_LENGTH * 4) {
+            let expected = signal(i as f64 / OUTPUT_SAMPLE_RATE as f64);
+            assert!(
+                (sample - expected).abs() < 5e-4,
+                "{} instead of {} at {}",
+                sample,
+                expected,
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn test_downmix() {
+        let mut out = vec![];
+        // Front left, front right, center, LFE, back left, back right
+        let frames = [
+            [1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0, 0.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 0.0, 0.0, 0.0, 1.0],
+        ];
+        downmix_to_stereo(&frames.concat(), 6, &mut out);
+
+        let gain = std::f32::consts::FRAC_1_SQRT_2;
+        assert_eq!(out, [1.0, 0.0, gain, gain, 0.0, 0.0, 0.0, gain]);
+    }
+}
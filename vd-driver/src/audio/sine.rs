@@ -0,0 +1,50 @@
+use std::{f32::consts::TAU, time::Duration};
+
+use anyhow::Result;
+use crossbeam::channel;
+
+use super::source::{AudioEvent, AudioFormat, AudioSource, RealTimeClock};
+
+const CHUNK_DURATION: Duration = Duration::from_millis(10);
+const AMPLITUDE: f32 = 0.25;
+
+/// Generates a sine wave in real time, to test without audio devices.
+pub struct SineSource {
+    frequency: f32,
+    format: AudioFormat,
+}
+
+impl SineSource {
+    pub fn new(frequency: f32, format: AudioFormat) -> Self {
+        Self { frequency, format }
+    }
+}
+
+impl AudioSource for SineSource {
+    fn run(&mut self, events_tx: channel::Sender<AudioEvent>) -> Result<()> {
+        events_tx.send(AudioEvent::Format(self.format))?;
+
+        let channels = self.format.channels as usize;
+        let chunk_frames = self.format.sample_rate as usize / 100;
+        let step = TAU * self.frequency / self.format.sample_rate as f32;
+        let mut phase = 0.0f32;
+
+        let mut clock = RealTimeClock::new(CHUNK_DURATION);
+        loop {
+            let mut data = Vec::with_capacity(chunk_frames * channels);
+            for _ in 0..chunk_frames {
+                let value = phase.sin() * AMPLITUDE;
+                data.extend(std::iter::repeat(value).take(channels));
+                phase = (phase + step) % TAU;
+            }
+
+            let event = AudioEvent::Frames {
+                data,
+                timestamp: clock.tick(),
+            };
+            if events_tx.send(event).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
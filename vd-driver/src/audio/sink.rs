@@ -0,0 +1,12 @@
+use anyhow::Result;
+
+use super::source::AudioFormat;
+
+/// Plays the audio sent by clients, e.g. into a virtual cable used as a microphone.
+///
+/// Sinks are created on the thread writing to them, as audio streams cannot be moved
+/// to other threads on every platform.
+pub trait AudioSink {
+    /// Play interleaved frames. The format only changes when a client reconfigures.
+    fn write(&mut self, format: AudioFormat, data: &[f32]) -> Result<()>;
+}
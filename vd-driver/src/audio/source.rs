@@ -0,0 +1,132 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use cpal::{
+    traits::DeviceTrait, FromSample, SampleFormat, SizedSample, Stream, SupportedStreamConfig,
+};
+use crossbeam::channel;
+
+/// Format of the frames delivered by a source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioFormat {
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+pub enum AudioEvent {
+    /// Following frames use this format. Always sent before the first frames.
+    Format(AudioFormat),
+    /// Interleaved frames, the first one captured at `timestamp`.
+    Frames { data: Vec<f32>, timestamp: Instant },
+}
+
+/// Produces the audio to stream, e.g. by capturing a device.
+pub trait AudioSource: Send {
+    /// Deliver audio to `events_tx` until it is disconnected or the source fails.
+    fn run(&mut self, events_tx: channel::Sender<AudioEvent>) -> Result<()>;
+}
+
+/// Paces sources generating audio, so that they deliver it at the rate it is played.
+pub(super) struct RealTimeClock {
+    next: Instant,
+    period: Duration,
+}
+
+impl RealTimeClock {
+    /// A clock ticking every `period`, starting now.
+    pub fn new(period: Duration) -> Self {
+        Self {
+            next: Instant::now(),
+            period,
+        }
+    }
+
+    /// Wait for the next tick, returning its time.
+    pub fn tick(&mut self) -> Instant {
+        let now = Instant::now();
+        if self.next > now {
+            std::thread::sleep(self.next - now);
+        } else if now - self.next > self.period * 10 {
+            // Do not try to catch up after a long stall
+            self.next = now;
+        }
+
+        let tick = self.next;
+        self.next += self.period;
+        tick
+    }
+}
+
+/// Why a stream built by `build_input_stream` stopped.
+pub(super) enum StreamEnd {
+    /// The receiver of the events was dropped.
+    Disconnected,
+    Error(cpal::StreamError),
+}
+
+/// Capture `device` with `config`, sending its format and then its frames to `events_tx`.
+///
+/// Frames are dropped if the encoder does not keep up, the callback must not block.
+/// The returned receiver is notified when the stream stops delivering frames.
+pub(super) fn build_input_stream(
+    device: &cpal::Device,
+    config: &SupportedStreamConfig,
+    events_tx: &channel::Sender<AudioEvent>,
+) -> Result<(Stream, channel::Receiver<StreamEnd>)> {
+    events_tx.send(AudioEvent::Format(AudioFormat {
+        channels: config.channels(),
+        sample_rate: config.sample_rate().0,
+    }))?;
+
+    let (end_tx, end_rx) = channel::bounded(1);
+    let stream = match config.sample_format() {
+        SampleFormat::F32 => build_typed_input_stream::<f32>(device, config, events_tx, end_tx),
+        SampleFormat::I16 => build_typed_input_stream::<i16>(device, config, events_tx, end_tx),
+        SampleFormat::U16 => build_typed_input_stream::<u16>(device, config, events_tx, end_tx),
+        SampleFormat::I32 => build_typed_input_stream::<i32>(device, config, events_tx, end_tx),
+        format => bail!("Unsupported sample format {:?}", format),
+    }?;
+
+    Ok((stream, end_rx))
+}
+
+fn build_typed_input_stream<T>(
+    device: &cpal::Device,
+    config: &SupportedStreamConfig,
+    events_tx: &channel::Sender<AudioEvent>,
+    end_tx: channel::Sender<StreamEnd>,
+) -> Result<Stream>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let events_tx = events_tx.clone();
+    let end_tx_ = end_tx.clone();
+    let stream = device.build_input_stream(
+        &config.config(),
+        move |data: &[T], _callback_info| {
+            let event = AudioEvent::Frames {
+                data: data.iter().map(|s| f32::from_sample(*s)).collect(),
+                timestamp: Instant::now(),
+            };
+            match events_tx.try_send(event) {
+                Ok(()) => {}
+                Err(channel::TrySendError::Full(_)) => {
+                    tracing::trace!("Audio encoder busy, dropping frames");
+                }
+                Err(channel::TrySendError::Disconnected(_)) => {
+                    end_tx_.try_send(StreamEnd::Disconnected).ok();
+                }
+            }
+        },
+        move |err| {
+            tracing::error!(?err, "Audio stream error");
+            if let cpal::StreamError::DeviceNotAvailable = err {
+                end_tx.try_send(StreamEnd::Error(err)).ok();
+            }
+        },
+        None,
+    )?;
+
+    Ok(stream)
+}
@@ -0,0 +1,93 @@
+//! Audio sent by clients, such as their microphone, decoded and played into a sink.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Result;
+use crossbeam::channel;
+
+use super::{resample::OUTPUT_SAMPLE_RATE, source::AudioFormat};
+
+/// Longest Opus packet, 120ms.
+const MAX_FRAME_SIZE: usize = OUTPUT_SAMPLE_RATE as usize / 1000 * 120;
+
+static NEXT_CLIENT: AtomicU64 = AtomicU64::new(0);
+
+/// Decoded frames of a client.
+pub struct UplinkFrames {
+    /// Identifies the client, the sink plays a single one at a time.
+    pub client: u64,
+    pub format: AudioFormat,
+    pub data: Vec<f32>,
+}
+
+/// Decodes the Opus packets of one client, sending the frames to the sink.
+pub struct UplinkDecoder {
+    client: u64,
+    decoder: opus::Decoder,
+    format: AudioFormat,
+    /// Samples per channel of the last packet, used to conceal lost ones.
+    last_frame_size: usize,
+    frames_tx: channel::Sender<UplinkFrames>,
+}
+
+impl UplinkDecoder {
+    pub fn new(channels: u8, frames_tx: channel::Sender<UplinkFrames>) -> Result<Self> {
+        let (decoder, format) = new_decoder(channels)?;
+
+        Ok(Self {
+            client: NEXT_CLIENT.fetch_add(1, Ordering::Relaxed),
+            decoder,
+            format,
+            last_frame_size: OUTPUT_SAMPLE_RATE as usize / 50,
+            frames_tx,
+        })
+    }
+
+    /// Decode packets with `channels` channels from now on, for the same client.
+    pub fn reconfigure(&mut self, channels: u8) -> Result<()> {
+        (self.decoder, self.format) = new_decoder(channels)?;
+        Ok(())
+    }
+
+    /// Decode `packet`, or conceal a lost packet if there is none.
+    pub fn push(&mut self, packet: Option<&[u8]>) -> Result<()> {
+        let frame_size = match packet {
+            Some(packet) => self.decoder.nb_samples(packet)?.min(MAX_FRAME_SIZE),
+            None => self.last_frame_size,
+        };
+
+        let mut data = vec![0.0; frame_size * self.format.channels as usize];
+        let samples = self.decoder.decode_f32(packet, &mut data, false)?;
+        data.truncate(samples * self.format.channels as usize);
+        if packet.is_some() {
+            self.last_frame_size = samples;
+        }
+
+        // Drop the frames if the sink does not keep up
+        self.frames_tx
+            .try_send(UplinkFrames {
+                client: self.client,
+                format: self.format,
+                data,
+            })
+            .ok();
+        Ok(())
+    }
+}
+
+fn new_decoder(channels: u8) -> Result<(opus::Decoder, AudioFormat)> {
+    let format = AudioFormat {
+        channels: channels as u16,
+        sample_rate: OUTPUT_SAMPLE_RATE,
+    };
+    let decoder = opus::Decoder::new(
+        format.sample_rate,
+        match channels {
+            1 => opus::Channels::Mono,
+            2 => opus::Channels::Stereo,
+            _ => anyhow::bail!("Unsupported channel count: {}", channels),
+        },
+    )?;
+
+    Ok((decoder, format))
+}
@@ -0,0 +1,125 @@
+use std::{fs::File, io::BufWriter, path::PathBuf, time::Duration};
+
+use anyhow::{Context, Result};
+use crossbeam::channel;
+
+use super::{
+    sink::AudioSink,
+    source::{AudioEvent, AudioFormat, AudioSource, RealTimeClock},
+};
+
+const CHUNK_DURATION: Duration = Duration::from_millis(10);
+
+/// Plays a WAV file in real time, e.g. for reproducible tests.
+///
+/// The whole file is decoded in memory, so it should be short.
+pub struct WavSource {
+    path: PathBuf,
+    looping: bool,
+}
+
+impl WavSource {
+    pub fn new(path: PathBuf, looping: bool) -> Self {
+        Self { path, looping }
+    }
+
+    fn read(&self) -> Result<(AudioFormat, Vec<f32>)> {
+        let mut reader = hound::WavReader::open(&self.path)
+            .with_context(|| format!("Open {}", self.path.display()))?;
+        let spec = reader.spec();
+
+        let samples = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|s| s.map(|s| s as f32 * scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+
+        let format = AudioFormat {
+            channels: spec.channels,
+            sample_rate: spec.sample_rate,
+        };
+        Ok((format, samples))
+    }
+}
+
+impl AudioSource for WavSource {
+    fn run(&mut self, events_tx: channel::Sender<AudioEvent>) -> Result<()> {
+        let (format, samples) = self.read()?;
+        tracing::info!(path = %self.path.display(), ?format, "Playing WAV file");
+
+        events_tx.send(AudioEvent::Format(format))?;
+
+        let chunk_len = format.sample_rate as usize / 100 * format.channels as usize;
+        if samples.is_empty() || chunk_len == 0 {
+            return Ok(());
+        }
+
+        let mut clock = RealTimeClock::new(CHUNK_DURATION);
+        loop {
+            for chunk in samples.chunks(chunk_len) {
+                let timestamp = clock.tick();
+                let event = AudioEvent::Frames {
+                    data: chunk.to_vec(),
+                    timestamp,
+                };
+                if events_tx.send(event).is_err() {
+                    return Ok(());
+                }
+            }
+
+            if !self.looping {
+                tracing::info!("End of WAV file");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Records the audio sent by clients as 32-bit float samples, e.g. for tests.
+///
+/// The file is truncated when the format changes.
+pub struct WavSink {
+    path: PathBuf,
+    writer: Option<(AudioFormat, hound::WavWriter<BufWriter<File>>)>,
+}
+
+impl WavSink {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, writer: None }
+    }
+}
+
+impl AudioSink for WavSink {
+    fn write(&mut self, format: AudioFormat, data: &[f32]) -> Result<()> {
+        if self.writer.as_ref().map(|(f, _)| *f) != Some(format) {
+            if let Some((_, writer)) = self.writer.take() {
+                writer.finalize()?;
+            }
+
+            let spec = hound::WavSpec {
+                channels: format.channels,
+                sample_rate: format.sample_rate,
+                bits_per_sample: 32,
+                sample_format: hound::SampleFormat::Float,
+            };
+            let writer = hound::WavWriter::create(&self.path, spec)
+                .with_context(|| format!("Create {}", self.path.display()))?;
+            tracing::info!(path = %self.path.display(), ?format, "Recording WAV file");
+            self.writer = Some((format, writer));
+        }
+
+        let (_, writer) = self.writer.as_mut().unwrap();
+        for sample in data {
+            writer.write_sample(*sample)?;
+        }
+        // Keep the header valid, the file may be read while recording
+        writer.flush()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+//! Clock shared by all media, so audio and video stay in sync on every transport.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use once_cell::sync::OnceCell;
+
+pub static MEDIA_CLOCK: OnceCell<MediaClock> = OnceCell::new();
+pub fn get_media_clock() -> &'static MediaClock {
+    MEDIA_CLOCK.get().unwrap()
+}
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970).
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Media time of a capture instant, as the time elapsed since the start of the process.
+///
+/// The wall-clock time is only read once, so media time never jumps when the system
+/// clock is adjusted.
+#[derive(Debug)]
+pub struct MediaClock {
+    epoch: Instant,
+    wall_clock: SystemTime,
+}
+
+impl MediaClock {
+    /// Media time of `instant`, zero for instants before the epoch.
+    pub fn media_time(&self, instant: Instant) -> Duration {
+        instant.saturating_duration_since(self.epoch)
+    }
+
+    /// Instant of the media time `time`, e.g. a timestamp reported by a client.
+    pub fn instant(&self, time: Duration) -> Instant {
+        self.epoch + time
+    }
+
+    /// Media time of `instant` in units of `clock_rate`, wrapping like RTP timestamps.
+    pub fn rtp_timestamp(&self, instant: Instant, clock_rate: u32) -> u32 {
+        let time = self.media_time(instant);
+        let ticks = time.as_secs() * clock_rate as u64
+            + time.subsec_nanos() as u64 * clock_rate as u64 / 1_000_000_000;
+        ticks as u32
+    }
+
+    /// Wall-clock time of `instant`.
+    pub fn wall_clock(&self, instant: Instant) -> SystemTime {
+        self.wall_clock + self.media_time(instant)
+    }
+
+    /// Wall-clock time of `instant` in the 64-bit NTP format of RTCP sender reports.
+    pub fn ntp_timestamp(&self, instant: Instant) -> u64 {
+        let since_unix = self
+            .wall_clock(instant)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let seconds = since_unix.as_secs() + NTP_UNIX_OFFSET;
+        let fraction = ((since_unix.subsec_nanos() as u64) << 32) / 1_000_000_000;
+        (seconds << 32) | fraction
+    }
+}
+
+/// Start the media clock, before anything is captured.
+pub fn init() {
+    let clock = MediaClock {
+        epoch: Instant::now(),
+        wall_clock: SystemTime::now(),
+    };
+    MEDIA_CLOCK.set(clock).unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn clock() -> MediaClock {
+        MediaClock {
+            epoch: Instant::now(),
+            // 2024-01-01T00:00:00.25Z
+            wall_clock: UNIX_EPOCH + Duration::from_millis(1_704_067_200_250),
+        }
+    }
+
+    #[test]
+    fn test_rtp_timestamp() {
+        let clock = clock();
+        assert_eq!(clock.rtp_timestamp(clock.epoch, 90000), 0);
+        assert_eq!(
+            clock.rtp_timestamp(clock.instant(Duration::from_millis(20)), 48000),
+            960
+        );
+        assert_eq!(
+            clock.rtp_timestamp(clock.instant(Duration::new(2, 500_000_000)), 90000),
+            225_000
+        );
+        // Rounded down to a tick
+        assert_eq!(
+            clock.rtp_timestamp(clock.instant(Duration::from_micros(33)), 90000),
+            2
+        );
+        // Wraps after 2^32 ticks, about 13 hours at 90 kHz
+        let wrap = Duration::from_secs((1 << 32) / 90000);
+        let ticks = wrap.as_secs() * 90000;
+        assert_eq!(
+            clock.rtp_timestamp(clock.instant(wrap), 90000),
+            ticks as u32
+        );
+        let after = clock.instant(wrap + Duration::from_secs(1));
+        assert_eq!(
+            clock.rtp_timestamp(after, 90000),
+            (ticks + 90000 - (1 << 32)) as u32
+        );
+    }
+
+    #[test]
+    fn test_ntp_timestamp() {
+        let clock = clock();
+        let seconds = 1_704_067_200 + NTP_UNIX_OFFSET;
+        assert_eq!(clock.ntp_timestamp(clock.epoch), seconds << 32 | 1 << 30);
+        assert_eq!(
+            clock.ntp_timestamp(clock.instant(Duration::from_millis(1750))),
+            (seconds + 2) << 32
+        );
+        assert_eq!(
+            clock.ntp_timestamp(clock.instant(Duration::from_millis(250))),
+            seconds << 32 | 1 << 31
+        );
+    }
+}
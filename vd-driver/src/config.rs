@@ -0,0 +1,820 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use anyhow::{Context, Result};
+use ffmpeg_simple::Codec;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+
+pub static CONFIG: OnceCell<Config> = OnceCell::new();
+pub fn get_config() -> &'static Config {
+    CONFIG.get().unwrap()
+}
+
+/// Environment variable overriding the path of the configuration file.
+const CONFIG_PATH_ENV: &str = "VD_CONFIG";
+const CONFIG_FILE_NAME: &str = "vd-driver.json";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub video: VideoConfig,
+    pub audio: AudioConfig,
+    /// Settings of individual monitors, by connector index.
+    pub monitors: BTreeMap<u32, MonitorConfig>,
+}
+
+impl Config {
+    pub fn monitor(&self, index: u32) -> MonitorConfig {
+        self.monitors.get(&index).cloned().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MonitorConfig {
+    pub codec: VideoCodec,
+    /// Refresh the picture gradually over this many frames instead of sending
+    /// periodic IDR frames. Encoders without intra refresh send IDR frames at this interval.
+    /// Clients that start watching wait for the next refresh.
+    pub intra_refresh_period: Option<u32>,
+    /// Encode full chroma (4:4:4) for sharper text when the encoder supports it,
+    /// falling back to 4:2:0 otherwise.
+    ///
+    /// Few decoders support it, browsers in particular, so clients of the custom TCP
+    /// protocol should check the chroma format in the codec data. WebRTC clients must offer
+    /// a 4:4:4 profile, and RTSP clients must add `chroma=444` to the URI.
+    pub text_mode: bool,
+    /// Split each frame into this many bands of tile rows, each sent as soon as it is
+    /// compressed, so that clients can show the top of a frame before the rest is ready.
+    ///
+    /// Only supported by the raw codec: FFmpeg encoders return whole frames, even when
+    /// they encode several slices per frame.
+    pub slices: Option<u32>,
+}
+
+impl MonitorConfig {
+    fn validate(&self) -> Result<()> {
+        if let Some(period) = self.intra_refresh_period {
+            if period < 2 {
+                anyhow::bail!("Intra refresh period must be at least 2 frames");
+            }
+        }
+        if let Some(slices) = self.slices {
+            if slices < 2 {
+                anyhow::bail!("Slices must be at least 2");
+            }
+            if self.codec != VideoCodec::Raw {
+                anyhow::bail!("Slices are only supported by the raw codec");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoConfig {
+    pub encoder: EncoderPolicy,
+}
+
+/// Hardware encoders tried in order when no preference is configured.
+const DEFAULT_HW_ENCODERS: &[&str] = &["h264_qsv", "h264_nvenc", "h264_amf"];
+const SOFTWARE_ENCODER: &str = "libx264";
+
+/// Which encoders may be used, and how they are tuned.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncoderPolicy {
+    /// Encoders to try, in order. Defaults to all supported hardware encoders.
+    ///
+    /// The software encoder is always appended as the last resort.
+    pub preferred: Vec<String>,
+    /// Encoders that must never be used.
+    pub forbidden: Vec<String>,
+    /// Only use the software encoder.
+    pub force_software: bool,
+    pub preset: EncoderPreset,
+    /// How the encoder recovers when a client reports lost frames.
+    pub recovery: RecoveryMode,
+    /// Temporal layers to encode, so that clients falling behind can drop upper layers.
+    /// With encoders that cannot encode them, only the frames nothing references can
+    /// be dropped.
+    pub scalability: ScalabilityMode,
+    /// Additional options passed to every encoder, taking precedence over the preset.
+    pub options: BTreeMap<String, String>,
+}
+
+impl Default for EncoderPolicy {
+    fn default() -> Self {
+        Self {
+            preferred: vec![],
+            forbidden: vec![],
+            force_software: false,
+            preset: EncoderPreset::LowLatency,
+            recovery: RecoveryMode::IntraRefresh,
+            scalability: ScalabilityMode::L1T1,
+            options: BTreeMap::new(),
+        }
+    }
+}
+
+impl EncoderPolicy {
+    /// Encoders to try, in order of preference.
+    pub fn candidates(&self) -> Vec<String> {
+        let mut candidates: Vec<String> = if self.force_software {
+            vec![]
+        } else if self.preferred.is_empty() {
+            DEFAULT_HW_ENCODERS.iter().map(|s| s.to_string()).collect()
+        } else {
+            self.preferred.clone()
+        };
+
+        if !candidates.iter().any(|c| c == SOFTWARE_ENCODER) {
+            candidates.push(SOFTWARE_ENCODER.to_string());
+        }
+
+        candidates.retain(|c| !self.forbidden.contains(c));
+        candidates
+    }
+
+    /// Recovery mode actually used by `encoder`, which might not support the configured one.
+    ///
+    /// Periodic intra refresh on `monitor` implies intra refresh recovery.
+    pub fn recovery_for(&self, encoder: &str, monitor: &MonitorConfig) -> RecoveryMode {
+        let intra_refresh =
+            self.recovery == RecoveryMode::IntraRefresh || monitor.intra_refresh_period.is_some();
+
+        if intra_refresh && intra_refresh_options(EncoderVendor::from_name(encoder)).is_some() {
+            RecoveryMode::IntraRefresh
+        } else {
+            RecoveryMode::Keyframe
+        }
+    }
+
+    /// Scalability mode actually used by `encoder`, which might not support the configured one.
+    pub fn scalability_for(&self, encoder: &str) -> ScalabilityMode {
+        if scalability_options(EncoderVendor::from_name(encoder), self.scalability).is_some() {
+            self.scalability
+        } else {
+            ScalabilityMode::L1T1
+        }
+    }
+
+    /// Chroma formats to try with `encoder` for `monitor`, in order.
+    pub fn chroma_formats_for(&self, encoder: &str, monitor: &MonitorConfig) -> Vec<ChromaFormat> {
+        if monitor.text_mode && full_chroma_options(EncoderVendor::from_name(encoder)).is_some() {
+            vec![ChromaFormat::Yuv444, ChromaFormat::Yuv420]
+        } else {
+            vec![ChromaFormat::Yuv420]
+        }
+    }
+
+    /// Options to open `encoder` with for `monitor`, encoding `chroma`.
+    pub fn options_for(
+        &self,
+        encoder: &str,
+        monitor: &MonitorConfig,
+        chroma: ChromaFormat,
+    ) -> Vec<(String, String)> {
+        let mut options: BTreeMap<String, String> = BASE_OPTIONS
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        options.extend(
+            self.preset
+                .options(EncoderVendor::from_name(encoder))
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+
+        let vendor = EncoderVendor::from_name(encoder);
+        let recovery_options = match self.recovery_for(encoder, monitor) {
+            RecoveryMode::Keyframe => forced_idr_options(vendor),
+            RecoveryMode::IntraRefresh => intra_refresh_options(vendor).unwrap_or_default(),
+        };
+        options.extend(
+            recovery_options
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+
+        if let Some(period) = monitor.intra_refresh_period {
+            options.extend(intra_refresh_period_options(vendor, period));
+        }
+
+        options.extend(
+            scalability_options(vendor, self.scalability_for(encoder))
+                .unwrap_or_default()
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+
+        if chroma == ChromaFormat::Yuv444 {
+            options.extend(
+                full_chroma_options(vendor)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string())),
+            );
+        }
+
+        options.extend(self.options.clone());
+
+        options.into_iter().collect()
+    }
+
+    fn validate(&self) -> Result<()> {
+        for name in self.preferred.iter().chain(self.forbidden.iter()) {
+            if Codec::find_by_name(name).is_none() {
+                anyhow::bail!("Unknown encoder {}", name);
+            }
+        }
+
+        let candidates = self.candidates();
+        if candidates.is_empty() {
+            anyhow::bail!("All encoders are forbidden");
+        }
+
+        let available: Vec<Codec> = candidates
+            .iter()
+            .filter_map(|c| Codec::find_by_name(c))
+            .collect();
+        if available.is_empty() {
+            anyhow::bail!("None of the encoders {:?} are available", candidates);
+        }
+
+        // Options passed to every encoder may be meant for only some of them
+        for key in self.options.keys() {
+            let ignoring: Vec<&str> = available
+                .iter()
+                .filter(|codec| !codec.has_option(key))
+                .map(|codec| codec.name())
+                .collect();
+            if ignoring.len() == available.len() {
+                anyhow::bail!("None of the encoders {:?} have option {}", candidates, key);
+            }
+            if !ignoring.is_empty() {
+                tracing::warn!(option = %key, encoders = ?ignoring, "Encoders ignore option");
+            }
+        }
+
+        // Presets are written for recent FFmpeg versions
+        for codec in &available {
+            let vendor = EncoderVendor::from_name(codec.name());
+            for (key, _) in self.preset.options(vendor) {
+                if !codec.has_option(key) {
+                    tracing::warn!(
+                        encoder = codec.name(),
+                        option = key,
+                        preset = ?self.preset,
+                        "Encoder does not have preset option"
+                    );
+                }
+            }
+        }
+
+        for codec in &available {
+            let scalability = self.scalability_for(codec.name());
+            if scalability != self.scalability {
+                tracing::warn!(
+                    encoder = codec.name(),
+                    configured = ?self.scalability,
+                    ?scalability,
+                    "Scalability mode not supported, only non-reference frames can be dropped"
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Options applied to every encoder before the preset, unused ones are ignored.
+const BASE_OPTIONS: &[(&str, &str)] = &[("profile", "baseline")];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncoderVendor {
+    Nvidia,
+    Intel,
+    Amd,
+    X264,
+    Other,
+}
+
+impl EncoderVendor {
+    pub fn from_name(name: &str) -> Self {
+        if name.ends_with("_nvenc") {
+            EncoderVendor::Nvidia
+        } else if name.ends_with("_qsv") {
+            EncoderVendor::Intel
+        } else if name.ends_with("_amf") {
+            EncoderVendor::Amd
+        } else if name == "libx264" {
+            EncoderVendor::X264
+        } else {
+            EncoderVendor::Other
+        }
+    }
+}
+
+/// Named tuning presets, mapped to the private options of each vendor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncoderPreset {
+    /// Only the base options, leaving the encoder defaults.
+    Default,
+    /// Low latency without B-frames or lookahead.
+    LowLatency,
+    /// Lowest latency the encoder supports, at the cost of quality.
+    UltraLowLatency,
+}
+
+impl EncoderPreset {
+    pub fn options(&self, vendor: EncoderVendor) -> &'static [(&'static str, &'static str)] {
+        use EncoderPreset::*;
+        use EncoderVendor::*;
+
+        match (self, vendor) {
+            (Default, _) | (_, Other) => &[],
+            (LowLatency, Nvidia) => &[
+                ("preset", "p4"),
+                ("tune", "ll"),
+                ("zerolatency", "1"),
+                ("delay", "0"),
+                ("rc-lookahead", "0"),
+                ("bf", "0"),
+            ],
+            (UltraLowLatency, Nvidia) => &[
+                ("preset", "p1"),
+                ("tune", "ull"),
+                ("zerolatency", "1"),
+                ("delay", "0"),
+                ("rc-lookahead", "0"),
+                ("bf", "0"),
+            ],
+            (LowLatency, Intel) => &[
+                ("async_depth", "1"),
+                ("look_ahead", "0"),
+                ("b_strategy", "0"),
+                ("idr_interval", "1"),
+                ("bf", "0"),
+            ],
+            (UltraLowLatency, Intel) => &[
+                ("low_power", "1"),
+                ("async_depth", "1"),
+                ("look_ahead", "0"),
+                ("b_strategy", "0"),
+                ("idr_interval", "1"),
+                ("bf", "0"),
+            ],
+            (LowLatency, Amd) => &[
+                ("usage", "lowlatency"),
+                ("quality", "balanced"),
+                ("bf", "0"),
+            ],
+            (UltraLowLatency, Amd) => &[
+                ("usage", "ultralowlatency"),
+                ("quality", "speed"),
+                ("bf", "0"),
+            ],
+            (LowLatency, X264) => &[("preset", "veryfast"), ("tune", "zerolatency")],
+            (UltraLowLatency, X264) => &[("preset", "ultrafast"), ("tune", "zerolatency")],
+        }
+    }
+}
+
+/// How lost frames are recovered from.
+///
+/// Long-term references are not supported: predicting from the last frame acknowledged
+/// by the client needs per-frame control to mark a frame as a long-term reference and
+/// to force or invalidate references, and neither `AVCodecContext` options nor
+/// `AVFrame` side data of the FFmpeg wrappers of NVENC, QSV, AMF and x264 give access
+/// to it. That would need encoders driven through the vendor SDKs directly. Intra
+/// refresh is the cheapest alternative to a full IDR frame, and loss reports are
+/// matched against the last recovery, so that only losses it cannot fix start another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecoveryMode {
+    /// Send an IDR frame.
+    Keyframe,
+    /// Spread intra coded blocks over the following frames, falling back to
+    /// `Keyframe` if the encoder does not support it.
+    IntraRefresh,
+}
+
+/// Options making forced intra frames IDR frames, so that nothing references lost frames.
+fn forced_idr_options(vendor: EncoderVendor) -> &'static [(&'static str, &'static str)] {
+    match vendor {
+        EncoderVendor::Nvidia | EncoderVendor::X264 => &[("forced-idr", "1")],
+        EncoderVendor::Intel => &[("forced_idr", "1")],
+        EncoderVendor::Amd | EncoderVendor::Other => &[],
+    }
+}
+
+/// Options enabling intra refresh, in which case forced intra frames start a refresh cycle.
+fn intra_refresh_options(vendor: EncoderVendor) -> Option<&'static [(&'static str, &'static str)]> {
+    match vendor {
+        EncoderVendor::Nvidia => Some(&[("intra-refresh", "1")]),
+        EncoderVendor::Intel => Some(&[("int_ref_type", "vertical")]),
+        EncoderVendor::X264 => Some(&[("intra-refresh", "1")]),
+        EncoderVendor::Amd | EncoderVendor::Other => None,
+    }
+}
+
+/// Options setting the length of a refresh cycle, or the IDR interval without intra refresh.
+fn intra_refresh_period_options(vendor: EncoderVendor, period: u32) -> Vec<(String, String)> {
+    match vendor {
+        // Both derive the refresh period from the GOP size
+        EncoderVendor::Nvidia | EncoderVendor::X264 => vec![("g".into(), period.to_string())],
+        EncoderVendor::Intel => vec![("int_ref_cycle_size".into(), period.to_string())],
+        EncoderVendor::Amd | EncoderVendor::Other => vec![("g".into(), period.to_string())],
+    }
+}
+
+/// Temporal scalability modes, named as in WebRTC: one spatial layer and one to three
+/// temporal layers in a dyadic hierarchical-P structure.
+///
+/// With three layers the frames after a keyframe are in layers 0, 2, 1, 2, 0, ...
+/// so every layer doubles the frame rate of the layers below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ScalabilityMode {
+    L1T1,
+    L1T2,
+    L1T3,
+}
+
+impl ScalabilityMode {
+    pub fn temporal_layers(&self) -> u8 {
+        match self {
+            ScalabilityMode::L1T1 => 1,
+            ScalabilityMode::L1T2 => 2,
+            ScalabilityMode::L1T3 => 3,
+        }
+    }
+
+    /// Temporal layer of the frame `position` frames after the last keyframe.
+    pub fn temporal_layer(&self, position: u64) -> u8 {
+        match self {
+            ScalabilityMode::L1T1 => 0,
+            ScalabilityMode::L1T2 => (position % 2) as u8,
+            ScalabilityMode::L1T3 => match position % 4 {
+                0 => 0,
+                2 => 1,
+                _ => 2,
+            },
+        }
+    }
+}
+
+/// Options making the encoder use the reference structure of `mode`.
+///
+/// The FFmpeg wrappers of NVENC, AMF and x264 do not expose temporal layers or a
+/// way to choose the references of a frame, only QSV can build a P-pyramid.
+fn scalability_options(
+    vendor: EncoderVendor,
+    mode: ScalabilityMode,
+) -> Option<&'static [(&'static str, &'static str)]> {
+    match (mode, vendor) {
+        (ScalabilityMode::L1T1, _) => Some(&[]),
+        (_, EncoderVendor::Intel) => Some(&[("bf", "0"), ("p_strategy", "2")]),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoCodec {
+    #[default]
+    H264,
+    /// Lossless BGRA tiles, only sent where the frame changed. Uses a lot of
+    /// bandwidth, and is only supported by the custom TCP protocol.
+    Raw,
+}
+
+/// Chroma subsampling of the encoded video.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaFormat {
+    Yuv420,
+    Yuv444,
+}
+
+impl ChromaFormat {
+    /// `chroma_format_idc` of the H.264 SPS.
+    pub fn idc(&self) -> u8 {
+        match self {
+            ChromaFormat::Yuv420 => 1,
+            ChromaFormat::Yuv444 => 3,
+        }
+    }
+}
+
+/// Options selecting a profile with 4:4:4 support, replacing the baseline profile.
+///
+/// QSV and AMF only support it with HEVC, which is not used.
+fn full_chroma_options(vendor: EncoderVendor) -> Option<&'static [(&'static str, &'static str)]> {
+    match vendor {
+        EncoderVendor::Nvidia => Some(&[("profile", "high444p")]),
+        EncoderVendor::X264 => Some(&[("profile", "high444")]),
+        EncoderVendor::Intel | EncoderVendor::Amd | EncoderVendor::Other => None,
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    pub source: AudioSourceConfig,
+    pub opus: OpusConfig,
+    /// Also encode AAC, for players without Opus support. Disabled if not set.
+    pub aac: Option<AacConfig>,
+    /// Where audio sent by clients, such as their microphone, is played.
+    /// It is not accepted if this is not set.
+    pub uplink: Option<AudioSinkConfig>,
+}
+
+/// Where the streamed audio comes from.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AudioSourceConfig {
+    /// Capture what is played on the default output device, following changes of it.
+    Loopback,
+    /// Capture an input device, the default one if `device` is not set.
+    Input {
+        #[serde(default)]
+        device: Option<String>,
+    },
+    /// A test tone.
+    Sine {
+        #[serde(default = "default_sine_frequency")]
+        frequency: f32,
+        #[serde(default = "default_sample_rate")]
+        sample_rate: u32,
+        #[serde(default = "default_channels")]
+        channels: u16,
+    },
+    /// Play a WAV file in real time.
+    Wav {
+        path: PathBuf,
+        #[serde(default)]
+        looping: bool,
+    },
+}
+
+impl Default for AudioSourceConfig {
+    fn default() -> Self {
+        if cfg!(windows) {
+            AudioSourceConfig::Loopback
+        } else {
+            // Loopback capture relies on WASAPI
+            AudioSourceConfig::Input { device: None }
+        }
+    }
+}
+
+impl AudioSourceConfig {
+    fn validate(&self) -> Result<()> {
+        match self {
+            AudioSourceConfig::Loopback if !cfg!(windows) => {
+                anyhow::bail!("Loopback capture is only supported on Windows")
+            }
+            AudioSourceConfig::Sine {
+                frequency,
+                sample_rate,
+                channels,
+            } => {
+                if *sample_rate == 0 || *channels == 0 {
+                    anyhow::bail!("Invalid test tone format");
+                }
+                if *frequency <= 0.0 || *frequency * 2.0 > *sample_rate as f32 {
+                    anyhow::bail!("Test tone frequency must be below half the sample rate");
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Where the audio sent by clients is played.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AudioSinkConfig {
+    /// Play on an output device, the default one if `device` is not set. Through a
+    /// virtual cable, applications can use it as a microphone.
+    Output {
+        #[serde(default)]
+        device: Option<String>,
+    },
+    /// Record to a WAV file.
+    Wav { path: PathBuf },
+}
+
+/// Settings of the Opus encoder. The defaults suit music and other system audio,
+/// voice over lossy networks benefits from `voice` signal, FEC and a lower bitrate.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpusConfig {
+    /// Target bits per second, chosen from the channel count if not set.
+    pub bitrate: Option<u32>,
+    /// Variable bitrate.
+    pub vbr: bool,
+    /// Keep the variable bitrate close to the target.
+    pub constrained_vbr: bool,
+    /// From 0 to 10, trading CPU time for quality.
+    pub complexity: u8,
+    pub signal: OpusSignal,
+    /// Highest encoded bandwidth, all of them if not set.
+    pub max_bandwidth: Option<OpusBandwidth>,
+    /// In-band forward error correction, recovering a lost packet from the next one.
+    /// Only used for speech, when `expected_packet_loss` is not zero.
+    pub fec: bool,
+    /// Lost packets the encoder prepares for, in percent.
+    pub expected_packet_loss: u8,
+    /// Discontinuous transmission, sending almost nothing during silence.
+    pub dtx: bool,
+    /// Bit depth of the captured audio, from 8 to 24, below which the encoder does not
+    /// spend bits. Float input is treated as 24 bits if not set.
+    pub lsb_depth: Option<u8>,
+    /// Duration of each packet, one of 2.5, 5, 10, 20, 40 or 60 milliseconds.
+    /// Shorter packets lower the latency, longer ones the packet rate and overhead.
+    pub frame_duration_ms: f32,
+}
+
+impl Default for OpusConfig {
+    fn default() -> Self {
+        Self {
+            bitrate: None,
+            vbr: true,
+            constrained_vbr: true,
+            complexity: 10,
+            signal: OpusSignal::Auto,
+            max_bandwidth: None,
+            fec: false,
+            expected_packet_loss: 0,
+            dtx: false,
+            lsb_depth: None,
+            frame_duration_ms: 10.0,
+        }
+    }
+}
+
+impl OpusConfig {
+    fn validate(&self) -> Result<()> {
+        if let Some(bitrate) = self.bitrate {
+            if !(500..=512000).contains(&bitrate) {
+                anyhow::bail!("Bitrate must be between 500 and 512000 bits per second");
+            }
+        }
+        if self.complexity > 10 {
+            anyhow::bail!("Complexity must be between 0 and 10");
+        }
+        if self.expected_packet_loss > 100 {
+            anyhow::bail!("Expected packet loss must be a percentage");
+        }
+        if let Some(depth) = self.lsb_depth {
+            if !(8..=24).contains(&depth) {
+                anyhow::bail!("LSB depth must be between 8 and 24 bits");
+            }
+        }
+        if ![2.5, 5.0, 10.0, 20.0, 40.0, 60.0].contains(&self.frame_duration_ms) {
+            anyhow::bail!("Frame duration must be 2.5, 5, 10, 20, 40 or 60 ms");
+        }
+        Ok(())
+    }
+}
+
+/// Settings of the AAC encoder, which only runs while someone receives AAC.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AacConfig {
+    /// FFmpeg encoder, `aac` or `libfdk_aac` if FFmpeg was built with it.
+    pub encoder: String,
+    /// Target bits per second, chosen from the channel count if not set.
+    pub bitrate: Option<u32>,
+}
+
+impl Default for AacConfig {
+    fn default() -> Self {
+        Self {
+            encoder: "aac".into(),
+            bitrate: None,
+        }
+    }
+}
+
+impl AacConfig {
+    fn validate(&self) -> Result<()> {
+        match Codec::find_by_name(&self.encoder) {
+            Some(codec) if codec.is_audio() => {}
+            Some(_) => anyhow::bail!("{} is not an audio encoder", self.encoder),
+            None => anyhow::bail!("Unknown encoder {}", self.encoder),
+        }
+        if let Some(bitrate) = self.bitrate {
+            if bitrate < 8000 {
+                anyhow::bail!("Bitrate must be at least 8000 bits per second");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpusSignal {
+    /// Detected by the encoder.
+    #[default]
+    Auto,
+    Voice,
+    Music,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpusBandwidth {
+    /// 4 kHz
+    Narrowband,
+    /// 6 kHz
+    Mediumband,
+    /// 8 kHz
+    Wideband,
+    /// 12 kHz
+    Superwideband,
+    /// 20 kHz
+    Fullband,
+}
+
+fn default_sine_frequency() -> f32 {
+    440.0
+}
+
+fn default_sample_rate() -> u32 {
+    48000
+}
+
+fn default_channels() -> u16 {
+    2
+}
+
+fn config_path() -> Result<PathBuf> {
+    if let Some(path) = std::env::var_os(CONFIG_PATH_ENV) {
+        return Ok(path.into());
+    }
+
+    // Running as a service, the working directory is not meaningful.
+    let exe = std::env::current_exe().context("Get executable path")?;
+    Ok(exe.with_file_name(CONFIG_FILE_NAME))
+}
+
+/// Load and validate the configuration, falling back to defaults if there is no file.
+pub fn load() -> Result<Config> {
+    let path = config_path()?;
+
+    let config: Config = match std::fs::read(&path) {
+        Ok(buf) => serde_json::from_slice(&buf)
+            .with_context(|| format!("Parse configuration {}", path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            tracing::info!(path = %path.display(), "No configuration file, using defaults");
+            Config::default()
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("Read configuration {}", path.display()));
+        }
+    };
+
+    config
+        .video
+        .encoder
+        .validate()
+        .context("Invalid encoder policy")?;
+
+    config
+        .audio
+        .source
+        .validate()
+        .context("Invalid audio source")?;
+
+    config
+        .audio
+        .opus
+        .validate()
+        .context("Invalid Opus settings")?;
+
+    if let Some(aac) = &config.audio.aac {
+        aac.validate().context("Invalid AAC settings")?;
+    }
+
+    for (index, monitor) in &config.monitors {
+        monitor
+            .validate()
+            .with_context(|| format!("Invalid configuration of monitor {}", index))?;
+    }
+
+    Ok(config)
+}
+
+pub fn init() -> Result<()> {
+    let config = load()?;
+    tracing::info!(?config, "Loaded configuration");
+    CONFIG.set(config).unwrap();
+    Ok(())
+}
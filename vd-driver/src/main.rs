@@ -2,11 +2,14 @@
 
 use anyhow::Result;
 
+#[cfg(windows)]
 use monitor::Monitor;
+#[cfg(windows)]
 use windows::Win32::Media::MediaFoundation::{MFStartup, MFSTARTUP_FULL};
 
+use std::io::Read;
+#[cfg(windows)]
 use std::{
-    io::Read,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -16,14 +19,20 @@ use once_cell::sync::OnceCell;
 mod adb;
 mod app;
 mod audio;
+mod clock;
+mod config;
 mod metrics;
 mod monitor;
+mod pool;
 mod server;
+mod tile;
 mod utils;
+#[cfg(windows)]
 mod win32;
 
 use app::ApplicationHandle;
 
+#[cfg(windows)]
 use crate::win32::Waitable;
 
 pub static APPLICATION: OnceCell<ApplicationHandle> = OnceCell::new();
@@ -56,12 +65,18 @@ fn read_configuration(buf: &[u8]) -> Option<(u32, u32, u32)> {
 
 pub async fn entry() -> Result<()> {
     let (audio_codec_data_tx, audio_codec_data_rx) = tokio::sync::watch::channel(None);
+    let (aac_codec_data_tx, aac_codec_data_rx) = tokio::sync::watch::channel(None);
+    let uplink_tx = audio::setup_uplink();
 
     APPLICATION
-        .set(ApplicationHandle::new(audio_codec_data_rx))
+        .set(ApplicationHandle::new(
+            audio_codec_data_rx,
+            aac_codec_data_rx,
+            uplink_tx,
+        ))
         .unwrap();
 
-    if let Err(e) = audio::setup_audio(audio_codec_data_tx) {
+    if let Err(e) = audio::setup_audio(audio_codec_data_tx, aac_codec_data_tx) {
         tracing::error!(?e, "Failed to setup audio");
     };
 
@@ -70,6 +85,16 @@ pub async fn entry() -> Result<()> {
 
     tracing::info!("Initialized");
 
+    connect_driver()?;
+
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
+}
+
+/// Stream the monitor of the display driver, shared through named kernel objects.
+#[cfg(windows)]
+fn connect_driver() -> Result<()> {
     let descriptor: win32::SecurityDescriptor = "D:(A;;0xc01f0003;;;AU)".parse()?;
 
     let frame_buffer_mutex = Arc::new(win32::Mutex::new(
@@ -238,8 +263,13 @@ pub async fn entry() -> Result<()> {
         }
     });
 
-    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
 
+/// The display driver only exists on Windows, the audio is still streamed.
+#[cfg(not(windows))]
+fn connect_driver() -> Result<()> {
+    tracing::warn!("No display driver on this platform, only streaming audio");
     Ok(())
 }
 
@@ -253,13 +283,15 @@ pub fn main() -> Result<()> {
         })
         .build()?;
 
-    dcv_color_primitives::initialize();
     tracing_subscriber::fmt()
         .with_env_filter("debug,webrtc_sctp=info,hyper=info,webrtc_mdns::conn=off")
         .init();
     metrics::init();
+    clock::init();
     ffmpeg_simple::init_logging();
+    config::init()?;
 
+    #[cfg(windows)]
     unsafe {
         if let Err(e) = MFStartup(
             windows::Win32::Media::MediaFoundation::MF_SDK_VERSION << 16
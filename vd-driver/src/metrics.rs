@@ -1,5 +1,5 @@
 use once_cell::sync::OnceCell;
-use prometheus::{Histogram, IntCounter};
+use prometheus::{Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts};
 
 pub static METRICS: OnceCell<Metrics> = OnceCell::new();
 pub fn get_metrics() -> &'static Metrics {
@@ -11,6 +11,14 @@ pub struct Metrics {
     pub encoded_frames: IntCounter,
     pub end_to_end_latency_ms: Histogram,
     pub encoding_latency_ms: Histogram,
+    pub encoder_errors: IntCounter,
+    pub encoder_recoveries: IntCounter,
+    /// Labelled with the failed encoder (`from`) and its replacement (`to`).
+    pub encoder_fallbacks: IntCounterVec,
+    /// Processing time of each stage of the video pipeline, labelled with the `stage`.
+    pub stage_latency_ms: HistogramVec,
+    /// Frames dropped by each stage of the video pipeline, labelled with the `stage`.
+    pub dropped_frames: IntCounterVec,
 }
 
 pub fn init() {
@@ -26,15 +34,65 @@ pub fn init() {
     )
     .unwrap();
 
+    let encoder_errors = IntCounter::new("encoder_errors", "Number of encoder failures").unwrap();
+    let encoder_recoveries = IntCounter::new(
+        "encoder_recoveries",
+        "Number of recoveries from frame loss reported by clients",
+    )
+    .unwrap();
+    let encoder_fallbacks = IntCounterVec::new(
+        Opts::new(
+            "encoder_fallbacks",
+            "Number of times the encoder fell back to another one",
+        ),
+        &["from", "to"],
+    )
+    .unwrap();
+
+    let stage_latency_ms = HistogramVec::new(
+        HistogramOpts::new(
+            "stage_latency_ms",
+            "Processing time of frames in each pipeline stage",
+        )
+        .buckets(vec![0.5, 1.0, 2.0, 3.0, 4.0, 6.0, 8.0, 10.0, 20.0, 50.0]),
+        &["stage"],
+    )
+    .unwrap();
+    let dropped_frames = IntCounterVec::new(
+        Opts::new(
+            "dropped_frames",
+            "Number of frames dropped in each pipeline stage",
+        ),
+        &["stage"],
+    )
+    .unwrap();
+
     prometheus::register(Box::new(encoded_frames.clone())).unwrap();
     prometheus::register(Box::new(end_to_end_latency_ms.clone())).unwrap();
     prometheus::register(Box::new(encoding_latency_ms.clone())).unwrap();
+    prometheus::register(Box::new(encoder_errors.clone())).unwrap();
+    prometheus::register(Box::new(encoder_recoveries.clone())).unwrap();
+    prometheus::register(Box::new(encoder_fallbacks.clone())).unwrap();
+    prometheus::register(Box::new(stage_latency_ms.clone())).unwrap();
+    prometheus::register(Box::new(dropped_frames.clone())).unwrap();
 
     METRICS
         .set(Metrics {
             encoded_frames,
             end_to_end_latency_ms,
             encoding_latency_ms,
+            encoder_errors,
+            encoder_recoveries,
+            encoder_fallbacks,
+            stage_latency_ms,
+            dropped_frames,
         })
         .unwrap();
 }
+
+/// Initialize the metrics for tests of code updating them, once for all tests.
+#[cfg(test)]
+pub fn init_for_tests() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(init);
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::BTreeMap,
     num::NonZeroUsize,
     sync::{atomic::AtomicU32, Arc, Mutex},
     time::{Duration, Instant},
@@ -7,36 +8,334 @@ use std::{
 use anyhow::Result;
 use bytes::Bytes;
 use crossbeam::channel;
-use dcv_color_primitives as dcp;
 use ffmpeg_simple::{
-    codec::HwCodecSetupMethod, Codec, CodecContext, HwDeviceContext, OpenedCodecContext,
+    codec::HwCodecSetupMethod,
+    ffi,
+    scale::{ColorMatrix, ColorRange, ImageSpec, ScaleAlgorithm, ScaleFlags},
+    Codec, CodecContext, Dictionary, Frame, HwDeviceContext, HwFramesContext, OpenedCodecContext,
+    Scaler,
 };
 use image::{ImageBuffer, ImageOutputFormat, Rgba};
 use lru::LruCache;
 use tokio::sync::{broadcast, watch};
-use webrtc_media::io::h264_reader::{H264Reader, NalUnitType};
 
-use crate::{get_app, utils::Sample};
+use crate::{
+    config::{
+        ChromaFormat, EncoderPolicy, MonitorConfig, RecoveryMode, ScalabilityMode, VideoCodec,
+    },
+    get_app,
+    pool::{Pool, Pooled},
+    tile::{TileEncoder, TILE_SIZE},
+    utils::{
+        is_recovery_point, is_reference, split_nal_units, svc_temporal_id, Sample,
+        NAL_UNIT_TYPE_MASK, NAL_UNIT_TYPE_PPS, NAL_UNIT_TYPE_SPS,
+    },
+};
 
-#[derive(Debug)]
 enum EncodingCommand {
-    NewFrame(Instant),
+    NewFrame(ConvertedFrame),
     Configure {
         width: u32,
         height: u32,
         framerate: u32,
     },
+    /// Try to open the encoder again after a failure.
+    Reopen,
+    /// Clients lost frames, the requests are waiting in the `RecoveryQueue`.
+    Recover,
+}
+
+/// Clients lost frames or started watching.
+#[derive(Debug, Clone, Copy, Default)]
+struct RecoveryRequest {
+    /// A client lost frames.
+    lost: bool,
+    /// Capture time of the last frame the client decoded correctly, if it reported one.
+    last_decoded: Option<Instant>,
+    /// A client started watching and waits for a recovery point.
+    joined: bool,
+}
+
+impl RecoveryRequest {
+    /// A request covering both `self` and `other`.
+    fn merge(self, other: Self) -> Self {
+        let last_decoded = match (self.lost, other.lost) {
+            (true, true) => match (self.last_decoded, other.last_decoded) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                _ => None,
+            },
+            (true, false) => self.last_decoded,
+            (false, _) => other.last_decoded,
+        };
+        Self {
+            lost: self.lost || other.lost,
+            last_decoded,
+            joined: self.joined || other.joined,
+        }
+    }
+}
+
+/// Recovery requests merged until the encoder handles them, so that none is lost
+/// to a full command channel.
+#[derive(Debug, Clone)]
+struct RecoveryQueue {
+    pending: Arc<Mutex<Option<RecoveryRequest>>>,
+    /// Wakes the encoding thread, a full channel means it has not woken up yet.
+    notify_tx: channel::Sender<()>,
+}
+
+impl RecoveryQueue {
+    fn new() -> (Self, channel::Receiver<()>) {
+        let (notify_tx, notify_rx) = channel::bounded(1);
+        let queue = Self {
+            pending: Default::default(),
+            notify_tx,
+        };
+        (queue, notify_rx)
+    }
+
+    /// Another queue waking the same encoding thread.
+    fn sibling(&self) -> Self {
+        Self {
+            pending: Default::default(),
+            notify_tx: self.notify_tx.clone(),
+        }
+    }
+
+    fn push(&self, request: RecoveryRequest) {
+        let mut pending = self.pending.lock().unwrap();
+        *pending = Some(match pending.take() {
+            Some(previous) => previous.merge(request),
+            None => request,
+        });
+        self.notify_tx.try_send(()).ok();
+    }
+
+    fn take(&self) -> Option<RecoveryRequest> {
+        self.pending.lock().unwrap().take()
+    }
+}
+
+/// Last recovery of an encoder, to tell whether a loss report is already covered by it.
+#[derive(Debug, Default)]
+struct RecoveryState {
+    /// When the encoder was asked to recover.
+    requested_at: Option<Instant>,
+    /// Capture time of the first frame encoded since, which starts the recovery.
+    first_frame: Option<Instant>,
+    /// When to recover for clients that joined too soon after the last recovery.
+    join_due: Option<Instant>,
+}
+
+impl RecoveryState {
+    /// Whether `request` needs a recovery now.
+    ///
+    /// Joining clients wait for the next refresh if the encoder refreshes periodically.
+    /// Otherwise they get at most one recovery per `MIN_JOIN_INTERVAL`, as every client
+    /// receives it, and later ones are postponed until `join_due`.
+    fn needs_recovery(
+        &mut self,
+        request: &RecoveryRequest,
+        periodic_refresh: bool,
+        now: Instant,
+    ) -> bool {
+        if request.lost && self.loss_needs_recovery(request, now) {
+            return true;
+        }
+        if !request.joined || periodic_refresh {
+            return false;
+        }
+
+        match self.requested_at {
+            Some(at) if now.saturating_duration_since(at) < MIN_JOIN_INTERVAL => {
+                self.join_due.get_or_insert(at + MIN_JOIN_INTERVAL);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Whether a recovery postponed for joining clients is due.
+    fn join_due(&self, now: Instant) -> bool {
+        self.join_due.is_some_and(|at| at <= now)
+    }
+
+    /// Whether a loss needs another recovery, as the last one cannot fix it.
+    fn loss_needs_recovery(&self, request: &RecoveryRequest, now: Instant) -> bool {
+        let requested_at = match self.requested_at {
+            Some(at) => at,
+            None => return true,
+        };
+        if now.saturating_duration_since(requested_at) >= MIN_RECOVERY_INTERVAL {
+            // It should have fixed the picture by now, so it was lost as well
+            return true;
+        }
+
+        match (request.last_decoded, self.first_frame) {
+            // The client decoded the recovery, so it lost a later frame
+            (Some(last_decoded), Some(first_frame)) => last_decoded >= first_frame,
+            // Reported before the recovery reached the client
+            _ => false,
+        }
+    }
+
+    fn start(&mut self, now: Instant) {
+        self.requested_at = Some(now);
+        self.first_frame = None;
+        self.join_due = None;
+    }
+
+    fn on_frame(&mut self, timestamp: Instant) {
+        if self.requested_at.is_some() && self.first_frame.is_none() {
+            self.first_frame = Some(timestamp);
+        }
+    }
+}
+
+/// Lower frame rate renditions of a monitor by frame rate, created when a client
+/// asks for one.
+type Renditions = Arc<Mutex<BTreeMap<u32, RenditionOutput>>>;
+
+/// Where a rendition is published, shared by its clients and the encoding thread.
+#[derive(Debug, Clone)]
+struct RenditionOutput {
+    encoded_tx: broadcast::Sender<Sample>,
+    codec_data_tx: Arc<watch::Sender<Option<VideoCodecData>>>,
+    recovery: RecoveryQueue,
+}
+
+impl RenditionOutput {
+    /// Whether no client holds the rendition anymore, handles keeping a codec data
+    /// receiver until they are dropped.
+    fn is_unused(&self) -> bool {
+        self.encoded_tx.receiver_count() == 0 && self.codec_data_tx.receiver_count() == 0
+    }
+}
+
+/// Outputs of the renditions which have clients, forgetting the unused ones.
+fn rendition_outputs(renditions: &Renditions) -> Vec<(u32, RenditionOutput)> {
+    let mut renditions = renditions.lock().unwrap();
+    renditions.retain(|_, output| !output.is_unused());
+    renditions
+        .iter()
+        .filter(|(_, output)| output.encoded_tx.receiver_count() > 0)
+        .map(|(max_framerate, output)| (*max_framerate, output.clone()))
+        .collect()
+}
+
+/// Frame rate of the rendition for clients asking for at most `max_framerate` on a
+/// monitor running at `framerate`, the largest `framerate / n` not above it.
+fn rendition_framerate(framerate: u32, max_framerate: Option<u32>) -> Option<u32> {
+    match max_framerate {
+        Some(max_framerate) if max_framerate > 0 && max_framerate < framerate => {
+            Some(framerate / framerate.div_ceil(max_framerate))
+        }
+        _ => None,
+    }
+}
+
+/// Picks the frames of a rendition, at most one per interval.
+#[derive(Debug)]
+struct FrameDecimator {
+    interval: Duration,
+    next_frame: Option<Instant>,
+}
+
+impl FrameDecimator {
+    fn new(max_framerate: u32) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / max_framerate as f64),
+            next_frame: None,
+        }
+    }
+
+    /// Whether the frame captured at `timestamp` is encoded, for frames of the
+    /// monitor lasting `frame_duration`.
+    fn pass(&mut self, timestamp: Instant, frame_duration: Duration) -> bool {
+        match self.next_frame {
+            // Frames close to the due time are taken, so that the rate is not rounded down
+            Some(next) if timestamp + frame_duration / 2 < next => false,
+            Some(next) if timestamp < next + self.interval => {
+                self.next_frame = Some(next + self.interval);
+                true
+            }
+            // First frame, or no frame for a while
+            _ => {
+                self.next_frame = Some(timestamp + self.interval);
+                true
+            }
+        }
+    }
+}
+
+/// Number of buffers per pipeline stage: one being processed, one queued and one spare.
+const PIPELINE_DEPTH: usize = 3;
+
+/// A BGRA frame copied out of the frame buffer.
+struct CapturedFrame {
+    buffer: Pooled<Vec<u8>>,
+    width: u32,
+    height: u32,
+    timestamp: Instant,
+}
+
+/// A frame converted to the input format of the encoder.
+struct ConvertedFrame {
+    frame: Pooled<Frame>,
+    timestamp: Instant,
+}
+
+/// Queue `item` for the next pipeline stage, dropping the oldest queued item if
+/// that stage is busy so that the latest frame is always encoded.
+fn send_latest<T>(tx: &channel::Sender<T>, rx: &channel::Receiver<T>, mut item: T, stage: &str) {
+    loop {
+        match tx.try_send(item) {
+            Ok(()) => return,
+            Err(channel::TrySendError::Full(rejected)) => {
+                if rx.try_recv().is_ok() {
+                    crate::metrics::get_metrics()
+                        .dropped_frames
+                        .with_label_values(&[stage])
+                        .inc();
+                }
+                item = rejected;
+            }
+            Err(channel::TrySendError::Disconnected(_)) => return,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum VideoCodecData {
-    H264 { sps: Bytes, pps: Bytes },
+    H264 {
+        sps: Bytes,
+        pps: Bytes,
+        chroma_format: ChromaFormat,
+    },
+    /// Lossless tiles, see `crate::tile`.
+    Raw { tile_size: u32 },
 }
 
+/// `profile-level-id` of High 4:4:4 Predictive at level 3.1, as offered by browsers able
+/// to decode it. Such decoders also decode the 4:2:0 profiles.
+pub const FULL_CHROMA_PROFILE_LEVEL_ID: &str = "f4001f";
+
 impl VideoCodecData {
     pub fn mime(&self) -> &'static str {
         match self {
             VideoCodecData::H264 { .. } => "video/avc",
+            VideoCodecData::Raw { .. } => "video/x-vd-tiles",
+        }
+    }
+
+    /// `profile-level-id` of the SDP format parameters (RFC 6184), from the SPS.
+    pub fn profile_level_id(&self) -> Option<String> {
+        match self {
+            // Start code and NAL unit header, then profile_idc, constraint flags and level_idc
+            VideoCodecData::H264 { sps, .. } => sps
+                .get(5..8)
+                .map(|id| id.iter().map(|b| format!("{:02x}", b)).collect()),
+            VideoCodecData::Raw { .. } => None,
         }
     }
 }
@@ -45,6 +344,8 @@ impl VideoCodecData {
 pub struct MonitorHandle {
     pub encoded_tx: broadcast::Sender<Sample>,
     codec_data_rx: watch::Receiver<Option<VideoCodecData>>,
+    recovery: RecoveryQueue,
+    renditions: Renditions,
 
     width: Arc<AtomicU32>,
     height: Arc<AtomicU32>,
@@ -75,6 +376,79 @@ impl MonitorHandle {
         self.cursor_position_rx.clone()
     }
 
+    /// The video at `max_framerate` or below, `None` or 0 for no limit.
+    ///
+    /// Below the frame rate of the monitor, the frames are encoded again at the lower
+    /// rate, as dropping frames of this stream would break the decoding of later ones.
+    /// The rate is rounded down to a fraction of the monitor frame rate so that clients
+    /// share renditions, and the full frame rate is used beyond `MAX_RENDITIONS`.
+    pub fn rendition(&self, max_framerate: Option<u32>) -> MonitorHandle {
+        let max_framerate = match rendition_framerate(self.framerate(), max_framerate) {
+            Some(max_framerate) => max_framerate,
+            None => return self.clone(),
+        };
+
+        let output = {
+            let mut renditions = self.renditions.lock().unwrap();
+            renditions.retain(|_, output| !output.is_unused());
+            if !renditions.contains_key(&max_framerate) && renditions.len() >= MAX_RENDITIONS {
+                tracing::warn!(
+                    max_framerate,
+                    "Too many frame rate renditions, using the full frame rate"
+                );
+                return self.clone();
+            }
+            renditions
+                .entry(max_framerate)
+                .or_insert_with(|| RenditionOutput {
+                    encoded_tx: broadcast::channel(8).0,
+                    codec_data_tx: Arc::new(watch::channel(None).0),
+                    recovery: self.recovery.sibling(),
+                })
+                .clone()
+        };
+        tracing::info!(max_framerate, "Using a lower frame rate rendition");
+
+        MonitorHandle {
+            encoded_tx: output.encoded_tx,
+            codec_data_rx: output.codec_data_tx.subscribe(),
+            recovery: output.recovery,
+            ..self.clone()
+        }
+    }
+
+    /// Ask the encoder to recover from lost frames, e.g. on a picture loss indication.
+    ///
+    /// Requests are coalesced by the encoder, so this can be called for every report.
+    pub fn request_recovery(&self) {
+        self.recovery.push(RecoveryRequest {
+            lost: true,
+            ..Default::default()
+        });
+    }
+
+    /// Ask the encoder to recover from frames lost after the one captured at
+    /// `last_decoded`, unless a recovery already on its way to the client covers them.
+    pub fn report_loss(&self, last_decoded: Instant) {
+        self.recovery.push(RecoveryRequest {
+            lost: true,
+            last_decoded: Some(last_decoded),
+            ..Default::default()
+        });
+    }
+
+    /// Ask for a recovery point for a client starting to watch.
+    ///
+    /// Unlike losses, joins wait for the periodic intra refresh of the monitor if it has
+    /// one, and are rate limited otherwise, so that clients joining do not keep sending
+    /// large frames to the others.
+    pub fn request_join(&self) {
+        self.recovery.push(RecoveryRequest {
+            joined: true,
+            ..Default::default()
+        });
+    }
+
     pub fn cursor_image(&self) -> watch::Receiver<Option<CursorImage>> {
         self.cursor_image_rx.clone()
     }
@@ -106,7 +480,10 @@ impl CursorImage {
 
 pub struct Monitor {
     cmd_tx: channel::Sender<EncodingCommand>,
-    bgra_buffer: Arc<Mutex<Vec<u8>>>,
+    captured_tx: channel::Sender<CapturedFrame>,
+    /// Used to drop the oldest captured frame if the conversion stage is busy.
+    captured_rx: channel::Receiver<CapturedFrame>,
+    capture_pool: Pool<Vec<u8>>,
 
     cursor_cache: Mutex<LruCache<u32, CursorImage>>,
 
@@ -124,16 +501,59 @@ pub struct Monitor {
 impl Monitor {
     pub fn new(index: u32) -> Self {
         let (cmd_tx, cmd_rx) = channel::bounded(1);
+        let (recovery, recovery_rx) = RecoveryQueue::new();
+        let renditions = Renditions::default();
         let (data_tx, _) = broadcast::channel(8);
         let (codec_data_tx, encoder_data_rx) = watch::channel(None);
         let (cursor_position_tx, cursor_position_rx) = watch::channel(None);
         let (cursor_image_tx, cursor_image_rx) = watch::channel(None);
-        let bgra_buffer = Arc::new(Mutex::new(Vec::new()));
 
-        let b = bgra_buffer.clone();
+        // Capture -> conversion -> encode, each stage running concurrently.
+        let (captured_tx, captured_rx) = channel::bounded(1);
+        let (converted_tx, converted_rx) = channel::bounded(1);
+        let (target_tx, target_rx) = watch::channel(None);
+
+        let c = captured_rx.clone();
+        let f = converted_rx.clone();
+        let t = data_tx.clone();
+        let r = renditions.clone();
+        std::thread::spawn(move || {
+            conversion_thread(c, converted_tx, f, target_rx, t, r);
+        });
+
         let t = data_tx.clone();
+        let q = recovery.clone();
+        let r = renditions.clone();
+        let config = crate::config::get_config();
+        let policy = config.video.encoder.clone();
+        let monitor_config = config.monitor(index);
         std::thread::spawn(move || {
-            if let Err(e) = encoding_thread(cmd_rx, t, codec_data_tx, b) {
+            let res = match monitor_config.codec {
+                VideoCodec::H264 => encoding_thread(
+                    cmd_rx,
+                    q,
+                    recovery_rx,
+                    converted_rx,
+                    t,
+                    r,
+                    codec_data_tx,
+                    target_tx,
+                    policy,
+                    monitor_config,
+                ),
+                VideoCodec::Raw => raw_encoding_thread(
+                    cmd_rx,
+                    q,
+                    recovery_rx,
+                    converted_rx,
+                    t,
+                    r,
+                    codec_data_tx,
+                    target_tx,
+                    monitor_config.slices.unwrap_or(1),
+                ),
+            };
+            if let Err(e) = res {
                 tracing::error!(?e, "Encoding thread failed");
             }
         });
@@ -147,6 +567,8 @@ impl Monitor {
             MonitorHandle {
                 encoded_tx: data_tx,
                 codec_data_rx: encoder_data_rx,
+                recovery,
+                renditions,
                 width: width.clone(),
                 height: height.clone(),
                 framerate: framerate.clone(),
@@ -158,7 +580,9 @@ impl Monitor {
 
         Self {
             cmd_tx,
-            bgra_buffer,
+            captured_tx,
+            captured_rx,
+            capture_pool: Pool::new(PIPELINE_DEPTH),
 
             cursor_cache: Mutex::new(LruCache::new(NonZeroUsize::new(60).unwrap())),
 
@@ -202,15 +626,33 @@ impl Monitor {
     /// Notify the monitor that a new frame is available.
     ///
     /// This function is non-blocking, and will return immediately after the data has been copied.
-    /// The event is lost if the encoding task is busy.
+    /// If the conversion stage is busy, the oldest frame waiting for it is dropped.
     pub fn send_frame(&self, bgra_buffer: &[u8], timestamp: Instant) {
-        let mut monitor_buffer = self.bgra_buffer.lock().unwrap();
-        monitor_buffer.clear();
-        monitor_buffer.extend_from_slice(bgra_buffer);
-
-        self.cmd_tx
-            .try_send(EncodingCommand::NewFrame(timestamp))
-            .ok();
+        let metrics = crate::metrics::get_metrics();
+        let capture_start = Instant::now();
+
+        let mut buffer = match self.capture_pool.get(Vec::new) {
+            Some(buffer) => buffer,
+            None => {
+                metrics.dropped_frames.with_label_values(&["capture"]).inc();
+                return;
+            }
+        };
+        buffer.clear();
+        buffer.extend_from_slice(bgra_buffer);
+
+        metrics
+            .stage_latency_ms
+            .with_label_values(&["capture"])
+            .observe(capture_start.elapsed().as_secs_f64() * 1000.0);
+
+        let frame = CapturedFrame {
+            buffer,
+            width: self.width(),
+            height: self.height(),
+            timestamp,
+        };
+        send_latest(&self.captured_tx, &self.captured_rx, frame, "conversion");
     }
 
     pub fn set_cursor_position(&self, x: i32, y: i32, visible: bool) {
@@ -268,144 +710,756 @@ impl Drop for Monitor {
     }
 }
 
+/// Number of consecutive failures after which the next candidate encoder is used.
+const MAX_ENCODER_FAILURES: u32 = 3;
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Time for a recovery to reach the clients, during which reports of losses it
+/// covers are ignored. Clients usually report a loss several times.
+const MIN_RECOVERY_INTERVAL: Duration = Duration::from_millis(500);
+/// Shortest time between two recoveries for clients starting to watch.
+const MIN_JOIN_INTERVAL: Duration = Duration::from_secs(1);
+/// Maximum number of lower frame rate renditions of a monitor, each having its own encoder.
+const MAX_RENDITIONS: usize = 3;
+
+/// Number of device frames allocated up front when uploading frames directly.
+const HW_FRAMES_POOL_SIZE: u32 = 8;
+
+struct ActiveEncoder {
+    /// Index into the candidate encoders.
+    index: usize,
+    name: String,
+    inner: OpenedCodecContext,
+    pts: i64,
+    recovery: RecoveryMode,
+    scalability: ScalabilityMode,
+    /// Whether the policy asks for temporal layers, non-reference frames being the only
+    /// droppable layer when `scalability` cannot provide them.
+    layered: bool,
+    /// Position in the temporal layer structure.
+    frames_since_keyframe: u64,
+    /// Whether frames of intermediate layers were found to be kept by the clients.
+    intermediate_layers_kept: bool,
+    chroma: ChromaFormat,
+
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    sps_pps_sent: bool,
+}
+
+impl ActiveEncoder {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Size and format of the frames expected by `encode`.
+    fn input_spec(&self, width: u32, height: u32) -> ImageSpec {
+        ImageSpec::new(width, height, self.inner.input_format())
+    }
+
+    /// Temporal layer of the next frame, so that dropping upper layers never drops a
+    /// frame that a kept one references.
+    ///
+    /// Layers are taken from the bitstream: the `temporal_id` if the encoder signals
+    /// it, otherwise frames that are not references form the only droppable layer, as
+    /// the references of a frame are not known. The same goes for encoders without
+    /// temporal layers, whose non-reference frames can be dropped as well.
+    fn temporal_layer(&mut self, keyframe: bool, nal_units: &[Bytes]) -> u8 {
+        if keyframe {
+            self.frames_since_keyframe = 0;
+        }
+        let layer = self.scalability.temporal_layer(self.frames_since_keyframe);
+        self.frames_since_keyframe += 1;
+
+        if !self.layered {
+            return 0;
+        }
+        if let Some(temporal_id) = svc_temporal_id(nal_units) {
+            return temporal_id;
+        }
+
+        let reference = is_reference(nal_units);
+        if self.scalability == ScalabilityMode::L1T1 {
+            return !reference as u8;
+        }
+
+        let top_layer = self.scalability.temporal_layers() - 1;
+        if layer == top_layer && reference {
+            tracing::warn!(
+                codec = %self.name,
+                scalability = ?self.scalability,
+                "Encoder does not follow the temporal layer structure, only dropping \
+                 non-reference frames"
+            );
+            self.scalability = ScalabilityMode::L1T1;
+            return 0;
+        }
+
+        if layer > 0 && reference && !self.intermediate_layers_kept {
+            tracing::info!(
+                codec = %self.name,
+                scalability = ?self.scalability,
+                "Encoder does not signal temporal ids, only the top layer can be dropped"
+            );
+            self.intermediate_layers_kept = true;
+        }
+
+        // Every other frame, the top layer of the structure
+        (layer > 0 && !reference) as u8
+    }
+
+    fn encode(
+        &mut self,
+        frame: &mut Frame,
+        timestamp: Instant,
+        duration: Duration,
+        data_tx: &broadcast::Sender<Sample>,
+        mirrors: &[broadcast::Sender<Sample>],
+        codec_data_tx: &watch::Sender<Option<VideoCodecData>>,
+    ) -> Result<()> {
+        self.inner.send_frame_from(frame, self.pts)?;
+        self.pts += 1;
+
+        while let Some(packet) = self.inner.receive_packet()? {
+            let data = if let Some(data) = packet.data() {
+                data
+            } else {
+                continue;
+            };
+
+            tracing::trace!("Sending frame");
+
+            // The only copy of the packet, shared by all transports from here on.
+            let data = Bytes::copy_from_slice(data);
+            let nal_units = split_nal_units(&data);
+            let recovery_point = packet.is_keyframe() || is_recovery_point(&nal_units);
+            let temporal_layer = self.temporal_layer(packet.is_keyframe(), &nal_units);
+
+            if !self.sps_pps_sent {
+                for nal in &nal_units {
+                    match nal[0] & NAL_UNIT_TYPE_MASK {
+                        NAL_UNIT_TYPE_SPS => {
+                            let mut cur_sps = vec![0, 0, 0, 1];
+                            cur_sps.extend_from_slice(nal);
+                            self.sps = Some(cur_sps);
+                        }
+                        NAL_UNIT_TYPE_PPS => {
+                            let mut cur_pps = vec![0, 0, 0, 1];
+                            cur_pps.extend_from_slice(nal);
+                            self.pps = Some(cur_pps);
+                        }
+                        _ => {}
+                    }
+                }
+
+                if let (Some(sps), Some(pps)) = (&self.sps, &self.pps) {
+                    codec_data_tx
+                        .send(Some(VideoCodecData::H264 {
+                            sps: sps.clone().into(),
+                            pps: pps.clone().into(),
+                            chroma_format: self.chroma,
+                        }))
+                        .ok();
+
+                    self.sps_pps_sent = true;
+
+                    tracing::info!("SPS/PPS sent");
+                }
+            }
+
+            let sample = Sample::with_nal_units(data, nal_units, timestamp, duration)
+                .with_recovery_point(recovery_point)
+                .with_temporal_layer(temporal_layer);
+            for tx in mirrors {
+                tx.send(sample.clone()).ok();
+            }
+            data_tx.send(sample).ok();
+        }
+
+        Ok(())
+    }
+}
+
+/// Encoder of a rendition, owned by the encoding thread.
+struct RenditionEncoder {
+    max_framerate: u32,
+    decimator: FrameDecimator,
+    state: RenditionState,
+    recovery_state: RecoveryState,
+}
+
+enum RenditionState {
+    /// Opened for the first frame it encodes.
+    Closed,
+    Open(ActiveEncoder),
+    /// No encoder could be opened next to the main one, the full frame rate is forwarded.
+    Mirrored,
+}
+
+impl RenditionEncoder {
+    fn new(max_framerate: u32) -> Self {
+        Self {
+            max_framerate,
+            decimator: FrameDecimator::new(max_framerate),
+            state: RenditionState::Closed,
+            recovery_state: RecoveryState::default(),
+        }
+    }
+
+    fn is_mirrored(&self) -> bool {
+        matches!(self.state, RenditionState::Mirrored)
+    }
+
+    /// Encode `frame` if it is due, opening the encoder with `open` first if needed.
+    #[allow(clippy::too_many_arguments)]
+    fn encode(
+        &mut self,
+        frame: &mut Frame,
+        timestamp: Instant,
+        frame_duration: Duration,
+        output: &RenditionOutput,
+        main_codec_data: Option<VideoCodecData>,
+        open: impl FnOnce() -> Result<ActiveEncoder>,
+    ) {
+        if self.is_mirrored() || !self.decimator.pass(timestamp, frame_duration) {
+            return;
+        }
+
+        if let RenditionState::Closed = self.state {
+            match open() {
+                Ok(opened) => {
+                    tracing::info!(
+                        codec = opened.name(),
+                        max_framerate = self.max_framerate,
+                        "Opened rendition encoder"
+                    );
+                    self.state = RenditionState::Open(opened);
+                }
+                Err(e) => {
+                    self.mirror(e, output, main_codec_data);
+                    return;
+                }
+            }
+        }
+
+        if let RenditionState::Open(encoder) = &mut self.state {
+            if self.recovery_state.join_due(Instant::now()) {
+                encoder.inner.request_keyframe();
+                crate::metrics::get_metrics().encoder_recoveries.inc();
+                self.recovery_state.start(Instant::now());
+            }
+            self.recovery_state.on_frame(timestamp);
+            let res = encoder.encode(
+                frame,
+                timestamp,
+                self.decimator.interval,
+                &output.encoded_tx,
+                &[],
+                &output.codec_data_tx,
+            );
+            if let Err(e) = res {
+                self.mirror(e, output, main_codec_data);
+            }
+        }
+    }
+
+    fn mirror(
+        &mut self,
+        e: anyhow::Error,
+        output: &RenditionOutput,
+        main_codec_data: Option<VideoCodecData>,
+    ) {
+        tracing::warn!(
+            ?e,
+            max_framerate = self.max_framerate,
+            "Cannot encode a lower frame rate, forwarding the full frame rate"
+        );
+        self.state = RenditionState::Mirrored;
+        output.codec_data_tx.send_replace(main_codec_data);
+    }
+}
+
+/// Open an encoder for a rendition of the frames encoded by `main`, which must
+/// accept the same frames.
+fn open_rendition_encoder(
+    main: &ActiveEncoder,
+    policy: &EncoderPolicy,
+    monitor_config: &MonitorConfig,
+    encoders: &[String],
+    width: u32,
+    height: u32,
+    framerate: u32,
+) -> Result<ActiveEncoder> {
+    let opened = open_any_encoder(
+        policy,
+        monitor_config,
+        encoders,
+        main.index,
+        width,
+        height,
+        framerate,
+    )?;
+    if opened.index != main.index
+        || opened.input_spec(width, height) != main.input_spec(width, height)
+    {
+        anyhow::bail!("{} cannot be opened a second time", main.name());
+    }
+    Ok(opened)
+}
+
+fn retry_delay(retries: u32) -> Duration {
+    MIN_RETRY_DELAY
+        .saturating_mul(1u32 << retries.min(16))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Convert captured BGRA frames into the input format of the encoder.
+///
+/// The target format is published by the encoding thread whenever an encoder is opened.
+fn conversion_thread(
+    captured_rx: channel::Receiver<CapturedFrame>,
+    converted_tx: channel::Sender<ConvertedFrame>,
+    converted_rx: channel::Receiver<ConvertedFrame>,
+    target_rx: watch::Receiver<Option<ImageSpec>>,
+    data_tx: broadcast::Sender<Sample>,
+    renditions: Renditions,
+) {
+    crate::utils::set_thread_characteristics();
+
+    let metrics = crate::metrics::get_metrics();
+    let conversion_latency_ms_local = metrics
+        .stage_latency_ms
+        .with_label_values(&["conversion"])
+        .local();
+
+    let pool: Pool<Frame> = Pool::new(PIPELINE_DEPTH);
+    let mut scaler = Scaler::new();
+    if let Err(e) = scaler.set_colorspace(
+        ColorMatrix::Bt709,
+        ColorRange::Full,
+        ColorMatrix::Bt709,
+        ColorRange::Limited,
+    ) {
+        tracing::error!(?e, "Failed to set colorspace");
+    }
+
+    let mut last_receiver_count = 0;
+    let mut converted_frames = 0u32;
+
+    while let Ok(captured) = captured_rx.recv() {
+        let receiver_count = data_tx.receiver_count()
+            + renditions
+                .lock()
+                .unwrap()
+                .values()
+                .map(|output| output.encoded_tx.receiver_count())
+                .sum::<usize>();
+        if receiver_count == 0 {
+            if last_receiver_count > 0 {
+                tracing::info!("No more connected clients, stopping encoding");
+                last_receiver_count = 0;
+            }
+            continue;
+        } else if receiver_count > last_receiver_count {
+            if last_receiver_count == 0 {
+                tracing::info!("New client connected, starting encoding");
+            }
+            tracing::info!("New client connected");
+        }
+        last_receiver_count = receiver_count;
+
+        let target = match *target_rx.borrow() {
+            Some(target) => target,
+            // No encoder yet
+            None => continue,
+        };
+
+        if captured.buffer.len() != (captured.width * captured.height * 4) as usize {
+            tracing::warn!("Invalid buffer size");
+            continue;
+        }
+
+        if captured.width != target.width || captured.height != target.height {
+            // Reconfiguration in progress
+            continue;
+        }
+
+        let conversion_start = Instant::now();
+        match convert_frame(&pool, &mut scaler, &captured, target) {
+            Ok(Some(frame)) => {
+                let frame = ConvertedFrame {
+                    frame,
+                    timestamp: captured.timestamp,
+                };
+                send_latest(&converted_tx, &converted_rx, frame, "encode");
+            }
+            Ok(None) => {
+                metrics
+                    .dropped_frames
+                    .with_label_values(&["conversion"])
+                    .inc();
+            }
+            Err(e) => {
+                tracing::error!(?e, "Failed to convert frame");
+                metrics
+                    .dropped_frames
+                    .with_label_values(&["conversion"])
+                    .inc();
+            }
+        }
+
+        conversion_latency_ms_local.observe(conversion_start.elapsed().as_secs_f64() * 1000.0);
+        converted_frames += 1;
+        if converted_frames > 120 {
+            conversion_latency_ms_local.flush();
+            converted_frames = 0;
+        }
+    }
+}
+
+/// Convert `captured` into a pooled frame matching `target`, or `None` if all frames are in use.
+fn convert_frame(
+    pool: &Pool<Frame>,
+    scaler: &mut Scaler,
+    captured: &CapturedFrame,
+    target: ImageSpec,
+) -> Result<Option<Pooled<Frame>>> {
+    let mut frame = loop {
+        match pool.try_get(|| Frame::new(target.width, target.height, target.format))? {
+            Some(frame)
+                if frame.width() == target.width as usize
+                    && frame.height() == target.height as usize
+                    && frame.format() == target.format =>
+            {
+                break frame
+            }
+            // Allocated for a previous encoder
+            Some(frame) => frame.discard(),
+            None => return Ok(None),
+        }
+    };
+
+    // The encoder might still be holding on to the previous contents.
+    frame.make_writable()?;
+
+    let src: &[u8] = &captured.buffer;
+    let stride = captured.width as usize * 4;
+    if target.format == ffi::AVPixelFormat_AV_PIX_FMT_BGRA {
+        // Uploaded as-is, the device takes care of the conversion
+        frame.copy_from_planes(&[src], &[stride])?;
+    } else {
+        scaler
+            .configure(
+                ImageSpec::new(
+                    captured.width,
+                    captured.height,
+                    ffi::AVPixelFormat_AV_PIX_FMT_BGRA,
+                ),
+                target,
+                ScaleAlgorithm::Bilinear,
+                ScaleFlags::empty(),
+            )?
+            .scale(&[src], &[stride], &mut frame)?;
+    }
+
+    Ok(Some(frame))
+}
+
 fn encoding_thread(
     cmd_rx: channel::Receiver<EncodingCommand>,
+    recovery: RecoveryQueue,
+    recovery_rx: channel::Receiver<()>,
+    frame_rx: channel::Receiver<ConvertedFrame>,
     data_tx: broadcast::Sender<Sample>,
+    renditions: Renditions,
     codec_data_tx: watch::Sender<Option<VideoCodecData>>,
-    bgra_buffer: Arc<Mutex<Vec<u8>>>,
+    target_tx: watch::Sender<Option<ImageSpec>>,
+    policy: EncoderPolicy,
+    monitor_config: MonitorConfig,
 ) -> Result<()> {
     crate::utils::set_thread_characteristics();
 
+    let encoders = policy.candidates();
+    tracing::info!(
+        ?encoders,
+        preset = ?policy.preset,
+        intra_refresh_period = ?monitor_config.intra_refresh_period,
+        "Encoder policy"
+    );
+
     let metrics = crate::metrics::get_metrics();
     let encoded_frames_local = metrics.encoded_frames.local();
     let encoding_latency_ms_local = metrics.encoding_latency_ms.local();
+    let stage_latency_ms_local = metrics
+        .stage_latency_ms
+        .with_label_values(&["encode"])
+        .local();
 
-    let mut encoder: Option<OpenedCodecContext> = None;
+    let mut encoder: Option<ActiveEncoder> = None;
+    // Opened next to `encoder` while their renditions have clients.
+    let mut rendition_encoders: BTreeMap<u32, RenditionEncoder> = BTreeMap::new();
+    // Forwarded to mirrored renditions.
+    let mut main_codec_data_rx = codec_data_tx.subscribe();
 
-    let mut pts = 0;
     let mut width = 0u32;
     let mut height = 0u32;
     let mut framerate = 0;
     let mut sample_duration = Duration::from_secs_f64(0.0);
 
-    let mut last_receiver_count = 0;
-
-    let dcp_src_format = dcp::ImageFormat {
-        pixel_format: dcp::PixelFormat::Bgra,
-        color_space: dcp::ColorSpace::Rgb,
-        num_planes: 1,
-    };
-
-    let dcp_dst_format = dcp::ImageFormat {
-        pixel_format: dcp::PixelFormat::Nv12,
-        color_space: dcp::ColorSpace::Bt709,
-        num_planes: 2,
-    };
+    // == Recovery
+    // First entry of `encoders` to try when (re)opening the encoder.
+    let mut first_encoder = 0;
+    // Consecutive failures of the current encoder.
+    let mut encoder_failures = 0;
+    // Consecutive attempts without successfully encoding a frame, for the backoff.
+    let mut retries = 0;
+    let mut retry_at: Option<Instant> = None;
+    // Encoder that failed at runtime, to report the fallback once a new one is opened.
+    let mut failed_encoder: Option<usize> = None;
+    let mut recovery_state = RecoveryState::default();
+    // Joining clients can wait for the next refresh
+    let periodic_refresh = monitor_config.intra_refresh_period.is_some();
+
+    loop {
+        let retry_timer = match retry_at {
+            Some(at) if encoder.is_none() => channel::at(at),
+            _ => channel::never(),
+        };
 
-    let mut sps = None;
-    let mut pps = None;
-    let mut sps_pps_sent = false;
+        let cmd = channel::select! {
+            recv(cmd_rx) -> cmd => cmd.ok(),
+            recv(recovery_rx) -> r => r.ok().map(|_| EncodingCommand::Recover),
+            recv(frame_rx) -> frame => frame.ok().map(EncodingCommand::NewFrame),
+            recv(retry_timer) -> _ => Some(EncodingCommand::Reopen),
+        };
+        let cmd = match cmd {
+            Some(cmd) => cmd,
+            None => break,
+        };
 
-    while let Ok(cmd) = cmd_rx.recv() {
         match cmd {
-            EncodingCommand::NewFrame(timestamp) => {
-                tracing::trace!("New frame");
+            EncodingCommand::Recover => {
+                let mut main_request = recovery.take();
+                for (max_framerate, output) in rendition_outputs(&renditions) {
+                    let request = match output.recovery.take() {
+                        Some(request) => request,
+                        None => continue,
+                    };
+                    let rendition = match rendition_encoders.get_mut(&max_framerate) {
+                        Some(rendition) => rendition,
+                        // Starts with an IDR frame anyway
+                        None => continue,
+                    };
+
+                    match &mut rendition.state {
+                        RenditionState::Open(active) => {
+                            if rendition.recovery_state.needs_recovery(
+                                &request,
+                                periodic_refresh,
+                                Instant::now(),
+                            ) {
+                                active.inner.request_keyframe();
+                                metrics.encoder_recoveries.inc();
+                                rendition.recovery_state.start(Instant::now());
+                            }
+                        }
+                        RenditionState::Mirrored => {
+                            main_request = Some(match main_request {
+                                Some(main_request) => main_request.merge(request),
+                                None => request,
+                            });
+                        }
+                        RenditionState::Closed => {}
+                    }
+                }
 
-                let encoder = match encoder.as_mut() {
+                let request = match main_request {
+                    Some(request) => request,
+                    // Taken along with an earlier request
+                    None => continue,
+                };
+                let active = match encoder.as_mut() {
                     Some(encoder) => encoder,
+                    // A new encoder starts with an IDR frame anyway
                     None => continue,
                 };
 
-                let receiver_count = data_tx.receiver_count();
-                if receiver_count == 0 {
-                    if last_receiver_count > 0 {
-                        tracing::info!("No more connected clients, stopping encoding");
-                        last_receiver_count = 0;
-                    }
+                if !recovery_state.needs_recovery(&request, periodic_refresh, Instant::now()) {
                     continue;
-                } else if receiver_count > last_receiver_count {
-                    if last_receiver_count == 0 {
-                        tracing::info!("New client connected, starting encoding");
-                    }
-                    tracing::info!("New client connected, forcing keyframe");
                 }
 
-                let frame = encoder.request_frame()?;
-                let [y, uv, _, _] = frame.planes_mut();
-                let mut y = y.unwrap();
-                let mut uv = uv.unwrap();
-
-                let src = bgra_buffer.lock().unwrap();
-
-                if src.len() != (width * height * 4) as usize {
-                    tracing::warn!("Invalid buffer size");
+                tracing::debug!(codec = active.name(), recovery = ?active.recovery, "Recovering for clients");
+                active.inner.request_keyframe();
+                metrics.encoder_recoveries.inc();
+                recovery_state.start(Instant::now());
+            }
+            EncodingCommand::Reopen => {
+                if encoder.is_some() {
                     continue;
                 }
 
-                dcp::convert_image(
+                match open_any_encoder(
+                    &policy,
+                    &monitor_config,
+                    &encoders,
+                    first_encoder,
                     width,
                     height,
-                    &dcp_src_format,
-                    None,
-                    &[src.as_slice()],
-                    &dcp_dst_format,
-                    Some(&[y.line_size(), uv.line_size()]),
-                    &mut [y.data(), uv.data()],
-                )?;
-
-                let encoding_start = Instant::now();
-                encoder.send_frame(pts)?;
-                pts += 1;
-
-                while let Some(packet) = encoder.receive_packet()? {
-                    let data = if let Some(data) = packet.data() {
-                        data
-                    } else {
-                        continue;
-                    };
-
-                    tracing::trace!("Sending frame");
-
-                    if !sps_pps_sent {
-                        let cursor = std::io::Cursor::new(data);
-                        let mut reader = H264Reader::new(cursor, 1024 * 1024);
-                        while let Ok(nal) = reader.next_nal() {
-                            match nal.unit_type {
-                                NalUnitType::SPS => {
-                                    let mut cur_sps = vec![0, 0, 0, 1];
-                                    cur_sps.extend_from_slice(&nal.data);
-                                    sps = Some(cur_sps);
-                                }
-                                NalUnitType::PPS => {
-                                    let mut cur_pps = vec![0, 0, 0, 1];
-                                    cur_pps.extend_from_slice(&nal.data);
-                                    pps = Some(cur_pps);
-                                }
-                                _ => {}
+                    framerate,
+                ) {
+                    Ok(opened) => {
+                        if let Some(failed) = failed_encoder.take() {
+                            if failed != opened.index {
+                                tracing::warn!(
+                                    from = %encoders[failed],
+                                    to = opened.name(),
+                                    "Encoder fell back"
+                                );
+                                metrics
+                                    .encoder_fallbacks
+                                    .with_label_values(&[encoders[failed].as_str(), opened.name()])
+                                    .inc();
                             }
                         }
 
-                        if let (Some(sps), Some(pps)) = (&sps, &pps) {
-                            codec_data_tx
-                                .send(Some(VideoCodecData::H264 {
-                                    sps: sps.clone().into(),
-                                    pps: pps.clone().into(),
-                                }))
-                                .ok();
+                        tracing::info!(codec = opened.name(), "Encoder reopened");
+                        retry_at = None;
+                        target_tx.send_replace(Some(opened.input_spec(width, height)));
+                        encoder = Some(opened);
+                    }
+                    Err(e) => {
+                        retries += 1;
+                        let delay = retry_delay(retries);
+                        tracing::error!(?e, ?delay, "Failed to reopen encoder, retrying");
+                        retry_at = Some(Instant::now() + delay);
+                    }
+                }
+            }
+            EncodingCommand::NewFrame(mut converted) => {
+                tracing::trace!("New frame");
+
+                let active = match encoder.as_mut() {
+                    Some(encoder) => encoder,
+                    None => continue,
+                };
 
-                            sps_pps_sent = true;
+                let frame_spec = ImageSpec::new(
+                    converted.frame.width() as u32,
+                    converted.frame.height() as u32,
+                    converted.frame.format(),
+                );
+                if frame_spec != active.input_spec(width, height) {
+                    // Converted for a previous encoder
+                    continue;
+                }
 
-                            tracing::info!("SPS/PPS sent");
+                if recovery_state.join_due(Instant::now()) {
+                    active.inner.request_keyframe();
+                    metrics.encoder_recoveries.inc();
+                    recovery_state.start(Instant::now());
+                }
+                recovery_state.on_frame(converted.timestamp);
+
+                let outputs = rendition_outputs(&renditions);
+                // Renditions without clients release their encoder
+                rendition_encoders.retain(|f, _| outputs.iter().any(|(o, _)| o == f));
+                if main_codec_data_rx.has_changed().unwrap_or(false) {
+                    let codec_data = main_codec_data_rx.borrow_and_update().clone();
+                    for (max_framerate, output) in &outputs {
+                        if rendition_encoders
+                            .get(max_framerate)
+                            .is_some_and(|r| r.is_mirrored())
+                        {
+                            output.codec_data_tx.send_replace(codec_data.clone());
                         }
                     }
+                }
+                let mirrors: Vec<_> = outputs
+                    .iter()
+                    .filter(|(f, _)| rendition_encoders.get(f).is_some_and(|r| r.is_mirrored()))
+                    .map(|(_, output)| output.encoded_tx.clone())
+                    .collect();
+
+                let encoding_start = Instant::now();
+                let res = active.encode(
+                    &mut converted.frame,
+                    converted.timestamp,
+                    sample_duration,
+                    &data_tx,
+                    &mirrors,
+                    &codec_data_tx,
+                );
+
+                if res.is_ok() {
+                    for (max_framerate, output) in &outputs {
+                        let rendition = rendition_encoders
+                            .entry(*max_framerate)
+                            .or_insert_with(|| RenditionEncoder::new(*max_framerate));
+                        rendition.encode(
+                            &mut converted.frame,
+                            converted.timestamp,
+                            sample_duration,
+                            output,
+                            codec_data_tx.borrow().clone(),
+                            || {
+                                open_rendition_encoder(
+                                    active,
+                                    &policy,
+                                    &monitor_config,
+                                    &encoders,
+                                    width,
+                                    height,
+                                    *max_framerate,
+                                )
+                            },
+                        );
+                    }
+                }
+                // Return the frame to the pool as early as possible
+                drop(converted);
 
-                    let sample = Sample::new(data, timestamp, sample_duration);
-                    data_tx.send(sample).ok();
+                match res {
+                    Ok(()) => {
+                        encoder_failures = 0;
+                        retries = 0;
+                    }
+                    Err(e) => {
+                        let index = active.index;
+                        encoder_failures += 1;
+                        retries += 1;
+                        metrics.encoder_errors.inc();
+
+                        first_encoder = index;
+                        if encoder_failures >= MAX_ENCODER_FAILURES && index + 1 < encoders.len() {
+                            // Give up on this encoder
+                            first_encoder = index + 1;
+                            encoder_failures = 0;
+                        }
+
+                        let delay = retry_delay(retries);
+                        tracing::error!(
+                            ?e,
+                            codec = %encoders[index],
+                            ?delay,
+                            "Encoding failed, reopening encoder"
+                        );
+
+                        // Drop the encoder so that a new one is opened, which will
+                        // re-broadcast the codec data.
+                        encoder = None;
+                        rendition_encoders.clear();
+                        target_tx.send_replace(None);
+                        failed_encoder = Some(index);
+                        retry_at = Some(Instant::now() + delay);
+                        continue;
+                    }
                 }
 
+                let encoding_latency_ms = encoding_start.elapsed().as_secs_f64() * 1000.0;
                 encoded_frames_local.inc();
-                encoding_latency_ms_local.observe(encoding_start.elapsed().as_secs_f64() * 1000.0);
-                last_receiver_count = receiver_count;
+                encoding_latency_ms_local.observe(encoding_latency_ms);
+                stage_latency_ms_local.observe(encoding_latency_ms);
             }
             EncodingCommand::Configure {
                 width: width_,
@@ -428,60 +1482,606 @@ fn encoding_thread(
 
                 tracing::info!(?width, ?height, ?framerate, "Configuring encoder with");
 
-                let mut device_context = None;
-                let mut codec = Codec::find_by_name("libx264").unwrap();
+                // Start over with the preferred encoders, the failure might have been transient.
+                encoder = None;
+                rendition_encoders.clear();
+                target_tx.send_replace(None);
+                first_encoder = 0;
+                encoder_failures = 0;
+                retries = 0;
+                failed_encoder = None;
+
+                match open_any_encoder(
+                    &policy,
+                    &monitor_config,
+                    &encoders,
+                    first_encoder,
+                    width,
+                    height,
+                    framerate,
+                ) {
+                    Ok(opened) => {
+                        tracing::info!(codec = opened.name(), "Encoder configured");
+                        retry_at = None;
+                        target_tx.send_replace(Some(opened.input_spec(width, height)));
+                        encoder = Some(opened);
+                    }
+                    Err(e) => {
+                        let delay = retry_delay(retries);
+                        tracing::error!(?e, ?delay, "Failed to configure encoder, retrying");
+                        retry_at = Some(Instant::now() + delay);
+                    }
+                }
+            }
+        }
+
+        if encoded_frames_local.get() > 120 {
+            // Flush metrics to the global registry every 2 seconds
+            encoded_frames_local.flush();
+            encoding_latency_ms_local.flush();
+            stage_latency_ms_local.flush();
+        }
+    }
 
-                for hw_codec_name in &["h264_qsv", "h264_nvenc", "h264_amf"] {
-                    let hw_codec = if let Some(codec) = Codec::find_by_name(hw_codec_name) {
-                        codec
-                    } else {
-                        continue;
-                    };
+    Ok(())
+}
 
-                    for hw_config in hw_codec.hw_configs() {
-                        if !hw_config.methods.contains(HwCodecSetupMethod::HwDeviceCtx) {
-                            continue;
-                        }
+/// Publishes the bands of a tile frame captured at `timestamp` as they are compressed.
+fn band_sender(
+    tx: &broadcast::Sender<Sample>,
+    timestamp: Instant,
+    duration: Duration,
+) -> impl FnMut(Bytes, bool, bool) + '_ {
+    let mut first = true;
+    move |data, keyframe, end_of_frame| {
+        // Clients must start at the first band of a keyframe to receive all of its tiles
+        let recovery_point = keyframe && std::mem::take(&mut first);
+        let sample = Sample::new(data, timestamp, duration)
+            .with_recovery_point(recovery_point)
+            .with_end_of_frame(end_of_frame);
+        tx.send(sample).ok();
+    }
+}
+
+/// Encoding thread of monitors using the lossless tile codec instead of an FFmpeg encoder.
+#[allow(clippy::too_many_arguments)]
+fn raw_encoding_thread(
+    cmd_rx: channel::Receiver<EncodingCommand>,
+    recovery: RecoveryQueue,
+    recovery_rx: channel::Receiver<()>,
+    frame_rx: channel::Receiver<ConvertedFrame>,
+    data_tx: broadcast::Sender<Sample>,
+    renditions: Renditions,
+    codec_data_tx: watch::Sender<Option<VideoCodecData>>,
+    target_tx: watch::Sender<Option<ImageSpec>>,
+    slices: u32,
+) -> Result<()> {
+    crate::utils::set_thread_characteristics();
+
+    let metrics = crate::metrics::get_metrics();
+    let encoded_frames_local = metrics.encoded_frames.local();
+    let encoding_latency_ms_local = metrics.encoding_latency_ms.local();
+    let stage_latency_ms_local = metrics
+        .stage_latency_ms
+        .with_label_values(&["encode"])
+        .local();
+
+    let mut encoder: Option<(TileEncoder, ImageSpec)> = None;
+    // Tiles are sent relative to the last frame of each rendition, so each needs its own encoder.
+    let mut rendition_encoders: BTreeMap<u32, (TileEncoder, FrameDecimator, RecoveryState)> =
+        BTreeMap::new();
+    let mut framerate = 0;
+    let mut sample_duration = Duration::from_secs_f64(0.0);
+    let mut recovery_state = RecoveryState::default();
+
+    loop {
+        let cmd = channel::select! {
+            recv(cmd_rx) -> cmd => cmd.ok(),
+            recv(recovery_rx) -> r => r.ok().map(|_| EncodingCommand::Recover),
+            recv(frame_rx) -> frame => frame.ok().map(EncodingCommand::NewFrame),
+        };
+        let cmd = match cmd {
+            Some(cmd) => cmd,
+            None => break,
+        };
 
-                        if let Ok(ctx) = HwDeviceContext::new(hw_config.device_type) {
-                            device_context = Some(ctx);
-                            codec = hw_codec;
-                            break;
+        match cmd {
+            EncodingCommand::Recover => {
+                for (max_framerate, output) in rendition_outputs(&renditions) {
+                    let request = match output.recovery.take() {
+                        Some(request) => request,
+                        None => continue,
+                    };
+                    if let Some((active, _, state)) = rendition_encoders.get_mut(&max_framerate) {
+                        if state.needs_recovery(&request, false, Instant::now()) {
+                            active.request_keyframe();
+                            metrics.encoder_recoveries.inc();
+                            state.start(Instant::now());
                         }
                     }
+                }
+
+                let request = match recovery.take() {
+                    Some(request) => request,
+                    None => continue,
+                };
+                let (active, _) = match encoder.as_mut() {
+                    Some(encoder) => encoder,
+                    None => continue,
+                };
+
+                // Keyframes are large, do not send them for every report
+                if !recovery_state.needs_recovery(&request, false, Instant::now()) {
+                    continue;
+                }
 
-                    if device_context.is_some() {
-                        break;
+                active.request_keyframe();
+                metrics.encoder_recoveries.inc();
+                recovery_state.start(Instant::now());
+            }
+            // Cannot fail to open
+            EncodingCommand::Reopen => {}
+            EncodingCommand::NewFrame(converted) => {
+                let (active, spec) = match encoder.as_mut() {
+                    Some(encoder) => encoder,
+                    None => continue,
+                };
+
+                let frame_spec = ImageSpec::new(
+                    converted.frame.width() as u32,
+                    converted.frame.height() as u32,
+                    converted.frame.format(),
+                );
+                if frame_spec != *spec {
+                    // Converted before the last configuration
+                    continue;
+                }
+
+                if recovery_state.join_due(Instant::now()) {
+                    active.request_keyframe();
+                    metrics.encoder_recoveries.inc();
+                    recovery_state.start(Instant::now());
+                }
+                recovery_state.on_frame(converted.timestamp);
+
+                let outputs = rendition_outputs(&renditions);
+                rendition_encoders.retain(|f, _| outputs.iter().any(|(o, _)| o == f));
+
+                let encoding_start = Instant::now();
+                let plane = match &converted.frame.planes()[0] {
+                    Some(plane) => plane,
+                    None => {
+                        tracing::error!("Frame has no data");
+                        metrics.encoder_errors.inc();
+                        continue;
+                    }
+                };
+                let timestamp = converted.timestamp;
+
+                for (max_framerate, output) in &outputs {
+                    let (rendition, decimator, state) =
+                        rendition_encoders.entry(*max_framerate).or_insert_with(|| {
+                            output.codec_data_tx.send_replace(Some(VideoCodecData::Raw {
+                                tile_size: TILE_SIZE,
+                            }));
+                            (
+                                TileEncoder::new(spec.width, spec.height),
+                                FrameDecimator::new(*max_framerate),
+                                RecoveryState::default(),
+                            )
+                        });
+                    if !decimator.pass(timestamp, sample_duration) {
+                        continue;
+                    }
+
+                    if state.join_due(Instant::now()) {
+                        rendition.request_keyframe();
+                        metrics.encoder_recoveries.inc();
+                        state.start(Instant::now());
                     }
+                    state.on_frame(timestamp);
+                    let interval = decimator.interval;
+                    let res = rendition.encode_bands(
+                        plane.data(),
+                        plane.line_size(),
+                        slices,
+                        band_sender(&output.encoded_tx, timestamp, interval),
+                    );
+                    if let Err(e) = res {
+                        tracing::error!(?e, max_framerate, "Encoding rendition failed");
+                        metrics.encoder_errors.inc();
+                    }
+                }
+
+                let res = active.encode_bands(
+                    plane.data(),
+                    plane.line_size(),
+                    slices,
+                    band_sender(&data_tx, timestamp, sample_duration),
+                );
+                drop(converted);
+
+                if let Err(e) = res {
+                    tracing::error!(?e, "Encoding failed");
+                    metrics.encoder_errors.inc();
+                    continue;
                 }
 
-                let mut ctx = CodecContext::new(codec);
-                ctx.set_size(width, height)
-                    .set_framerate(framerate, 1)
-                    .set_time_base(1, framerate)
-                    .set_pix_fmt(ffmpeg_simple::ffi::AVPixelFormat_AV_PIX_FMT_NV12)
-                    .set_global_quality(25)
-                    .set_option("profile", "baseline")?
-                    .set_option("b_strategy", "0")?
-                    .set_option("idr_interval", "1")?;
-                if let Some(device_context) = device_context {
-                    ctx.set_hw_device_ctx(device_context);
+                let encoding_latency_ms = encoding_start.elapsed().as_secs_f64() * 1000.0;
+                encoded_frames_local.inc();
+                encoding_latency_ms_local.observe(encoding_latency_ms);
+                stage_latency_ms_local.observe(encoding_latency_ms);
+            }
+            EncodingCommand::Configure {
+                width,
+                height,
+                framerate: framerate_,
+            } => {
+                let spec = ImageSpec::new(width, height, ffi::AVPixelFormat_AV_PIX_FMT_BGRA);
+                if matches!(&encoder, Some((_, s)) if *s == spec) && framerate == framerate_ {
+                    // No change
+                    continue;
                 }
 
-                encoder = Some(ctx.open()?);
+                framerate = framerate_.max(1);
+                sample_duration = Duration::from_secs_f64(1.0 / framerate as f64);
 
-                sps_pps_sent = false;
+                tracing::info!(?width, ?height, ?framerate, "Configuring tile encoder");
 
-                tracing::info!("Encoder configured");
+                encoder = Some((TileEncoder::new(width, height), spec));
+                rendition_encoders.clear();
+                target_tx.send_replace(Some(spec));
+                codec_data_tx
+                    .send(Some(VideoCodecData::Raw {
+                        tile_size: TILE_SIZE,
+                    }))
+                    .ok();
             }
         }
 
         if encoded_frames_local.get() > 120 {
-            // Flush metrics to the global registry every 2 seconds
             encoded_frames_local.flush();
             encoding_latency_ms_local.flush();
+            stage_latency_ms_local.flush();
         }
     }
 
     Ok(())
 }
+
+/// Open the first working encoder in `encoders`, starting at index `first`.
+fn open_any_encoder(
+    policy: &EncoderPolicy,
+    monitor_config: &MonitorConfig,
+    encoders: &[String],
+    first: usize,
+    width: u32,
+    height: u32,
+    framerate: u32,
+) -> Result<ActiveEncoder> {
+    for (index, name) in encoders.iter().enumerate().skip(first) {
+        let codec = if let Some(codec) = Codec::find_by_name(name) {
+            codec
+        } else {
+            continue;
+        };
+
+        let mut opened = None;
+        for chroma in policy.chroma_formats_for(name, monitor_config) {
+            let pix_fmt = chroma_pix_fmt(chroma);
+            if chroma != ChromaFormat::Yuv420 && !codec.pixel_formats().any(|f| f == pix_fmt) {
+                continue;
+            }
+
+            let spec = ImageSpec::new(width, height, pix_fmt);
+            let options = policy.options_for(name, monitor_config, chroma);
+            match open_codec(codec, spec, framerate, &options) {
+                Ok(inner) => {
+                    opened = Some((inner, chroma));
+                    break;
+                }
+                Err(e) => {
+                    tracing::warn!(?e, codec = %name, ?chroma, "Failed to open encoder");
+                }
+            }
+        }
+        let (inner, chroma) = if let Some(opened) = opened {
+            opened
+        } else {
+            continue;
+        };
+
+        let input_format = inner.input_format();
+        let scalability = policy.scalability_for(name);
+        tracing::info!(
+            codec = %name,
+            ?input_format,
+            ?chroma,
+            ?scalability,
+            "Opened encoder"
+        );
+
+        return Ok(ActiveEncoder {
+            index,
+            name: name.clone(),
+            inner,
+            pts: 0,
+            recovery: policy.recovery_for(name, monitor_config),
+            scalability,
+            layered: policy.scalability != ScalabilityMode::L1T1,
+            frames_since_keyframe: 0,
+            intermediate_layers_kept: false,
+            chroma,
+            sps: None,
+            pps: None,
+            sps_pps_sent: false,
+        });
+    }
+
+    anyhow::bail!("No usable encoder found")
+}
+
+/// Pixel format of frames sent to encoders encoding `chroma`.
+fn chroma_pix_fmt(chroma: ChromaFormat) -> ffi::AVPixelFormat {
+    match chroma {
+        ChromaFormat::Yuv420 => ffi::AVPixelFormat_AV_PIX_FMT_NV12,
+        ChromaFormat::Yuv444 => ffi::AVPixelFormat_AV_PIX_FMT_YUV444P,
+    }
+}
+
+/// Open `codec` with input frames of `spec`, on the first available hardware
+/// device if it is a hardware encoder.
+fn open_codec(
+    codec: Codec,
+    spec: ImageSpec,
+    framerate: u32,
+    options: &[(String, String)],
+) -> Result<OpenedCodecContext> {
+    let mut hw_configs = codec
+        .hw_configs()
+        .filter(|c| c.methods.contains(HwCodecSetupMethod::HwDeviceCtx))
+        .peekable();
+
+    if hw_configs.peek().is_none() {
+        // Software encoder
+        return open_encoder(codec, spec, framerate, options, None, None);
+    }
+
+    let mut hw = None;
+    for hw_config in hw_configs {
+        if let Ok(ctx) = HwDeviceContext::new(hw_config.device_type) {
+            hw = Some((ctx, hw_config));
+            break;
+        }
+    }
+
+    let (device_context, hw_config) = match hw {
+        Some(hw) => hw,
+        None => anyhow::bail!("No hardware device available for {}", codec.name()),
+    };
+
+    // The device would subsample the chroma of uploaded BGRA frames
+    if spec.format == ffi::AVPixelFormat_AV_PIX_FMT_NV12
+        && hw_config.methods.contains(HwCodecSetupMethod::HwFramesCtx)
+        && device_context
+            .valid_sw_formats()
+            .contains(&ffi::AVPixelFormat_AV_PIX_FMT_BGRA)
+    {
+        // Try uploading BGRA directly and let the device convert it
+        let res = HwFramesContext::new(
+            &device_context,
+            hw_config.pix_fmt,
+            ffi::AVPixelFormat_AV_PIX_FMT_BGRA,
+            spec.width,
+            spec.height,
+            HW_FRAMES_POOL_SIZE,
+        )
+        .map_err(anyhow::Error::from)
+        .and_then(|hw_frames_ctx| {
+            open_encoder(
+                codec,
+                spec,
+                framerate,
+                options,
+                Some(device_context.clone()),
+                Some(hw_frames_ctx),
+            )
+        });
+
+        match res {
+            Ok(e) => return Ok(e),
+            Err(e) => {
+                tracing::warn!(
+                    ?e,
+                    codec = codec.name(),
+                    "BGRA upload not supported, converting on the CPU"
+                );
+            }
+        }
+    }
+
+    open_encoder(codec, spec, framerate, options, Some(device_context), None)
+}
+
+fn open_encoder(
+    codec: Codec,
+    spec: ImageSpec,
+    framerate: u32,
+    options: &[(String, String)],
+    device_context: Option<HwDeviceContext>,
+    hw_frames_ctx: Option<HwFramesContext>,
+) -> Result<OpenedCodecContext> {
+    let mut ctx = CodecContext::new(codec);
+    ctx.set_size(spec.width, spec.height)
+        .set_framerate(framerate, 1)
+        .set_time_base(1, framerate)
+        .set_pix_fmt(spec.format)
+        .set_global_quality(25);
+    if let Some(device_context) = device_context {
+        ctx.set_hw_device_ctx(device_context);
+    }
+    if let Some(hw_frames_ctx) = hw_frames_ctx {
+        ctx.set_hw_frames_ctx(hw_frames_ctx);
+    }
+
+    let options = options
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect::<Dictionary>();
+    let (opened, unused_options) = ctx.open_with_options(options)?;
+    for (key, value) in unused_options.entries() {
+        tracing::debug!(codec = codec.name(), %key, %value, "Encoder option not used");
+    }
+
+    Ok(opened)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Capture times of `count` frames at `framerate`, starting at `start`.
+    fn frame_times(start: Instant, framerate: u32, count: u32) -> Vec<Instant> {
+        (0..count)
+            .map(|i| start + Duration::from_secs_f64(i as f64 / framerate as f64))
+            .collect()
+    }
+
+    /// Indices of the frames kept by a decimator to `max_framerate` from `framerate`.
+    fn decimate(framerate: u32, max_framerate: u32, count: u32) -> Vec<u32> {
+        let frame_duration = Duration::from_secs_f64(1.0 / framerate as f64);
+        let mut decimator = FrameDecimator::new(max_framerate);
+        frame_times(Instant::now(), framerate, count)
+            .into_iter()
+            .zip(0..)
+            .filter(|(timestamp, _)| decimator.pass(*timestamp, frame_duration))
+            .map(|(_, i)| i)
+            .collect()
+    }
+
+    const LOSS: RecoveryRequest = RecoveryRequest {
+        lost: true,
+        last_decoded: None,
+        joined: false,
+    };
+    const JOIN: RecoveryRequest = RecoveryRequest {
+        lost: false,
+        last_decoded: None,
+        joined: true,
+    };
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_first_loss() {
+        let mut state = RecoveryState::default();
+        assert!(state.needs_recovery(&LOSS, false, Instant::now()));
+    }
+
+    #[test]
+    fn test_covered_loss() {
+        let t0 = Instant::now() + ms(50);
+        let mut state = RecoveryState::default();
+        state.start(t0);
+        state.on_frame(t0 + ms(10));
+        state.on_frame(t0 + ms(26));
+
+        // Reported again before the recovery reached the client
+        assert!(!state.needs_recovery(&LOSS, false, t0 + ms(100)));
+        let before = RecoveryRequest {
+            last_decoded: Some(t0 - ms(50)),
+            ..LOSS
+        };
+        assert!(!state.needs_recovery(&before, false, t0 + ms(100)));
+
+        // The client decoded the recovery and lost a later frame
+        let after = RecoveryRequest {
+            last_decoded: Some(t0 + ms(10)),
+            ..LOSS
+        };
+        assert!(state.needs_recovery(&after, false, t0 + ms(100)));
+
+        // The recovery should have arrived by now
+        assert!(state.needs_recovery(&before, false, t0 + MIN_RECOVERY_INTERVAL));
+        assert!(state.needs_recovery(&LOSS, false, t0 + MIN_RECOVERY_INTERVAL));
+    }
+
+    #[test]
+    fn test_join_rate_limit() {
+        let t0 = Instant::now();
+        let mut state = RecoveryState::default();
+        assert!(state.needs_recovery(&JOIN, false, t0));
+        state.start(t0);
+
+        // Postponed to one interval after the last recovery
+        assert!(!state.needs_recovery(&JOIN, false, t0 + ms(200)));
+        assert!(!state.needs_recovery(&JOIN, false, t0 + ms(600)));
+        assert!(!state.join_due(t0 + ms(900)));
+        assert!(state.join_due(t0 + MIN_JOIN_INTERVAL));
+
+        state.start(t0 + MIN_JOIN_INTERVAL);
+        assert!(!state.join_due(t0 + ms(5000)));
+        assert!(state.needs_recovery(&JOIN, false, t0 + MIN_JOIN_INTERVAL * 2));
+
+        // Losses are not rate limited along with joins
+        let joined_and_lost = RecoveryRequest { lost: true, ..JOIN };
+        state.start(t0 + ms(3000));
+        assert!(state.needs_recovery(&joined_and_lost, false, t0 + ms(3600)));
+    }
+
+    #[test]
+    fn test_join_periodic_refresh() {
+        let t0 = Instant::now();
+        let mut state = RecoveryState::default();
+        assert!(!state.needs_recovery(&JOIN, true, t0));
+        state.start(t0);
+        assert!(!state.needs_recovery(&JOIN, true, t0 + ms(200)));
+        assert!(!state.join_due(t0 + ms(5000)));
+        // Losses still need a recovery
+        assert!(state.needs_recovery(&LOSS, true, t0 + MIN_RECOVERY_INTERVAL));
+    }
+
+    #[test]
+    fn test_rendition_framerate() {
+        assert_eq!(rendition_framerate(60, None), None);
+        assert_eq!(rendition_framerate(60, Some(0)), None);
+        assert_eq!(rendition_framerate(60, Some(60)), None);
+        assert_eq!(rendition_framerate(60, Some(120)), None);
+        assert_eq!(rendition_framerate(60, Some(59)), Some(30));
+        assert_eq!(rendition_framerate(60, Some(30)), Some(30));
+        assert_eq!(rendition_framerate(60, Some(25)), Some(20));
+        assert_eq!(rendition_framerate(60, Some(1)), Some(1));
+        assert_eq!(rendition_framerate(59, Some(30)), Some(29));
+    }
+
+    #[test]
+    fn test_decimator_integer_ratio() {
+        let kept = decimate(60, 20, 600);
+        assert_eq!(kept.len(), 200);
+        assert!(kept.iter().zip(0..).all(|(i, n)| *i == n * 3));
+    }
+
+    #[test]
+    fn test_decimator_fractional_ratio() {
+        let kept = decimate(60, 25, 600);
+        assert_eq!(kept.len(), 250);
+        // Every 12 frames, alternating intervals of 2 and 3 frames
+        assert_eq!(kept[..6], [0, 2, 5, 7, 10, 12]);
+    }
+
+    #[test]
+    fn test_decimator_gap() {
+        let frame_duration = Duration::from_secs_f64(1.0 / 60.0);
+        let mut decimator = FrameDecimator::new(20);
+        let start = Instant::now();
+        assert!(decimator.pass(start, frame_duration));
+        assert!(!decimator.pass(start + frame_duration, frame_duration));
+        // A frame long after the last one is kept and restarts the schedule
+        let later = start + Duration::from_secs(1);
+        assert!(decimator.pass(later, frame_duration));
+        assert!(!decimator.pass(later + frame_duration * 2, frame_duration));
+        assert!(decimator.pass(later + frame_duration * 3, frame_duration));
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    convert::Infallible,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crossbeam::queue::ArrayQueue;
+
+struct PoolInner<T> {
+    free: ArrayQueue<T>,
+    capacity: usize,
+    /// Number of values currently owned by the pool, free or not.
+    allocated: AtomicUsize,
+}
+
+/// A fixed-size pool of reusable values (e.g. frame buffers), passed around by ownership.
+///
+/// Values are returned to the pool when the `Pooled` wrapper is dropped.
+pub struct Pool<T> {
+    inner: Arc<PoolInner<T>>,
+}
+
+impl<T> Clone for Pool<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Pool<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                free: ArrayQueue::new(capacity),
+                capacity,
+                allocated: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Take a free value, or allocate a new one with `alloc` if the pool is not full yet.
+    ///
+    /// Returns `None` if all values are in use.
+    pub fn get(&self, alloc: impl FnOnce() -> T) -> Option<Pooled<T>> {
+        match self.try_get(|| Ok::<_, Infallible>(alloc())) {
+            Ok(value) => value,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Like `get`, with an allocation that may fail.
+    pub fn try_get<E>(&self, alloc: impl FnOnce() -> Result<T, E>) -> Result<Option<Pooled<T>>, E> {
+        let value = if let Some(value) = self.inner.free.pop() {
+            value
+        } else {
+            let reserved = self
+                .inner
+                .allocated
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |allocated| {
+                    (allocated < self.inner.capacity).then_some(allocated + 1)
+                })
+                .is_ok();
+            if !reserved {
+                return Ok(None);
+            }
+
+            match alloc() {
+                Ok(value) => value,
+                Err(e) => {
+                    self.inner.allocated.fetch_sub(1, Ordering::AcqRel);
+                    return Err(e);
+                }
+            }
+        };
+
+        Ok(Some(Pooled {
+            value: Some(value),
+            pool: self.inner.clone(),
+        }))
+    }
+}
+
+pub struct Pooled<T> {
+    value: Option<T>,
+    pool: Arc<PoolInner<T>>,
+}
+
+impl<T> Pooled<T> {
+    /// Drop the value instead of returning it to the pool, e.g. because it has the wrong size.
+    ///
+    /// A new value will be allocated in its place on demand.
+    pub fn discard(mut self) {
+        self.value = None;
+        self.pool.allocated.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl<T> Deref for Pooled<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for Pooled<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for Pooled<T> {
+    fn drop(&mut self) {
+        if let Some(value) = self.value.take() {
+            // Cannot fail, at most `capacity` values are allocated.
+            let _ = self.pool.free.push(value);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! RTP payload format for AAC, `mpeg4-generic` in the AAC-hbr mode of RFC 3640.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use rtp::packetizer::Payloader;
+
+/// Encoding name of the `a=rtpmap` attribute.
+pub const ENCODING_NAME: &str = "mpeg4-generic";
+
+/// Bits of the AU-size field of an AU-header, the AU-index taking the 3 others.
+const SIZE_LENGTH: usize = 13;
+/// AU-headers-length field followed by a single AU-header.
+const HEADERS_LEN: usize = 4;
+
+/// Parameters of the `a=fmtp` attribute, for the `AudioSpecificConfig` of the stream.
+pub fn fmtp(audio_specific_config: &[u8]) -> String {
+    let config: String = audio_specific_config
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    format!(
+        "streamtype=5;profile-level-id=1;mode=AAC-hbr;sizelength={};indexlength=3;\
+         indexdeltalength=3;config={}",
+        SIZE_LENGTH, config
+    )
+}
+
+/// Puts each access unit in its own packet, fragmenting the ones larger than the MTU.
+///
+/// Fragments repeat the AU-header with the size of the whole access unit, and the last
+/// one is marked by the packetizer, which sets the marker bit on the last packet of a sample.
+#[derive(Debug, Default, Clone)]
+pub struct AacPayloader;
+
+impl Payloader for AacPayloader {
+    fn payload(&mut self, mtu: usize, payload: &Bytes) -> Result<Vec<Bytes>, rtp::Error> {
+        // Access units can only be this large at bitrates far above the ones of AAC-LC
+        if payload.is_empty() || mtu <= HEADERS_LEN || payload.len() >= 1 << SIZE_LENGTH {
+            return Ok(vec![]);
+        }
+
+        let au_header = (payload.len() << (16 - SIZE_LENGTH)) as u16;
+        let packets = payload
+            .chunks(mtu - HEADERS_LEN)
+            .map(|fragment| {
+                let mut packet = BytesMut::with_capacity(HEADERS_LEN + fragment.len());
+                packet.put_u16(16); // AU-headers-length, in bits
+                packet.put_u16(au_header);
+                packet.put_slice(fragment);
+                packet.freeze()
+            })
+            .collect();
+
+        Ok(packets)
+    }
+
+    fn clone_to(&self) -> Box<dyn Payloader + Send + Sync> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn payload(len: usize) -> Bytes {
+        (0..len).map(|i| i as u8).collect::<Vec<_>>().into()
+    }
+
+    #[test]
+    fn test_au_header() {
+        let au = payload(100);
+        let packets = AacPayloader.payload(1200, &au).unwrap();
+        assert_eq!(packets.len(), 1);
+
+        // 16 bits of AU-headers, then a size of 100 and an index of 0
+        assert_eq!(packets[0][..HEADERS_LEN], [0x00, 0x10, 0x03, 0x20]);
+        assert_eq!(packets[0][HEADERS_LEN..], au[..]);
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        let au = payload(3000);
+        let packets = AacPayloader.payload(1000, &au).unwrap();
+        assert_eq!(packets.len(), 4);
+
+        // Every fragment has the size of the whole access unit
+        let mut data = vec![];
+        for packet in &packets {
+            assert!(packet.len() <= 1000);
+            assert_eq!(packet[..HEADERS_LEN], [0x00, 0x10, 0x5D, 0xC0]);
+            data.extend_from_slice(&packet[HEADERS_LEN..]);
+        }
+        assert_eq!(data, au[..]);
+    }
+
+    #[test]
+    fn test_invalid_payload() {
+        assert!(AacPayloader
+            .payload(1200, &Bytes::new())
+            .unwrap()
+            .is_empty());
+        assert!(AacPayloader
+            .payload(1200, &payload(1 << SIZE_LENGTH))
+            .unwrap()
+            .is_empty());
+        assert!(AacPayloader
+            .payload(HEADERS_LEN, &payload(10))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_fmtp() {
+        // AAC-LC, 48 kHz, stereo
+        assert_eq!(
+            fmtp(&[0x11, 0x90]),
+            "streamtype=5;profile-level-id=1;mode=AAC-hbr;sizelength=13;indexlength=3;\
+             indexdeltalength=3;config=1190"
+        );
+    }
+}
@@ -4,7 +4,7 @@ use anyhow::Result;
 use askama::Template;
 use askama_axum::IntoResponse;
 use axum::{
-    extract::Path,
+    extract::{Path, Query},
     http::StatusCode,
     routing::{get, post},
     Json,
@@ -26,6 +26,12 @@ struct WebrtcTemplate {
     id: u32,
 }
 
+#[derive(serde::Deserialize)]
+struct SdpParams {
+    /// Maximum video frame rate, e.g. `/webrtc/0/sdp?max_fps=10`.
+    max_fps: Option<u32>,
+}
+
 pub(super) struct HttpServerContext {
     #[cfg(feature = "webrtc")]
     pub sdp_tx: mpsc::Sender<super::webrtc::SdpRequest>,
@@ -84,11 +90,14 @@ async fn http_server(ctx: HttpServerContext) -> Result<()> {
             .route(
                 "/webrtc/:id/sdp",
                 post(
-                    |Path(monitor_id): Path<u32>, Json(body): Json<RTCSessionDescription>| async move {
+                    |Path(monitor_id): Path<u32>,
+                     Query(params): Query<SdpParams>,
+                     Json(body): Json<RTCSessionDescription>| async move {
                         let (tx, rx) = tokio::sync::oneshot::channel();
                         let req = SdpRequest {
                             index: monitor_id,
                             sdp: body,
+                            max_framerate: params.max_fps,
                             reply: tx,
                         };
 
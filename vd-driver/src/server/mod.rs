@@ -1,3 +1,4 @@
+pub mod aac;
 pub mod http;
 pub mod rtsp;
 pub mod tcp;
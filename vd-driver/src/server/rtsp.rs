@@ -1,4 +1,4 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use axum::http::StatusCode;
@@ -10,20 +10,54 @@ use sdp::{
 use tokio::{
     io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
+    sync::broadcast,
 };
 use tracing::Instrument;
 use webrtc_util::{Marshal, MarshalSize};
 
-use crate::get_app;
+use crate::{
+    audio::AudioCodecData,
+    clock::get_media_clock,
+    config::VideoCodec,
+    get_app,
+    monitor::{VideoCodecData, FULL_CHROMA_PROFILE_LEVEL_ID},
+    utils::{RecoveryPointGate, Sample, TemporalLayerFilter},
+};
 
 const RTSP_PROTOCOL: &[u8] = b"RTSP/1.0\r\n";
 const HTTP_PROTOCOL: &[u8] = b"HTTP/1.1\r\n";
 
-fn video_sdp() -> String {
-    // m=video 0 RTP/AVP/TCP 96
-    let media_desc = MediaDescription {
+const VIDEO_PAYLOAD_TYPE: u8 = 96;
+const AUDIO_PAYLOAD_TYPE: u8 = 97;
+const VIDEO_CLOCK_RATE: u32 = 90000;
+/// Opus is always timed at 48 kHz, and AAC is encoded at the same rate.
+const AUDIO_CLOCK_RATE: u32 = 48000;
+/// Interval of the RTCP sender reports, as suggested by RFC 3550 for small sessions.
+const SENDER_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Tracks of the session, identified by `trackID=<n>` in their control URL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Track {
+    Video = 0,
+    Audio = 1,
+}
+
+impl Track {
+    fn from_id(id: u32) -> Option<Self> {
+        match id {
+            0 => Some(Track::Video),
+            1 => Some(Track::Audio),
+            _ => None,
+        }
+    }
+}
+
+/// Media section of `track`, without codec.
+fn media_description(media: &str, track: Track) -> MediaDescription {
+    // m=<media> 0 RTP/AVP/TCP <formats>
+    MediaDescription {
         media_name: MediaName {
-            media: "video".into(),
+            media: media.into(),
             port: RangedPort {
                 value: 0,
                 range: None,
@@ -34,7 +68,67 @@ fn video_sdp() -> String {
         },
         ..Default::default()
     }
-    .with_codec(96, "H264".into(), 90000, 0, Default::default());
+    .with_value_attribute("control".into(), format!("trackID={}", track as u32))
+}
+
+/// Format parameters of the video, with the profile of `codec_data` if there is any yet.
+///
+/// In text mode, the video might use a 4:4:4 profile.
+fn video_fmtp(codec_data: Option<&VideoCodecData>, text_mode: bool) -> String {
+    let profile_level_id = codec_data
+        .and_then(|codec_data| codec_data.profile_level_id())
+        .or_else(|| text_mode.then(|| FULL_CHROMA_PROFILE_LEVEL_ID.to_owned()));
+
+    match profile_level_id {
+        Some(profile_level_id) => {
+            format!("packetization-mode=1;profile-level-id={}", profile_level_id)
+        }
+        None => "packetization-mode=1".to_owned(),
+    }
+}
+
+/// Describe the video of the monitor and, if there is one, the audio track.
+fn session_sdp(video_fmtp: String, audio: Option<&AudioCodecData>) -> String {
+    let mut media_descriptions = vec![media_description("video", Track::Video).with_codec(
+        VIDEO_PAYLOAD_TYPE,
+        "H264".into(),
+        VIDEO_CLOCK_RATE,
+        0,
+        video_fmtp,
+    )];
+
+    if let Some(audio) = audio {
+        let (encoding_name, channels, fmtp) = match audio {
+            AudioCodecData::Opus { channels, .. } if *channels > 2 => (
+                "multiopus",
+                *channels,
+                audio.multiopus_fmtp().unwrap_or_default(),
+            ),
+            // Always described as stereo (RFC 7587)
+            AudioCodecData::Opus { channels, .. } => (
+                "opus",
+                2,
+                format!("sprop-stereo={}", (*channels == 2) as u8),
+            ),
+            AudioCodecData::Aac {
+                audio_specific_config,
+                channels,
+                ..
+            } => (
+                super::aac::ENCODING_NAME,
+                *channels,
+                super::aac::fmtp(audio_specific_config),
+            ),
+        };
+
+        media_descriptions.push(media_description("audio", Track::Audio).with_codec(
+            AUDIO_PAYLOAD_TYPE,
+            encoding_name.into(),
+            AUDIO_CLOCK_RATE,
+            channels as u16,
+            fmtp,
+        ));
+    }
 
     let origin = sdp::description::session::Origin {
         username: "-".into(),
@@ -60,7 +154,7 @@ fn video_sdp() -> String {
         origin,
         session_name: "Display 0".into(),
         connection_information: Some(conn_info),
-        media_descriptions: vec![media_desc],
+        media_descriptions,
         ..Default::default()
     };
 
@@ -74,6 +168,147 @@ fn find_and_decode_header(headers: &[httparse::Header], name: &str) -> Option<St
         .and_then(|h| String::from_utf8(h.value.to_vec()).ok())
 }
 
+/// Audio offered to RTSP clients, AAC if it is enabled as many players do not support Opus.
+fn audio_codec_data() -> Option<AudioCodecData> {
+    if crate::config::get_config().audio.aac.is_some() {
+        get_app().aac_codec_data().borrow().clone()
+    } else {
+        get_app().audio_codec_data().borrow().clone()
+    }
+}
+
+fn new_audio_packetizer(codec_data: &AudioCodecData) -> Box<dyn Packetizer + Send + Sync> {
+    let payloader: Box<dyn rtp::packetizer::Payloader + Send + Sync> = match codec_data {
+        AudioCodecData::Opus { .. } => Box::<rtp::codecs::opus::OpusPayloader>::default(),
+        AudioCodecData::Aac { .. } => Box::<super::aac::AacPayloader>::default(),
+    };
+
+    Box::new(rtp::packetizer::new_packetizer(
+        1200,
+        AUDIO_PAYLOAD_TYPE,
+        0, // Value is handled when writing
+        payloader,
+        Box::new(rtp::sequence::new_random_sequencer()),
+        AUDIO_CLOCK_RATE,
+    ))
+}
+
+/// Track of a SETUP request URI, e.g. `rtsp://host/trackID=1`, the video if there is none.
+fn track_param(uri: &str) -> Option<Track> {
+    match uri.split_once("trackID=") {
+        Some((_, id)) => {
+            let end = id.find(|c: char| !c.is_ascii_digit()).unwrap_or(id.len());
+            Track::from_id(id[..end].parse().ok()?)
+        }
+        None => Some(Track::Video),
+    }
+}
+
+/// First channel of the `interleaved=<n>-<m>` parameter of a Transport header.
+fn interleaved_param(transport: &str) -> Option<u8> {
+    transport
+        .split(';')
+        .find_map(|param| param.strip_prefix("interleaved="))
+        .and_then(|channels| channels.split('-').next())
+        .and_then(|channel| channel.trim().parse().ok())
+}
+
+/// Append the header of an interleaved frame of `len` bytes on `channel` to `buf`.
+fn write_interleaved_header(buf: &mut Vec<u8>, channel: u8, len: usize) {
+    let len_be = (len as u16).to_be_bytes();
+    buf.extend_from_slice(&[b'$', channel, len_be[0], len_be[1]]);
+}
+
+/// Append `packet` to `buf`, framed for the interleaved `channel`.
+fn write_interleaved(buf: &mut Vec<u8>, channel: u8, packet: &rtp::packet::Packet) -> Result<()> {
+    let len = packet.marshal_size();
+    write_interleaved_header(buf, channel, len);
+
+    let start = buf.len();
+    buf.resize(start + len, 0);
+    packet.marshal_to(&mut buf[start..])?;
+
+    Ok(())
+}
+
+/// RTCP sender reports of a track, which let clients map its RTP timestamps to wall-clock
+/// time and synchronise it with the other track.
+struct SenderReports {
+    clock_rate: u32,
+    packet_count: u32,
+    octet_count: u32,
+    last_report: Option<Instant>,
+}
+
+impl SenderReports {
+    fn new(clock_rate: u32) -> Self {
+        Self {
+            clock_rate,
+            packet_count: 0,
+            octet_count: 0,
+            last_report: None,
+        }
+    }
+
+    fn on_packet(&mut self, packet: &rtp::packet::Packet) {
+        self.packet_count = self.packet_count.wrapping_add(1);
+        self.octet_count = self.octet_count.wrapping_add(packet.payload.len() as u32);
+    }
+
+    /// Append a sender report to `buf` for the RTCP `channel`, if one is due.
+    fn write_due(&mut self, buf: &mut Vec<u8>, channel: u8) {
+        let now = Instant::now();
+        if let Some(last_report) = self.last_report {
+            if now.duration_since(last_report) < SENDER_REPORT_INTERVAL {
+                return;
+            }
+        }
+        self.last_report = Some(now);
+
+        let clock = get_media_clock();
+        // The packetizers leave the SSRC at 0, there is a single source per track
+        let ssrc = 0u32;
+        write_interleaved_header(buf, channel, 28);
+        // V=2, no reception report, PT=200 (SR), length of 6 words after the first one
+        buf.extend_from_slice(&[0x80, 200, 0, 6]);
+        buf.extend_from_slice(&ssrc.to_be_bytes());
+        buf.extend_from_slice(&clock.ntp_timestamp(now).to_be_bytes());
+        buf.extend_from_slice(&clock.rtp_timestamp(now, self.clock_rate).to_be_bytes());
+        buf.extend_from_slice(&self.packet_count.to_be_bytes());
+        buf.extend_from_slice(&self.octet_count.to_be_bytes());
+    }
+}
+
+/// Receive the next sample of a track, never completing if the track is not playing.
+async fn recv_sample(
+    rx: &mut Option<broadcast::Receiver<Sample>>,
+) -> Result<Sample, broadcast::error::RecvError> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Value of the `max_fps` query parameter of a request URI, e.g. `rtsp://host/?max_fps=10`.
+fn max_framerate_param(uri: &str) -> Option<u32> {
+    let (_, query) = uri.split_once('?')?;
+    query
+        .split('&')
+        .find_map(|param| param.strip_prefix("max_fps=")?.parse().ok())
+}
+
+/// Whether a request URI has the `chroma=444` query parameter, e.g. `rtsp://host/?chroma=444`,
+/// with which clients confirm that they decode the 4:4:4 video of monitors in text mode.
+fn full_chroma_param(uri: &str) -> bool {
+    uri.split_once('?').is_some_and(|(_, query)| {
+        query.split('&').any(|param| {
+            param
+                .strip_prefix("chroma=")
+                .is_some_and(|v| v.starts_with("444"))
+        })
+    })
+}
+
 async fn handle_conn(conn: TcpStream) -> Result<()> {
     conn.set_nodelay(true).ok();
 
@@ -84,97 +319,128 @@ async fn handle_conn(conn: TcpStream) -> Result<()> {
 
     let mut run = true;
     // This indicates that the client has setup the stream.
-    let mut data_tx: Option<tokio::sync::broadcast::Sender<crate::utils::Sample>> = None;
+    let mut setup_monitor: Option<crate::monitor::MonitorHandle> = None;
+    // Audio described to the client, then its interleaved channel once setup.
+    let mut described_audio: Option<AudioCodecData> = None;
+    let mut setup_audio: Option<(AudioCodecData, u8)> = None;
+    let mut video_channel = 0;
+    // Whether the client confirmed that it decodes 4:4:4 video.
+    let mut full_chroma = false;
     // This indicates that the client is playing the stream.
-    let mut data_rx: Option<tokio::sync::broadcast::Receiver<crate::utils::Sample>> = None;
+    let mut video_rx: Option<broadcast::Receiver<Sample>> = None;
+    // Monitor or rendition played, asked to recover when frames are lost.
+    let mut playing_monitor: Option<crate::monitor::MonitorHandle> = None;
+    let mut audio_rx: Option<broadcast::Receiver<Sample>> = None;
+    let mut audio_packetizer: Option<Box<dyn Packetizer + Send + Sync>> = None;
+    let mut video_reports = SenderReports::new(VIDEO_CLOCK_RATE);
+    let mut audio_reports = SenderReports::new(AUDIO_CLOCK_RATE);
+    let mut gate = RecoveryPointGate::default();
+    let mut layers = TemporalLayerFilter::default();
 
-    let clock_rate = 90000;
     let sequencer: Box<dyn Sequencer + Send + Sync> =
         Box::new(rtp::sequence::new_random_sequencer());
     let mut packetizer = rtp::packetizer::new_packetizer(
         1200,
-        96, // Value is handled when writing
-        0,  // Value is handled when writing
+        VIDEO_PAYLOAD_TYPE,
+        0, // Value is handled when writing
         Box::<rtp::codecs::h264::H264Payloader>::default(),
         sequencer.clone(),
-        clock_rate,
+        VIDEO_CLOCK_RATE,
     );
 
-    let stream_start = Instant::now();
+    // Interleaved RTP packets of a sample, written at once.
+    let mut write_buf = Vec::with_capacity(64 * 1024);
 
     while run {
-        let (conn_readable, sample) = match data_rx.as_mut() {
-            Some(data_rx) => {
-                // We are playing
-                // let data_fut = ;
-                // let conn_fut = ;
-
-                // tokio::select! {
-                //     sample = data_rx.recv() => {
-                //         let sample = sample.ok();
-                //         (false, sample)
-                //     }
-                //     _ = conn.get_mut().readable() => {
-                //         (true, None)
-                //     }
-                // }
-
-                // // futures::pin_mut!(data_fut);
-                // // futures::pin_mut!(conn_fut);
-
-                // // let s = futures::future::select(data_fut, conn_fut).await;
-                // // match s {
-                // //     futures::future::Either::Left((sample, _)) => {
-                // //         let sample = sample?;
-                // //         (false, Some(sample))
-                // //     }
-                // //     futures::future::Either::Right((readable_res, _)) => {
-                // //         readable_res?;
-                // //         (true, None)
-                // //     }
-                // // }
-
-                let sample = data_rx.recv().await.ok();
-                (false, sample)
-            }
-            None => {
-                // We are not playing
-                conn.get_ref().readable().await?;
-                (true, None)
+        let (conn_readable, sample) = if video_rx.is_some() || audio_rx.is_some() {
+            // We are playing, requests such as PAUSE, TEARDOWN and keep-alives are still read
+            let received = tokio::select! {
+                sample = recv_sample(&mut video_rx) => Some((Track::Video, sample)),
+                sample = recv_sample(&mut audio_rx) => Some((Track::Audio, sample)),
+                readable = conn.get_ref().readable() => {
+                    readable?;
+                    None
+                }
+            };
+            match received {
+                Some((track, sample)) => {
+                    let sample = match sample {
+                        Ok(sample) => Some((track, sample)),
+                        Err(broadcast::error::RecvError::Lagged(_)) => {
+                            if track == Track::Video {
+                                // Frames were lost, drop upper layers and restart from a
+                                // recovery point
+                                layers.congested();
+                                gate = RecoveryPointGate::default();
+                                if let Some(monitor) = playing_monitor.as_ref() {
+                                    monitor.request_recovery();
+                                }
+                            }
+                            None
+                        }
+                        Err(broadcast::error::RecvError::Closed) => {
+                            match track {
+                                Track::Video => video_rx = None,
+                                Track::Audio => audio_rx = None,
+                            }
+                            None
+                        }
+                    };
+                    (false, sample)
+                }
+                None => (true, None),
             }
+        } else {
+            // We are not playing
+            conn.get_ref().readable().await?;
+            (true, None)
         };
 
-        if let Some(sample) = sample {
+        let sample = sample.and_then(|(track, mut sample)| {
+            let pass = track == Track::Audio || (gate.pass(&sample) && layers.pass(&mut sample));
+            pass.then_some((track, sample))
+        });
+        if let Some((Track::Audio, sample)) = sample {
+            if let (Some((_, channel)), Some(packetizer)) = (&setup_audio, &mut audio_packetizer) {
+                let timestamp = get_media_clock().rtp_timestamp(sample.timestamp, AUDIO_CLOCK_RATE);
+                let samples = (sample.duration.as_secs_f64() * AUDIO_CLOCK_RATE as f64).round();
+
+                write_buf.clear();
+                for mut packet in packetizer.packetize(&sample.data, samples as u32)? {
+                    packet.header.timestamp = timestamp;
+                    audio_reports.on_packet(&packet);
+                    write_interleaved(&mut write_buf, *channel, &packet)?;
+                }
+                audio_reports.write_due(&mut write_buf, channel + 1);
+
+                conn.write_all(&write_buf).await?;
+                conn.flush().await?;
+            }
+        } else if let Some((_, sample)) = sample {
             sample.record_end_to_end_latency();
 
-            let timestamp =
-                sample.timestamp.duration_since(stream_start).as_secs_f64() * (clock_rate as f64);
-
-            let data = &sample.data[..];
-            let mut h264 = webrtc_media::io::h264_reader::H264Reader::new(
-                std::io::Cursor::new(data),
-                1024 * 1024,
-            );
-
-            while let Ok(nal) = h264.next_nal() {
-                let samples = (sample.duration.as_secs_f64() * clock_rate as f64) as u32;
-                let packets = packetizer.packetize(&nal.data.freeze(), samples)?;
-
-                for mut packet in packets {
-                    packet.header.timestamp = timestamp as u32;
-
-                    let len = packet.marshal_size();
-                    let len_be = (len as u16).to_be_bytes();
-                    let mut buf = vec![0; len + 4];
-                    buf[0] = b'$';
-                    buf[1] = 0;
-                    buf[2] = len_be[0];
-                    buf[3] = len_be[1];
-                    packet.marshal_to(&mut buf[4..])?;
-                    conn.write_all(&buf).await?;
+            let timestamp = get_media_clock().rtp_timestamp(sample.timestamp, VIDEO_CLOCK_RATE);
+
+            write_buf.clear();
+
+            let nal_count = sample.nal_units.len();
+            for (i, nal) in sample.nal_units.iter().enumerate() {
+                let samples = (sample.duration.as_secs_f64() * VIDEO_CLOCK_RATE as f64) as u32;
+                let packets = packetizer.packetize(nal, samples)?;
+                let packet_count = packets.len();
+
+                for (j, mut packet) in packets.into_iter().enumerate() {
+                    packet.header.timestamp = timestamp;
+                    // Only the last packet of a frame carries the marker bit.
+                    packet.header.marker = i + 1 == nal_count && j + 1 == packet_count;
+
+                    video_reports.on_packet(&packet);
+                    write_interleaved(&mut write_buf, video_channel, &packet)?;
                 }
             }
+            video_reports.write_due(&mut write_buf, video_channel + 1);
 
+            conn.write_all(&write_buf).await?;
             conn.flush().await?;
         }
 
@@ -267,39 +533,121 @@ async fn handle_conn(conn: TcpStream) -> Result<()> {
                         "DESCRIBE" => {
                             tracing::debug!("=> DESCRIBE");
 
-                            response_lines.push("Content-Type: application/sdp".to_string());
-                            response_body = video_sdp().as_bytes().to_vec();
+                            let monitor_id = 0;
+                            let text_mode =
+                                crate::config::get_config().monitor(monitor_id).text_mode;
+                            full_chroma |= req.path.is_some_and(full_chroma_param);
+
+                            if text_mode && !full_chroma {
+                                tracing::error!("Text mode needs chroma=444 in the URI");
+                                status_code = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+                            } else {
+                                described_audio = audio_codec_data();
+                                let video_codec_data = get_app()
+                                    .get_monitor(monitor_id)
+                                    .and_then(|monitor| monitor.codec_data().borrow().clone());
+                                let fmtp = video_fmtp(video_codec_data.as_ref(), text_mode);
+
+                                response_lines.push("Content-Type: application/sdp".to_string());
+                                response_body =
+                                    session_sdp(fmtp, described_audio.as_ref()).into_bytes();
+                            }
                         }
                         "SETUP" => {
                             tracing::debug!("=> SETUP");
 
                             let monitor_id = 0;
 
-                            if let Some(monitor) = get_app().get_monitor(monitor_id) {
-                                // Force TCP mode
-                                response_lines.push(
-                                    "Transport: RTP/AVP/TCP;unicast;interleaved=0-1".to_string(),
-                                );
+                            let track = req.path.map_or(Some(Track::Video), track_param);
+                            // Channels requested by the client, or the default ones of the track
+                            let channel = find_and_decode_header(req.headers, "transport")
+                                .and_then(|transport| interleaved_param(&transport))
+                                .or_else(|| track.map(|track| track as u8 * 2));
+
+                            let monitor_config = crate::config::get_config().monitor(monitor_id);
+                            let codec = monitor_config.codec;
+                            full_chroma |= req.path.is_some_and(full_chroma_param);
+
+                            match (track, channel) {
+                                (Some(Track::Video), _) if codec != VideoCodec::H264 => {
+                                    tracing::error!(?codec, "Codec not supported over RTSP");
+                                    status_code = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+                                }
+                                (Some(Track::Video), _)
+                                    if monitor_config.text_mode && !full_chroma =>
+                                {
+                                    tracing::error!("Text mode needs chroma=444 in the URI");
+                                    status_code = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+                                }
+                                (Some(Track::Video), Some(channel)) => {
+                                    if let Some(monitor) = get_app().get_monitor(monitor_id) {
+                                        setup_monitor = Some(monitor);
+                                        video_channel = channel;
+                                    } else {
+                                        tracing::error!("Monitor {} not found", monitor_id);
+                                        status_code = StatusCode::NOT_FOUND;
+                                    }
+                                }
+                                (Some(Track::Audio), Some(channel)) => {
+                                    let codec_data =
+                                        described_audio.clone().or_else(audio_codec_data);
+                                    if let Some(codec_data) = codec_data {
+                                        audio_packetizer = Some(new_audio_packetizer(&codec_data));
+                                        setup_audio = Some((codec_data, channel));
+                                    } else {
+                                        tracing::error!("No audio to setup");
+                                        status_code = StatusCode::NOT_FOUND;
+                                    }
+                                }
+                                _ => {
+                                    tracing::error!(path = ?req.path, "Unknown track");
+                                    status_code = StatusCode::NOT_FOUND;
+                                }
+                            }
 
-                                data_tx = Some(monitor.encoded_tx.clone());
-                            } else {
-                                tracing::error!("Monitor {} not found", monitor_id);
-                                status_code = StatusCode::NOT_FOUND;
-                            };
+                            if let (StatusCode::OK, Some(channel)) = (status_code, channel) {
+                                // Force TCP mode
+                                response_lines.push(format!(
+                                    "Transport: RTP/AVP/TCP;unicast;interleaved={}-{}",
+                                    channel,
+                                    channel + 1
+                                ));
+                            }
                         }
                         "TEARDOWN" => {
                             tracing::debug!("=> TEARDOWN");
 
-                            data_tx = None;
-                            data_rx = None;
+                            setup_monitor = None;
+                            setup_audio = None;
+                            video_rx = None;
+                            audio_rx = None;
                             run = false;
                         }
                         "PLAY" => {
                             tracing::debug!("=> PLAY");
 
-                            if let Some(data_tx) = data_tx.as_ref() {
-                                data_rx = Some(data_tx.subscribe());
-                            } else {
+                            if let Some(monitor) = setup_monitor.as_ref() {
+                                let monitor =
+                                    monitor.rendition(req.path.and_then(max_framerate_param));
+                                video_rx = Some(monitor.encoded_tx.subscribe());
+                                video_reports = SenderReports::new(VIDEO_CLOCK_RATE);
+                                gate = RecoveryPointGate::default();
+                                layers = TemporalLayerFilter::default();
+                                monitor.request_join();
+                                playing_monitor = Some(monitor);
+                            }
+
+                            if let Some((codec_data, _)) = setup_audio.as_ref() {
+                                audio_rx = Some(match codec_data {
+                                    AudioCodecData::Opus { .. } => {
+                                        get_app().audio_data_tx.subscribe()
+                                    }
+                                    AudioCodecData::Aac { .. } => get_app().aac_data_tx.subscribe(),
+                                });
+                                audio_reports = SenderReports::new(AUDIO_CLOCK_RATE);
+                            }
+
+                            if setup_monitor.is_none() && setup_audio.is_none() {
                                 tracing::error!("Invalid state: PLAY without SETUP");
                                 status_code = StatusCode::BAD_REQUEST;
                             }
@@ -307,7 +655,8 @@ async fn handle_conn(conn: TcpStream) -> Result<()> {
                         "PAUSE" => {
                             tracing::debug!("=> PAUSE");
 
-                            data_rx = None;
+                            video_rx = None;
+                            audio_rx = None;
                         }
                         _ => {}
                     }
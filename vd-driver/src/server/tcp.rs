@@ -1,7 +1,7 @@
 use anyhow::Result;
 use tokio::io::AsyncWriteExt;
 
-use crate::get_app;
+use crate::{get_app, utils::RecoveryPointGate};
 
 async fn tcp_server() -> Result<()> {
     let listener = tokio::net::TcpListener::bind("0.0.0.0:9866").await?;
@@ -15,13 +15,20 @@ async fn tcp_server() -> Result<()> {
             socket.set_nodelay(true).ok();
 
             let mut data_rx = if let Some(monitor) = get_app().get_monitor(0) {
-                monitor.encoded_tx.subscribe()
+                let data_rx = monitor.encoded_tx.subscribe();
+                monitor.request_join();
+                data_rx
             } else {
                 tracing::error!("Monitor 0 not found");
                 return;
             };
 
+            let mut gate = RecoveryPointGate::default();
             while let Ok(sample) = data_rx.recv().await {
+                if !gate.pass(&sample) {
+                    continue;
+                }
+
                 sample.record_end_to_end_latency();
                 
                 let data = &sample.data[..];
@@ -1,31 +1,57 @@
-use std::time::{Duration, Instant};
+use std::{
+    io::IoSlice,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
 
 use anyhow::Result;
+use bytes::{Buf, Bytes, BytesMut};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, BufStream},
     net::TcpStream,
+    sync::broadcast,
 };
 use tracing::{info_span, Instrument};
 
 use crate::{
-    audio::AudioCodecData,
+    audio::{AudioCodecData, UplinkDecoder},
+    clock::get_media_clock,
     get_app,
     monitor::{MonitorHandle, VideoCodecData},
+    utils::{RecoveryPointGate, TemporalLayerFilter},
 };
 
 const TIMEOUT: Duration = Duration::from_secs(10);
 
+/// Interval of the `Timestamp` packets.
+const TIMESTAMP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Timestamp of packets captured at `instant`, in milliseconds of media time.
+///
+/// Audio and video share the media clock, so their timestamps can be compared directly.
+fn media_timestamp(instant: Instant) -> u64 {
+    get_media_clock().media_time(instant).as_millis() as u64
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 enum PacketType {
     /// `[i64 ts][data]`
     Video = 0,
     /// `[i64 ts][data]`
     Audio = 1,
-    /// `[i64 ts]`
+    /// `[i64 ts][i64 wall_clock]`, the current media time and the matching Unix time,
+    /// both in milliseconds, sent on the video channel.
     Timestamp = 2,
     /// `[i32 width][i32 height][u32 len][data][u32 len][data]...`
+    ///
+    /// For H.264 the items are the SPS, the PPS and the `chroma_format_idc` as a single byte.
+    /// For other codecs the first item is empty, followed by the codec type as a `u32`
+    /// (`VideoCodecType`) and its parameters.
     Configure = 3,
     /// `[u8 channels][i32 sample_rate][u32 len][data][u32 len][data]...`
+    ///
+    /// For Opus the items are the identification header, which uses channel mapping
+    /// family 1 with more than two channels, then the codec delay and the seek pre-roll
+    /// in nanoseconds as little endian `u64`s, as expected by Android's `MediaCodec`.
     AudioConfigure = 4,
     /// `[i32 x][i32 y][u32 visible]`
     CursorPosition = 5,
@@ -33,6 +59,65 @@ enum PacketType {
     CursorImage = 6,
 }
 
+/// Codecs other than H.264 in `Configure` packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum VideoCodecType {
+    /// Lossless tiles (see `crate::tile`), with the tile size as a `u32` parameter.
+    /// `Video` packets contain one frame each, or one band of a frame with the
+    /// end-of-frame flag set on the last band when the monitor has `slices` set.
+    Raw = 1,
+}
+
+/// Packets sent by the client, `[u32 type][u32 len][data]` like the ones sent by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ClientPacketType {
+    /// `[i64 ts]`, timestamp of the last frame decoded correctly.
+    LossReport = 0,
+    /// `[u32 fps]`, maximum video frame rate, 0 for no limit.
+    MaxFramerate = 1,
+    /// `[u8 channels]`, sent on the microphone channel before any audio.
+    /// Audio is Opus at 48 kHz, with one or two channels.
+    MicrophoneConfigure = 2,
+    /// `[data]`, one Opus packet. Empty packets mark a lost packet, which is concealed.
+    MicrophoneAudio = 3,
+}
+
+impl ClientPacketType {
+    fn from_u32(ty: u32) -> Option<Self> {
+        match ty {
+            0 => Some(ClientPacketType::LossReport),
+            1 => Some(ClientPacketType::MaxFramerate),
+            2 => Some(ClientPacketType::MicrophoneConfigure),
+            3 => Some(ClientPacketType::MicrophoneAudio),
+            _ => None,
+        }
+    }
+}
+
+/// Larger than any Opus packet without padding, which lasts at most 120 ms with up to
+/// 48 frames of at most 1275 bytes (RFC 6716).
+const MAX_CLIENT_PACKET_SIZE: usize = 64 * 1024;
+
+/// Take a complete client packet out of `buf`, if there is one.
+fn parse_client_packet(buf: &mut BytesMut) -> Result<Option<(u32, Bytes)>> {
+    if buf.len() < 8 {
+        return Ok(None);
+    }
+
+    let ty = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+    let len = u32::from_be_bytes(buf[4..8].try_into().unwrap()) as usize;
+    if len > MAX_CLIENT_PACKET_SIZE {
+        anyhow::bail!("Client packet too large ({} bytes)", len);
+    }
+
+    if buf.len() < 8 + len {
+        return Ok(None);
+    }
+
+    buf.advance(8);
+    Ok(Some((ty, buf.split_to(len).freeze())))
+}
+
 #[derive(Debug)]
 struct VdStream {
     inner: BufStream<TcpStream>,
@@ -44,11 +129,33 @@ impl VdStream {
 
         tracing::trace!("Writing {:?} ({} bytes)", ty, total_len);
 
+        let mut header = [0u8; 8];
+        header[..4].copy_from_slice(&(ty as u32).to_be_bytes());
+        header[4..].copy_from_slice(&(total_len as u32).to_be_bytes());
+
+        let mut parts: Vec<&[u8]> = Vec::with_capacity(data.len() + 1);
+        parts.push(&header);
+        parts.extend(data.iter().copied().filter(|d| !d.is_empty()));
+
+        // Vectored writes are copied into the write buffer if they fit, and passed to
+        // the socket at once otherwise, so large samples are not copied.
         let task = async move {
-            self.inner.write_u32(ty as u32).await?;
-            self.inner.write_u32(total_len as u32).await?;
-            for d in data {
-                self.inner.write_all(d).await?;
+            let mut first = 0;
+            while first < parts.len() {
+                let slices: Vec<IoSlice> = parts[first..].iter().map(|p| IoSlice::new(p)).collect();
+                let mut written = self.inner.write_vectored(&slices).await?;
+                if written == 0 {
+                    return Err(std::io::ErrorKind::WriteZero.into());
+                }
+
+                // Skip what was written, which may end in the middle of a part
+                while first < parts.len() && written >= parts[first].len() {
+                    written -= parts[first].len();
+                    first += 1;
+                }
+                if written > 0 {
+                    parts[first] = &parts[first][written..];
+                }
             }
 
             std::io::Result::Ok(())
@@ -59,9 +166,20 @@ impl VdStream {
         Ok(())
     }
 
-    async fn write_timestamp(&mut self, timestamp: u64) -> Result<()> {
-        self.write_packet(PacketType::Timestamp, &[&(timestamp as i64).to_be_bytes()])
-            .await
+    async fn write_timestamp(&mut self, instant: Instant) -> Result<()> {
+        let wall_clock = get_media_clock()
+            .wall_clock(instant)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        self.write_packet(
+            PacketType::Timestamp,
+            &[
+                &(media_timestamp(instant) as i64).to_be_bytes(),
+                &wall_clock.to_be_bytes(),
+            ],
+        )
+        .await
     }
 
     async fn write_video(&mut self, timestamp: u64, data: &[u8]) -> Result<()> {
@@ -86,50 +204,77 @@ impl VdStream {
         height: u32,
         data: &VideoCodecData,
     ) -> Result<()> {
-        let mut pkts = vec![
-            (width as i32).to_be_bytes().to_vec(),
-            (height as i32).to_be_bytes().to_vec(),
-        ];
+        let width = (width as i32).to_be_bytes();
+        let height = (height as i32).to_be_bytes();
 
         match data {
-            VideoCodecData::H264 { sps, pps } => {
-                pkts.push((sps.len() as u32).to_be_bytes().to_vec());
-                pkts.push(sps.to_vec());
-                pkts.push((pps.len() as u32).to_be_bytes().to_vec());
-                pkts.push(pps.to_vec());
+            VideoCodecData::H264 {
+                sps,
+                pps,
+                chroma_format,
+            } => {
+                self.write_packet(
+                    PacketType::Configure,
+                    &[
+                        &width,
+                        &height,
+                        &(sps.len() as u32).to_be_bytes(),
+                        sps,
+                        &(pps.len() as u32).to_be_bytes(),
+                        pps,
+                        &1u32.to_be_bytes(),
+                        &[chroma_format.idc()],
+                    ],
+                )
+                .await
+            }
+            VideoCodecData::Raw { tile_size } => {
+                self.write_packet(
+                    PacketType::Configure,
+                    &[
+                        &width,
+                        &height,
+                        &0u32.to_be_bytes(),
+                        &4u32.to_be_bytes(),
+                        &(VideoCodecType::Raw as u32).to_be_bytes(),
+                        &4u32.to_be_bytes(),
+                        &tile_size.to_be_bytes(),
+                    ],
+                )
+                .await
             }
         }
-
-        let pkts_ref: Vec<&[u8]> = pkts.iter().map(|v| v.as_slice()).collect();
-        self.write_packet(PacketType::Configure, &pkts_ref).await
     }
 
-    async fn write_audio_configure(
-        &mut self,
-        channels: u8,
-        sample_rate: u32,
-        data: &AudioCodecData,
-    ) -> Result<()> {
-        let mut pkts = vec![
-            channels.to_be_bytes().to_vec(),
-            (sample_rate as i32).to_be_bytes().to_vec(),
-        ];
+    async fn write_audio_configure(&mut self, data: &AudioCodecData) -> Result<()> {
+        let channels = data.channels().to_be_bytes();
+        let sample_rate = (data.sample_rate() as i32).to_be_bytes();
 
         match data {
-            AudioCodecData::Opus { ident_header } => {
-                pkts.push((ident_header.len() as u32).to_be_bytes().to_vec());
-                pkts.push(ident_header.to_vec());
-                pkts.push(8u32.to_be_bytes().to_vec());
-                pkts.push(vec![0, 0, 0, 0, 0, 0, 0, 0]);
-                pkts.push(8u32.to_be_bytes().to_vec());
-                pkts.push(vec![0, 0, 0, 0, 0, 0, 0, 0]);
+            AudioCodecData::Opus {
+                ident_header,
+                pre_skip,
+                ..
+            } => {
+                let codec_delay = *pre_skip as u64 * 1_000_000_000 / 48000;
+                self.write_packet(
+                    PacketType::AudioConfigure,
+                    &[
+                        &channels,
+                        &sample_rate,
+                        &(ident_header.len() as u32).to_be_bytes(),
+                        ident_header,
+                        &8u32.to_be_bytes(),
+                        &codec_delay.to_le_bytes(),
+                        &8u32.to_be_bytes(),
+                        &[0; 8],
+                    ],
+                )
+                .await
             }
+            // Clients receive the Opus stream, AAC is only encoded for RTSP
+            AudioCodecData::Aac { .. } => anyhow::bail!("AAC is not sent to custom clients"),
         }
-
-        let pkts_ref: Vec<&[u8]> = pkts.iter().map(|v| v.as_slice()).collect();
-
-        self.write_packet(PacketType::AudioConfigure, &pkts_ref)
-            .await
     }
 
     async fn write_cursor_position(&mut self, x: i32, y: i32, visible: bool) -> Result<()> {
@@ -155,24 +300,27 @@ impl VdStream {
     }
 }
 
-async fn handle_video(monitor: MonitorHandle, mut stream: VdStream) -> Result<()> {
+async fn handle_video(base: MonitorHandle, mut stream: VdStream) -> Result<()> {
     tracing::info!("Starting video handler");
 
+    // Replaced by a rendition when the client limits the frame rate
+    let mut monitor = base.clone();
     let mut video_data_rx = monitor.encoded_tx.subscribe();
     let mut video_codec_data_rx = monitor.codec_data();
+    let mut gate = RecoveryPointGate::default();
+    let mut layers = TemporalLayerFilter::default();
 
     // == Timing
 
-    let stream_start = Instant::now();
-
     {
         // Send initial timestamp packet
-        let now = stream_start.elapsed().as_millis() as u64;
-        stream.write_timestamp(now).await?;
+        stream.write_timestamp(Instant::now()).await?;
         stream.flush().await?;
     }
 
-    let mut timestamp_interval = tokio::time::interval(Duration::from_secs(10));
+    let mut timestamp_interval = tokio::time::interval(TIMESTAMP_INTERVAL);
+
+    let mut read_buf = BytesMut::with_capacity(MAX_CLIENT_PACKET_SIZE);
 
     let video_codec_data = loop {
         tracing::info!("Waiting for codec data");
@@ -190,6 +338,7 @@ async fn handle_video(monitor: MonitorHandle, mut stream: VdStream) -> Result<()
     stream
         .write_configure(monitor.width(), monitor.height(), &video_codec_data)
         .await?;
+    monitor.request_join();
 
     loop {
         tokio::select! {
@@ -210,24 +359,70 @@ async fn handle_video(monitor: MonitorHandle, mut stream: VdStream) -> Result<()
                     .await?;
             }
             _ = timestamp_interval.tick() => {
-                let now = stream_start.elapsed().as_millis() as u64;
-                stream.write_timestamp(now).await?;
+                stream.write_timestamp(Instant::now()).await?;
             }
             sample = video_data_rx.recv() => {
-                let sample = if let Ok(sample) = sample {
-                    sample
-                } else {
-                    break;
+                let mut sample = match sample {
+                    Ok(sample) => sample,
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        // Frames were lost, drop upper layers and restart from a recovery point
+                        tracing::debug!("Client fell behind");
+                        layers.congested();
+                        gate = RecoveryPointGate::default();
+                        monitor.request_recovery();
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
                 };
 
-                sample.record_end_to_end_latency();
+                if !gate.pass(&sample) || !layers.pass(&mut sample) {
+                    continue;
+                }
 
-                stream
-                    .write_video(
-                        sample.timestamp.duration_since(stream_start).as_millis() as u64,
-                        &sample.data,
-                    )
-                    .await?;
+                if sample.end_of_frame {
+                    sample.record_end_to_end_latency();
+                }
+                stream.write_video(media_timestamp(sample.timestamp), &sample.data).await?;
+            }
+            // Cancel safe, nothing is lost if another branch completes first
+            res = stream.inner.read_buf(&mut read_buf) => {
+                if res? == 0 {
+                    tracing::info!("Connection closed by client");
+                    break;
+                }
+
+                while let Some((ty, data)) = parse_client_packet(&mut read_buf)? {
+                    match ClientPacketType::from_u32(ty) {
+                        Some(ClientPacketType::LossReport) if data.len() >= 8 => {
+                            let ts = i64::from_be_bytes(data[..8].try_into().unwrap());
+                            tracing::debug!(ts, "Client reported frame loss");
+                            let last_decoded = Duration::from_millis(ts.max(0) as u64);
+                            monitor.report_loss(get_media_clock().instant(last_decoded));
+                        }
+                        Some(ClientPacketType::LossReport) => {
+                            tracing::debug!(len = data.len(), "Client reported frame loss");
+                            monitor.request_recovery();
+                        }
+                        Some(ClientPacketType::MaxFramerate) if data.len() >= 4 => {
+                            let max_framerate = u32::from_be_bytes(data[..4].try_into().unwrap());
+                            tracing::info!(max_framerate, "Client set maximum frame rate");
+                            monitor = base.rendition(Some(max_framerate));
+                            video_data_rx = monitor.encoded_tx.subscribe();
+                            video_codec_data_rx = monitor.codec_data();
+                            // Configure the client for the rendition once it has codec data
+                            video_codec_data_rx.mark_changed();
+                            gate = RecoveryPointGate::default();
+                            layers = TemporalLayerFilter::default();
+                            monitor.request_join();
+                        }
+                        Some(ClientPacketType::MaxFramerate) => {
+                            tracing::warn!(len = data.len(), "Invalid frame rate packet");
+                        }
+                        Some(ty) => tracing::warn!(?ty, "Unexpected client packet"),
+                        None => tracing::warn!(ty, "Unknown client packet"),
+                    }
+                }
+                continue;
             }
         }
         stream.flush().await?;
@@ -255,11 +450,7 @@ async fn handle_audio(mut stream: VdStream) -> Result<()> {
         }
     };
     tracing::info!("Obtained codec data");
-    stream
-        .write_audio_configure(2, 48000, &audio_codec_data)
-        .await?;
-
-    let stream_start = Instant::now();
+    stream.write_audio_configure(&audio_codec_data).await?;
 
     loop {
         tokio::select! {
@@ -275,9 +466,7 @@ async fn handle_audio(mut stream: VdStream) -> Result<()> {
                     }
                 };
 
-                stream
-                    .write_audio_configure(2, 48000, &codec_data)
-                    .await?;
+                stream.write_audio_configure(&codec_data).await?;
             }
             sample = audio_data_rx.recv() => {
                 let sample = if let Ok(sample) = sample {
@@ -286,10 +475,7 @@ async fn handle_audio(mut stream: VdStream) -> Result<()> {
                     break;
                 };
 
-                stream.write_audio(
-                    sample.timestamp.duration_since(stream_start).as_millis() as u64,
-                    &sample.data
-                ).await?;
+                stream.write_audio(media_timestamp(sample.timestamp), &sample.data).await?;
             }
         }
         stream.flush().await?;
@@ -335,6 +521,55 @@ async fn handle_control(monitor: MonitorHandle, mut stream: VdStream) -> Result<
     }
 }
 
+async fn handle_microphone(mut stream: VdStream) -> Result<()> {
+    tracing::info!("Starting microphone handler");
+
+    let frames_tx = match get_app().uplink_tx.clone() {
+        Some(tx) => tx,
+        None => anyhow::bail!("Microphone audio is disabled"),
+    };
+
+    let mut decoder: Option<UplinkDecoder> = None;
+    let mut read_buf = BytesMut::with_capacity(MAX_CLIENT_PACKET_SIZE);
+
+    loop {
+        if stream.inner.read_buf(&mut read_buf).await? == 0 {
+            tracing::info!("Connection closed by client");
+            break;
+        }
+
+        while let Some((ty, data)) = parse_client_packet(&mut read_buf)? {
+            match ClientPacketType::from_u32(ty) {
+                Some(ClientPacketType::MicrophoneConfigure) if !data.is_empty() => {
+                    tracing::info!(channels = data[0], "Client configured microphone");
+                    match decoder.as_mut() {
+                        Some(decoder) => decoder.reconfigure(data[0])?,
+                        None => decoder = Some(UplinkDecoder::new(data[0], frames_tx.clone())?),
+                    }
+                }
+                Some(ClientPacketType::MicrophoneAudio) => {
+                    let decoder = match decoder.as_mut() {
+                        Some(decoder) => decoder,
+                        None => anyhow::bail!("Microphone audio before configuration"),
+                    };
+                    let packet = if data.is_empty() {
+                        None
+                    } else {
+                        Some(&data[..])
+                    };
+                    if let Err(e) = decoder.push(packet) {
+                        tracing::warn!(?e, "Failed to decode microphone audio");
+                    }
+                }
+                Some(ty) => tracing::warn!(?ty, "Unexpected client packet"),
+                None => tracing::warn!(ty, "Unknown client packet"),
+            }
+        }
+    }
+
+    Ok(())
+}
+
 async fn handle(socket: TcpStream) -> Result<()> {
     socket.set_nodelay(true).ok();
 
@@ -360,6 +595,11 @@ async fn handle(socket: TcpStream) -> Result<()> {
                 .instrument(info_span!("control"))
                 .await?
         }
+        3 => {
+            handle_microphone(stream)
+                .instrument(info_span!("microphone"))
+                .await?
+        }
         _ => anyhow::bail!("Invalid channel type"),
     }
 
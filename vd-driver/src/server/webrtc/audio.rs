@@ -1,40 +1,154 @@
 use std::sync::Arc;
 
-use tokio::sync::broadcast;
-use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use anyhow::Result;
+use crossbeam::channel;
+use tokio::sync::{broadcast, watch};
+use webrtc::{
+    api::media_engine::MIME_TYPE_OPUS,
+    rtp::{self, packetizer::Packetizer},
+    rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters},
+    track::{
+        track_local::{track_local_static_rtp::TrackLocalStaticRTP, TrackLocalWriter},
+        track_remote::TrackRemote,
+    },
+};
 
-use crate::utils::Sample;
+use crate::{
+    audio::{AudioCodecData, UplinkDecoder, UplinkFrames},
+    clock::get_media_clock,
+    utils::Sample,
+};
 
+/// Opus with more than two channels, as supported by Chromium.
+const MIME_TYPE_MULTIOPUS: &str = "audio/multiopus";
+/// Payload type registered for `MIME_TYPE_MULTIOPUS`, answers use the one of the offer.
+const MULTIOPUS_PAYLOAD_TYPE: u8 = 112;
+const CLOCK_RATE: u32 = 48000;
+/// Most lost packets concealed at once, longer gaps are skipped.
+const MAX_CONCEALED_PACKETS: u16 = 5;
+
+/// Codec to register for surround audio, which is not part of the default codecs.
+pub fn surround_codec(codec_data: Option<&AudioCodecData>) -> Option<RTCRtpCodecParameters> {
+    let codec_data = codec_data?;
+    let fmtp = codec_data.multiopus_fmtp()?;
+
+    Some(RTCRtpCodecParameters {
+        capability: RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_MULTIOPUS.to_owned(),
+            clock_rate: CLOCK_RATE,
+            channels: codec_data.channels() as u16,
+            sdp_fmtp_line: fmtp,
+            rtcp_feedback: vec![],
+        },
+        payload_type: MULTIOPUS_PAYLOAD_TYPE,
+        ..Default::default()
+    })
+}
+
+/// Capability of the audio track for the current codec data.
+pub fn track_capability(codec_data: Option<&AudioCodecData>) -> RTCRtpCodecCapability {
+    match surround_codec(codec_data) {
+        Some(codec) => codec.capability,
+        None => RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_OPUS.to_owned(),
+            ..Default::default()
+        },
+    }
+}
+
+/// Send the encoded audio with `channels` channels to `track`.
+///
+/// The track ends when the channel count changes, as that requires another codec.
 pub async fn audio_sender(
-    track: Arc<TrackLocalStaticSample>,
+    track: Arc<TrackLocalStaticRTP>,
     mut audio_data_rx: broadcast::Receiver<Sample>,
+    mut audio_codec_data_rx: watch::Receiver<Option<AudioCodecData>>,
+    channels: u8,
 ) {
+    let mut packetizer = rtp::packetizer::new_packetizer(
+        1200,
+        0, // Value is handled by the track
+        0, // Value is handled by the track
+        Box::<rtp::codecs::opus::OpusPayloader>::default(),
+        Box::new(rtp::sequence::new_random_sequencer()),
+        CLOCK_RATE,
+    );
+
     loop {
-        match audio_data_rx.recv().await {
-            Ok(sample) => {
-                let data = sample.data.to_vec();
-
-                let res = track
-                    .write_sample(&webrtc::media::Sample {
-                        data: data.into(),
-                        // not really used in the stack
-                        // timestamp: sample.timestamp,
-                        duration: sample.duration,
-                        ..Default::default()
-                    })
-                    .await;
-
-                if let Err(e) = res {
-                    tracing::warn!(?e, "Failed to write video sample");
+        tokio::select! {
+            changed = audio_codec_data_rx.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+
+                let new_channels = audio_codec_data_rx.borrow().as_ref().map(|d| d.channels());
+                if new_channels.is_some_and(|c| c != channels) {
+                    tracing::warn!(?new_channels, "Audio channel count changed, ending the track");
                     break;
                 }
             }
-            Err(broadcast::error::RecvError::Lagged(_)) => {
-                // Ignore lagged frames
+            sample = audio_data_rx.recv() => match sample {
+                Ok(sample) => {
+                    let samples =
+                        (sample.duration.as_secs_f64() * CLOCK_RATE as f64).round() as u32;
+                    let packets = match packetizer.packetize(&sample.data, samples) {
+                        Ok(packets) => packets,
+                        Err(e) => {
+                            tracing::warn!(?e, "Failed to packetize audio sample");
+                            continue;
+                        }
+                    };
+
+                    // Timed from the capture like the video, though sender reports add the
+                    // encoding latency (see `webrtc_task`)
+                    let timestamp = get_media_clock().rtp_timestamp(sample.timestamp, CLOCK_RATE);
+                    for mut packet in packets {
+                        packet.header.timestamp = timestamp;
+                        if let Err(e) = track.write_rtp(&packet).await {
+                            tracing::warn!(?e, "Failed to write audio sample");
+                            return;
+                        }
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    // Ignore lagged frames
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    break;
+                }
             }
-            Err(broadcast::error::RecvError::Closed) => {
-                break;
+        }
+    }
+}
+
+/// Decode the microphone audio of the client from `track`.
+pub async fn uplink_receiver(
+    track: Arc<TrackRemote>,
+    frames_tx: channel::Sender<UplinkFrames>,
+) -> Result<()> {
+    // Browsers send mono, and stereo packets are downmixed by the decoder
+    let mut decoder = UplinkDecoder::new(1, frames_tx)?;
+    let mut last_sequence_number: Option<u16> = None;
+
+    loop {
+        let (packet, _) = track.read_rtp().await?;
+
+        let sequence_number = packet.header.sequence_number;
+        if let Some(last) = last_sequence_number {
+            let lost = sequence_number.wrapping_sub(last).wrapping_sub(1);
+            if lost >= 0x8000 {
+                // Late or duplicate packet, its time was already concealed
+                continue;
+            }
+
+            for _ in 0..lost.min(MAX_CONCEALED_PACKETS) {
+                decoder.push(None)?;
             }
         }
+        last_sequence_number = Some(sequence_number);
+
+        if let Err(e) = decoder.push(Some(&packet.payload)) {
+            tracing::warn!(?e, "Failed to decode microphone audio");
+        }
     }
 }
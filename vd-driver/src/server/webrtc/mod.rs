@@ -12,29 +12,66 @@ use webrtc::{
         peer_connection_state::RTCPeerConnectionState,
         sdp::session_description::RTCSessionDescription,
     },
-    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
-    track::track_local::{track_local_static_sample::TrackLocalStaticSample, TrackLocal},
+    rtcp::payload_feedbacks::{
+        full_intra_request::FullIntraRequest, picture_loss_indication::PictureLossIndication,
+    },
+    rtp_transceiver::rtp_codec::RTPCodecType,
+    track::track_local::{track_local_static_rtp::TrackLocalStaticRTP, TrackLocal},
 };
 
+use crate::config::VideoCodec;
+
 mod audio;
 mod video;
 
 pub struct SdpRequest {
     pub index: u32,
     pub sdp: RTCSessionDescription,
+    /// Maximum video frame rate requested by the client.
+    pub max_framerate: Option<u32>,
     pub reply: oneshot::Sender<RTCSessionDescription>,
 }
 
-async fn webrtc_task(index: u32, sdp: RTCSessionDescription) -> Result<RTCSessionDescription> {
+async fn webrtc_task(
+    index: u32,
+    sdp: RTCSessionDescription,
+    max_framerate: Option<u32>,
+) -> Result<RTCSessionDescription> {
     let monitor = if let Some(m) = crate::get_app().get_monitor(index) {
-        m.clone()
+        m.rendition(max_framerate)
     } else {
         return Err(anyhow::anyhow!("Monitor with index {} not found", index));
     };
 
+    let monitor_config = crate::config::get_config().monitor(index);
+    if monitor_config.codec != VideoCodec::H264 {
+        anyhow::bail!(
+            "Codec {:?} of monitor {} not supported over WebRTC",
+            monitor_config.codec,
+            index
+        );
+    }
+    // Text mode might encode 4:4:4, which most browsers cannot decode
+    let full_chroma = monitor_config.text_mode;
+    if full_chroma && !video::offers_full_chroma(&sdp.sdp) {
+        anyhow::bail!(
+            "Monitor {} is in text mode, but the client does not offer a 4:4:4 profile",
+            index
+        );
+    }
+
+    // The audio track is negotiated for the current channel count
+    let audio_codec_data = crate::get_app().audio_codec_data().borrow().clone();
+
     let api = {
         let mut m = MediaEngine::default();
         m.register_default_codecs()?;
+        if full_chroma {
+            m.register_codec(video::full_chroma_codec(), RTPCodecType::Video)?;
+        }
+        if let Some(codec) = audio::surround_codec(audio_codec_data.as_ref()) {
+            m.register_codec(codec, RTPCodecType::Audio)?;
+        }
 
         // Create a InterceptorRegistry. This is the user configurable RTP/RTCP Pipeline.
         // This provides NACKs, RTCP Reports and other features. If you use `webrtc.NewPeerConnection`
@@ -43,6 +80,11 @@ async fn webrtc_task(index: u32, sdp: RTCSessionDescription) -> Result<RTCSessio
         let mut registry = Registry::new();
 
         // Use the default set of Interceptors
+        //
+        // Their sender reports pair the RTP timestamp of the last packet sent with the
+        // wall-clock time it was sent at, while our timestamps are capture times. Clients
+        // thus see audio and video offset by the difference of their encoding latencies,
+        // unlike RTSP whose sender reports are taken from the media clock.
         registry =
             webrtc::api::interceptor_registry::register_default_interceptors(registry, &mut m)?;
 
@@ -64,12 +106,8 @@ async fn webrtc_task(index: u32, sdp: RTCSessionDescription) -> Result<RTCSessio
 
     let done = Arc::new(tokio::sync::Notify::new());
 
-    let video_track = Arc::new(TrackLocalStaticSample::new(
-        RTCRtpCodecCapability {
-            mime_type: webrtc::api::media_engine::MIME_TYPE_H264.to_owned(),
-            clock_rate: 90000,
-            ..Default::default()
-        },
+    let video_track = Arc::new(TrackLocalStaticRTP::new(
+        video::track_capability(full_chroma),
         "video".to_owned(),
         "webrtc-rs".to_owned(),
     ));
@@ -77,11 +115,13 @@ async fn webrtc_task(index: u32, sdp: RTCSessionDescription) -> Result<RTCSessio
     // Feed the video track with data from the encoding task.
     let vt = video_track.clone();
     let video_data_rx = monitor.encoded_tx.subscribe();
+    monitor.request_join();
+    let monitor_ = monitor.clone();
     let done_ = done.clone();
     tokio::spawn(
         async move {
             tokio::select! {
-                _ = video::video_sender(vt, video_data_rx) => {}
+                _ = video::video_sender(vt, video_data_rx, monitor_) => {}
                 _ = done_.notified() => {}
             }
             tracing::info!("Video track done");
@@ -90,22 +130,22 @@ async fn webrtc_task(index: u32, sdp: RTCSessionDescription) -> Result<RTCSessio
     );
 
     let audio_data_rx = crate::get_app().audio_data_tx.subscribe();
-    let audio_track = Arc::new(TrackLocalStaticSample::new(
-        RTCRtpCodecCapability {
-            mime_type: webrtc::api::media_engine::MIME_TYPE_OPUS.to_owned(),
-            ..Default::default()
-        },
+    let audio_track = Arc::new(TrackLocalStaticRTP::new(
+        audio::track_capability(audio_codec_data.as_ref()),
         "audio".to_owned(),
         "webrtc-rs".to_owned(),
     ));
 
     // Feed the audio track with data from the encoding task.
     let at = audio_track.clone();
+    let audio_codec_data_rx = crate::get_app().audio_codec_data();
+    let channels = audio_codec_data.as_ref().map_or(2, |d| d.channels());
     let done_ = done.clone();
     tokio::spawn(
         async move {
+            let sender = audio::audio_sender(at, audio_data_rx, audio_codec_data_rx, channels);
             tokio::select! {
-                _ = audio::audio_sender(at, audio_data_rx) => {}
+                _ = sender => {}
                 _ = done_.notified() => {}
             }
             tracing::info!("Audio track done");
@@ -119,9 +159,20 @@ async fn webrtc_task(index: u32, sdp: RTCSessionDescription) -> Result<RTCSessio
             .add_track(Arc::clone(&video_track) as Arc<dyn TrackLocal + Send + Sync>)
             .await?;
 
+        // Recover from lost frames reported by the client. NACKs are answered with
+        // retransmissions by the interceptors, and browsers send a PLI when that fails.
+        let monitor_ = monitor.clone();
         tokio::spawn(async move {
-            let mut rtcp_buf = vec![0u8; 1500];
-            while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
+            while let Ok((packets, _)) = rtp_sender.read_rtcp().await {
+                let lost = packets.iter().any(|packet| {
+                    let packet = packet.as_any();
+                    packet.is::<PictureLossIndication>() || packet.is::<FullIntraRequest>()
+                });
+
+                if lost {
+                    monitor_.request_recovery();
+                }
+            }
             Result::<()>::Ok(())
         });
     }
@@ -139,6 +190,34 @@ async fn webrtc_task(index: u32, sdp: RTCSessionDescription) -> Result<RTCSessio
         });
     }
 
+    // Microphone, sent by the client on the audio transceiver
+    if let Some(frames_tx) = crate::get_app().uplink_tx.clone() {
+        let done_ = done.clone();
+        let span_ = span.clone();
+        peer_connection.on_track(Box::new(move |track, _, _| {
+            if track.kind() == RTPCodecType::Audio {
+                let frames_tx = frames_tx.clone();
+                let done_ = done_.clone();
+                tokio::spawn(
+                    async move {
+                        tracing::info!("Receiving microphone audio");
+                        tokio::select! {
+                            res = audio::uplink_receiver(track, frames_tx) => {
+                                if let Err(e) = res {
+                                    tracing::info!(?e, "Microphone track ended");
+                                }
+                            }
+                            _ = done_.notified() => {}
+                        }
+                    }
+                    .instrument(span_.clone()),
+                );
+            }
+
+            Box::pin(async {})
+        }));
+    }
+
     // Cursor
     let control_data_channel = peer_connection
         .create_data_channel(
@@ -268,7 +347,7 @@ async fn webrtc_task(index: u32, sdp: RTCSessionDescription) -> Result<RTCSessio
 
 async fn webrtc_server(mut sdp_rx: mpsc::Receiver<SdpRequest>) {
     while let Some(req) = sdp_rx.recv().await {
-        match webrtc_task(req.index, req.sdp).await {
+        match webrtc_task(req.index, req.sdp, req.max_framerate).await {
             Ok(sdp) => {
                 req.reply.send(sdp).ok();
             }
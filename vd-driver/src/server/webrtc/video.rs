@@ -1,42 +1,132 @@
 use std::sync::Arc;
 
 use tokio::sync::broadcast;
-use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
-use webrtc_media::io::h264_reader::H264Reader;
+use webrtc::{
+    api::media_engine::MIME_TYPE_H264,
+    rtp::{self, packetizer::Packetizer},
+    rtp_transceiver::{
+        rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters},
+        RTCPFeedback,
+    },
+    track::track_local::{track_local_static_rtp::TrackLocalStaticRTP, TrackLocalWriter},
+};
 
-use crate::utils::Sample;
+use crate::{
+    clock::get_media_clock,
+    monitor::{MonitorHandle, FULL_CHROMA_PROFILE_LEVEL_ID},
+    utils::{RecoveryPointGate, Sample, TemporalLayerFilter},
+};
+
+pub const CLOCK_RATE: u32 = 90000;
+/// Payload type registered for the 4:4:4 profile, answers use the one of the offer.
+const FULL_CHROMA_PAYLOAD_TYPE: u8 = 122;
+
+/// Whether the `offer` of a client has an H.264 profile with 4:4:4 support.
+pub fn offers_full_chroma(offer: &str) -> bool {
+    let prefix = format!("profile-level-id={}", &FULL_CHROMA_PROFILE_LEVEL_ID[..2]);
+    offer.to_ascii_lowercase().contains(&prefix)
+}
+
+/// Codec to register for monitors in text mode, which is not part of the default codecs.
+pub fn full_chroma_codec() -> RTCRtpCodecParameters {
+    let feedback = |typ: &str, parameter: &str| RTCPFeedback {
+        typ: typ.to_owned(),
+        parameter: parameter.to_owned(),
+    };
+
+    RTCRtpCodecParameters {
+        capability: RTCRtpCodecCapability {
+            rtcp_feedback: vec![
+                feedback("goog-remb", ""),
+                feedback("ccm", "fir"),
+                feedback("nack", ""),
+                feedback("nack", "pli"),
+            ],
+            ..track_capability(true)
+        },
+        payload_type: FULL_CHROMA_PAYLOAD_TYPE,
+        ..Default::default()
+    }
+}
+
+/// Capability of the video track, with the 4:4:4 profile for monitors in text mode.
+pub fn track_capability(full_chroma: bool) -> RTCRtpCodecCapability {
+    let sdp_fmtp_line = if full_chroma {
+        format!(
+            "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id={}",
+            FULL_CHROMA_PROFILE_LEVEL_ID
+        )
+    } else {
+        String::new()
+    };
+
+    RTCRtpCodecCapability {
+        mime_type: MIME_TYPE_H264.to_owned(),
+        clock_rate: CLOCK_RATE,
+        sdp_fmtp_line,
+        ..Default::default()
+    }
+}
 
 pub async fn video_sender(
-    track: Arc<TrackLocalStaticSample>,
+    track: Arc<TrackLocalStaticRTP>,
     mut video_data_rx: broadcast::Receiver<Sample>,
+    monitor: MonitorHandle,
 ) {
+    let mut gate = RecoveryPointGate::default();
+    let mut layers = TemporalLayerFilter::default();
+
+    let mut packetizer = rtp::packetizer::new_packetizer(
+        1200,
+        0, // Value is handled by the track
+        0, // Value is handled by the track
+        Box::<rtp::codecs::h264::H264Payloader>::default(),
+        Box::new(rtp::sequence::new_random_sequencer()),
+        CLOCK_RATE,
+    );
+
     loop {
         match video_data_rx.recv().await {
-            Ok(sample) => {
+            Ok(mut sample) => {
+                if !gate.pass(&sample) || !layers.pass(&mut sample) {
+                    continue;
+                }
+
                 sample.record_end_to_end_latency();
 
-                let data = &sample.data[..];
-                let mut h264 = H264Reader::new(std::io::Cursor::new(data), 1024 * 1024);
-
-                while let Ok(nal) = h264.next_nal() {
-                    let res = track
-                        .write_sample(&webrtc::media::Sample {
-                            data: nal.data.freeze(),
-                            // not really used in the stack
-                            // timestamp: sample.timestamp,
-                            duration: sample.duration,
-                            ..Default::default()
-                        })
-                        .await;
-
-                    if let Err(e) = res {
-                        tracing::warn!(?e, "Failed to write video sample");
-                        break;
+                // Timed from the capture, on the clock of the audio. Sender reports are
+                // written by the interceptors from the send time (see `webrtc_task`)
+                let timestamp = get_media_clock().rtp_timestamp(sample.timestamp, CLOCK_RATE);
+                let samples = (sample.duration.as_secs_f64() * CLOCK_RATE as f64) as u32;
+
+                let nal_count = sample.nal_units.len();
+                'nals: for (i, nal) in sample.nal_units.iter().enumerate() {
+                    let packets = match packetizer.packetize(nal, samples) {
+                        Ok(packets) => packets,
+                        Err(e) => {
+                            tracing::warn!(?e, "Failed to packetize video sample");
+                            break;
+                        }
+                    };
+                    let packet_count = packets.len();
+
+                    for (j, mut packet) in packets.into_iter().enumerate() {
+                        packet.header.timestamp = timestamp;
+                        // Only the last packet of a frame carries the marker bit.
+                        packet.header.marker = i + 1 == nal_count && j + 1 == packet_count;
+
+                        if let Err(e) = track.write_rtp(&packet).await {
+                            tracing::warn!(?e, "Failed to write video sample");
+                            break 'nals;
+                        }
                     }
                 }
             }
             Err(broadcast::error::RecvError::Lagged(_)) => {
-                // Ignore lagged frames
+                // Frames were lost, drop upper layers and restart from a recovery point
+                layers.congested();
+                gate = RecoveryPointGate::default();
+                monitor.request_recovery();
             }
             Err(broadcast::error::RecvError::Closed) => {
                 break;
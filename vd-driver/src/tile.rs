@@ -0,0 +1,367 @@
+//! Lossless video codec sending the tiles of BGRA frames that changed, compressed with LZ4.
+//!
+//! A sample is `[u8 flags][u32 count]` followed by `count` tiles
+//! `[u16 x][u16 y][u16 width][u16 height][u32 len][data]`, `data` being the LZ4 block
+//! of the tile pixels as BGRA rows without padding. Bit 0 of `flags` is set on keyframes,
+//! which contain every tile. Integers are big endian.
+//!
+//! Frames can be split into bands of tile rows, each sent as soon as it is compressed.
+//! Bit 1 of `flags` is set on the last sample of a frame, which is the only one unless
+//! frames are split.
+
+use std::ops::Range;
+
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// Width and height of a tile, except at the right and bottom edges.
+pub const TILE_SIZE: u32 = 64;
+
+const FLAG_KEYFRAME: u8 = 1;
+const FLAG_END_OF_FRAME: u8 = 2;
+
+pub struct TileEncoder {
+    width: u32,
+    height: u32,
+    /// Last frame sent, without padding.
+    previous: Vec<u8>,
+    force_keyframe: bool,
+
+    tile_buf: Vec<u8>,
+    compress_buf: Vec<u8>,
+}
+
+impl TileEncoder {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            previous: vec![0; width as usize * height as usize * 4],
+            force_keyframe: true,
+            tile_buf: Vec::with_capacity((TILE_SIZE * TILE_SIZE * 4) as usize),
+            compress_buf: vec![],
+        }
+    }
+
+    /// Send every tile with the next frame.
+    pub fn request_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+
+    /// Encode a BGRA frame with rows `stride` bytes apart as up to `bands` samples of
+    /// whole tile rows, passing each one to `on_band` as soon as it is compressed,
+    /// along with whether it is part of a keyframe and whether it ends the frame.
+    ///
+    /// Bands without changed tiles are skipped, except the last one.
+    pub fn encode_bands(
+        &mut self,
+        data: &[u8],
+        stride: usize,
+        bands: u32,
+        mut on_band: impl FnMut(Bytes, bool, bool),
+    ) -> Result<()> {
+        let width = self.width as usize;
+        let height = self.height as usize;
+        if stride < width * 4 || data.len() < stride * (height.max(1) - 1) + width * 4 {
+            anyhow::bail!("Frame does not match the encoder size");
+        }
+
+        let keyframe = std::mem::take(&mut self.force_keyframe);
+
+        let tile_size = TILE_SIZE as usize;
+        let tile_rows = height.div_ceil(tile_size);
+        let band_rows = tile_rows.div_ceil(bands.max(1) as usize).max(1);
+        let band_count = tile_rows.div_ceil(band_rows).max(1);
+
+        for band in 0..band_count {
+            let last = band + 1 == band_count;
+            let rows =
+                band * band_rows * tile_size..((band + 1) * band_rows * tile_size).min(height);
+            let (sample, count) = self.encode_rows(data, stride, rows, keyframe, last)?;
+            if count > 0 || last {
+                on_band(sample, keyframe, last);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encode the tiles of the pixel rows `rows`, which start at a tile row.
+    ///
+    /// Returns the sample and the number of tiles in it.
+    fn encode_rows(
+        &mut self,
+        data: &[u8],
+        stride: usize,
+        rows: Range<usize>,
+        keyframe: bool,
+        end_of_frame: bool,
+    ) -> Result<(Bytes, u32)> {
+        let width = self.width as usize;
+
+        let mut out = BytesMut::with_capacity(if keyframe {
+            rows.len() * width * 2
+        } else {
+            4096
+        });
+        let mut flags = 0;
+        if keyframe {
+            flags |= FLAG_KEYFRAME;
+        }
+        if end_of_frame {
+            flags |= FLAG_END_OF_FRAME;
+        }
+        out.put_u8(flags);
+        // Tile count, written at the end
+        out.put_u32(0);
+
+        let tile_size = TILE_SIZE as usize;
+        let mut count = 0u32;
+        for tile_y in rows.clone().step_by(tile_size) {
+            for tile_x in (0..width).step_by(tile_size) {
+                let tile_width = tile_size.min(width - tile_x);
+                let tile_height = tile_size.min(rows.end - tile_y);
+                let row_len = tile_width * 4;
+
+                // Compare with the previous frame and update it in one pass
+                let mut dirty = keyframe;
+                for y in tile_y..tile_y + tile_height {
+                    let src = &data[y * stride + tile_x * 4..][..row_len];
+                    let prev = &mut self.previous[(y * width + tile_x) * 4..][..row_len];
+                    if src != prev {
+                        prev.copy_from_slice(src);
+                        dirty = true;
+                    }
+                }
+                if !dirty {
+                    continue;
+                }
+
+                self.tile_buf.clear();
+                for y in tile_y..tile_y + tile_height {
+                    self.tile_buf
+                        .extend_from_slice(&self.previous[(y * width + tile_x) * 4..][..row_len]);
+                }
+
+                self.compress_buf.resize(
+                    lz4_flex::block::get_maximum_output_size(self.tile_buf.len()),
+                    0,
+                );
+                let len = lz4_flex::block::compress_into(&self.tile_buf, &mut self.compress_buf)?;
+
+                out.put_u16(tile_x as u16);
+                out.put_u16(tile_y as u16);
+                out.put_u16(tile_width as u16);
+                out.put_u16(tile_height as u16);
+                out.put_u32(len as u32);
+                out.put_slice(&self.compress_buf[..len]);
+                count += 1;
+            }
+        }
+
+        out[1..5].copy_from_slice(&count.to_be_bytes());
+
+        Ok((out.freeze(), count))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::Buf;
+
+    impl TileEncoder {
+        /// Encode a whole frame as one sample, returning it and whether it is a keyframe.
+        fn encode(&mut self, data: &[u8], stride: usize) -> Result<(Bytes, bool)> {
+            let mut sample = None;
+            self.encode_bands(data, stride, 1, |data, keyframe, _| {
+                sample = Some((data, keyframe))
+            })?;
+            Ok(sample.expect("The last band is always output"))
+        }
+    }
+
+    // Three columns and two rows of tiles, the last ones smaller
+    const WIDTH: usize = TILE_SIZE as usize * 2 + 22;
+    const HEIGHT: usize = TILE_SIZE as usize + 36;
+    const STRIDE: usize = WIDTH * 4 + 32;
+
+    /// Frame with distinct pixels and garbage in the padding.
+    fn frame(seed: u8) -> Vec<u8> {
+        let mut data = vec![0xAA; STRIDE * HEIGHT];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH * 4 {
+                data[y * STRIDE + x] = (x as u8).wrapping_mul(7) ^ (y as u8) ^ seed;
+            }
+        }
+        data
+    }
+
+    fn without_padding(data: &[u8]) -> Vec<u8> {
+        data.chunks(STRIDE)
+            .flat_map(|row| &row[..WIDTH * 4])
+            .copied()
+            .collect()
+    }
+
+    /// Apply a sample to `picture`, returning whether it is a keyframe and the tile positions.
+    fn decode(mut sample: &[u8], picture: &mut [u8]) -> (bool, Vec<(usize, usize)>) {
+        let keyframe = sample.get_u8() & FLAG_KEYFRAME != 0;
+        let count = sample.get_u32();
+
+        let mut tiles = vec![];
+        for _ in 0..count {
+            let x = sample.get_u16() as usize;
+            let y = sample.get_u16() as usize;
+            let width = sample.get_u16() as usize;
+            let height = sample.get_u16() as usize;
+            let len = sample.get_u32() as usize;
+            let pixels = lz4_flex::block::decompress(&sample[..len], width * height * 4).unwrap();
+            sample.advance(len);
+
+            assert_eq!(pixels.len(), width * height * 4);
+            for (row, src) in pixels.chunks_exact(width * 4).enumerate() {
+                picture[((y + row) * WIDTH + x) * 4..][..width * 4].copy_from_slice(src);
+            }
+            tiles.push((x, y));
+        }
+        assert!(sample.is_empty());
+
+        (keyframe, tiles)
+    }
+
+    #[test]
+    fn test_keyframe() {
+        let mut encoder = TileEncoder::new(WIDTH as u32, HEIGHT as u32);
+        let data = frame(0);
+        let (sample, keyframe) = encoder.encode(&data, STRIDE).unwrap();
+        assert!(keyframe);
+
+        let mut picture = vec![0; WIDTH * HEIGHT * 4];
+        let (flagged, tiles) = decode(&sample, &mut picture);
+        assert!(flagged);
+        assert_eq!(
+            tiles,
+            [(0, 0), (64, 0), (128, 0), (0, 64), (64, 64), (128, 64)]
+        );
+        assert_eq!(picture, without_padding(&data));
+    }
+
+    #[test]
+    fn test_dirty_tiles() {
+        let mut encoder = TileEncoder::new(WIDTH as u32, HEIGHT as u32);
+        let mut data = frame(0);
+        let mut picture = vec![0; WIDTH * HEIGHT * 4];
+        decode(&encoder.encode(&data, STRIDE).unwrap().0, &mut picture);
+
+        // Same frame, only the padding changed
+        data[WIDTH * 4] ^= 0xFF;
+        let (sample, keyframe) = encoder.encode(&data, STRIDE).unwrap();
+        assert!(!keyframe);
+        assert_eq!(decode(&sample, &mut picture), (false, vec![]));
+
+        // One pixel in the top left tile and the last pixel of the bottom right tile
+        data[4 * 3 + 1] ^= 0xFF;
+        data[(HEIGHT - 1) * STRIDE + WIDTH * 4 - 1] ^= 0xFF;
+        let (sample, keyframe) = encoder.encode(&data, STRIDE).unwrap();
+        assert!(!keyframe);
+        assert_eq!(
+            decode(&sample, &mut picture),
+            (false, vec![(0, 0), (128, 64)])
+        );
+        assert_eq!(picture, without_padding(&data));
+
+        // A new frame changes every tile
+        let data = frame(1);
+        decode(&encoder.encode(&data, STRIDE).unwrap().0, &mut picture);
+        assert_eq!(picture, without_padding(&data));
+    }
+
+    #[test]
+    fn test_request_keyframe() {
+        let mut encoder = TileEncoder::new(WIDTH as u32, HEIGHT as u32);
+        let data = frame(0);
+        encoder.encode(&data, STRIDE).unwrap();
+
+        encoder.request_keyframe();
+        let (sample, keyframe) = encoder.encode(&data, STRIDE).unwrap();
+        assert!(keyframe);
+
+        // Decodes on its own
+        let mut picture = vec![0; WIDTH * HEIGHT * 4];
+        let (flagged, tiles) = decode(&sample, &mut picture);
+        assert!(flagged);
+        assert_eq!(tiles.len(), 6);
+        assert_eq!(picture, without_padding(&data));
+    }
+
+    #[test]
+    fn test_frame_size() {
+        let mut encoder = TileEncoder::new(WIDTH as u32, HEIGHT as u32);
+        let data = frame(0);
+        assert!(encoder.encode(&data, WIDTH * 4 - 4).is_err());
+        assert!(encoder
+            .encode(&data[..STRIDE * (HEIGHT - 1)], STRIDE)
+            .is_err());
+        // The padding of the last row may be missing
+        assert!(encoder
+            .encode(&data[..STRIDE * (HEIGHT - 1) + WIDTH * 4], STRIDE)
+            .is_ok());
+    }
+
+    /// Encode `data` in `bands` bands, returning the samples with their keyframe and
+    /// end-of-frame flags.
+    fn encode_bands(
+        encoder: &mut TileEncoder,
+        data: &[u8],
+        bands: u32,
+    ) -> Vec<(Bytes, bool, bool)> {
+        let mut samples = vec![];
+        encoder
+            .encode_bands(data, STRIDE, bands, |sample, keyframe, end_of_frame| {
+                assert_eq!(sample[0] & FLAG_END_OF_FRAME != 0, end_of_frame);
+                samples.push((sample, keyframe, end_of_frame));
+            })
+            .unwrap();
+        samples
+    }
+
+    #[test]
+    fn test_bands() {
+        let mut encoder = TileEncoder::new(WIDTH as u32, HEIGHT as u32);
+        let mut data = frame(0);
+        let mut picture = vec![0; WIDTH * HEIGHT * 4];
+
+        // One band per row of tiles, only the last one ends the frame
+        let samples = encode_bands(&mut encoder, &data, 2);
+        let flags: Vec<_> = samples.iter().map(|s| (s.1, s.2)).collect();
+        assert_eq!(flags, [(true, false), (true, true)]);
+        assert_eq!(
+            decode(&samples[0].0, &mut picture),
+            (true, vec![(0, 0), (64, 0), (128, 0)])
+        );
+        assert_eq!(
+            decode(&samples[1].0, &mut picture),
+            (true, vec![(0, 64), (64, 64), (128, 64)])
+        );
+        assert_eq!(picture, without_padding(&data));
+
+        // Bands without dirty tiles are skipped, except the last one
+        data[(HEIGHT - 1) * STRIDE] ^= 0xFF;
+        let samples = encode_bands(&mut encoder, &data, 2);
+        assert_eq!(samples.len(), 1);
+        assert_eq!(decode(&samples[0].0, &mut picture), (false, vec![(0, 64)]));
+
+        data[0] ^= 0xFF;
+        let samples = encode_bands(&mut encoder, &data, 2);
+        assert_eq!(samples.len(), 2);
+        assert_eq!(decode(&samples[0].0, &mut picture), (false, vec![(0, 0)]));
+        assert_eq!(decode(&samples[1].0, &mut picture), (false, vec![]));
+        assert_eq!(picture, without_padding(&data));
+
+        // No more bands than rows of tiles
+        encoder.request_keyframe();
+        assert_eq!(encode_bands(&mut encoder, &data, 5).len(), 2);
+        assert_eq!(encode_bands(&mut encoder, &data, 1).len(), 1);
+    }
+}
@@ -3,32 +3,277 @@ use std::{
     time::{Duration, Instant},
 };
 
-use dcv_color_primitives as dcp;
+use bytes::Bytes;
 
 #[derive(Debug, Clone)]
 pub struct Sample {
-    pub data: Arc<Vec<u8>>,
+    pub data: Bytes,
+    /// NAL units contained in `data`, without start codes. Empty for audio samples.
+    pub nal_units: Arc<[Bytes]>,
     pub timestamp: Instant,
     pub duration: Duration,
+    /// Whether decoding can start at this sample, i.e. it is the first sample of
+    /// an IDR frame or of a frame starting an intra refresh cycle.
+    pub recovery_point: bool,
+    /// Temporal layer of the frame, frames of upper layers can be dropped without
+    /// affecting the lower ones.
+    pub temporal_layer: u8,
+    /// Whether this is the last sample of its frame, frames of monitors with slice
+    /// output being split into several samples.
+    pub end_of_frame: bool,
 }
 
 impl Sample {
-    pub fn new(data: impl AsRef<[u8]>, timestamp: Instant, duration: Duration) -> Self {
+    pub fn new(data: Bytes, timestamp: Instant, duration: Duration) -> Self {
         Self {
-            data: Arc::new(data.as_ref().to_vec()),
+            data,
+            nal_units: Vec::new().into(),
             timestamp,
             duration,
+            recovery_point: true,
+            temporal_layer: 0,
+            end_of_frame: true,
         }
     }
 
+    /// A video sample, with `nal_units` being slices of `data`.
+    pub fn with_nal_units(
+        data: Bytes,
+        nal_units: Vec<Bytes>,
+        timestamp: Instant,
+        duration: Duration,
+    ) -> Self {
+        Self {
+            data,
+            nal_units: nal_units.into(),
+            timestamp,
+            duration,
+            recovery_point: false,
+            temporal_layer: 0,
+            end_of_frame: true,
+        }
+    }
+
+    /// Set whether decoding can start at this sample.
+    pub fn with_recovery_point(mut self, recovery_point: bool) -> Self {
+        self.recovery_point = recovery_point;
+        self
+    }
+
+    pub fn with_temporal_layer(mut self, temporal_layer: u8) -> Self {
+        self.temporal_layer = temporal_layer;
+        self
+    }
+
+    /// Set whether this is the last sample of its frame.
+    pub fn with_end_of_frame(mut self, end_of_frame: bool) -> Self {
+        self.end_of_frame = end_of_frame;
+        self
+    }
+
     pub fn record_end_to_end_latency(&self) {
         let end_to_end_latency = &crate::metrics::get_metrics().end_to_end_latency_ms;
         end_to_end_latency.observe(self.timestamp.elapsed().as_secs_f64() * 1000.0);
     }
 }
 
+/// Drops the samples a client joining a running stream cannot decode, up to the
+/// first recovery point.
+#[derive(Debug, Default)]
+pub struct RecoveryPointGate {
+    open: bool,
+}
+
+impl RecoveryPointGate {
+    /// Whether `sample` should be forwarded to the client.
+    pub fn pass(&mut self, sample: &Sample) -> bool {
+        self.open |= sample.recovery_point;
+        self.open
+    }
+}
+
+/// Time a client must keep up before the next temporal layer is forwarded again.
+const LAYER_RESTORE_DELAY: Duration = Duration::from_secs(5);
+
+/// Drops the upper temporal layers for a client that falls behind, and forwards them
+/// again once it keeps up.
+///
+/// Every dropped layer halves the frame rate, other frames cannot be dropped without
+/// breaking the decoding of the following ones. Clients asking for a lower frame rate
+/// get a rendition of the monitor instead, see `MonitorHandle::rendition`.
+#[derive(Debug)]
+pub struct TemporalLayerFilter {
+    max_layer: u8,
+    /// Highest layer seen so far.
+    top_layer: u8,
+    last_change: Instant,
+}
+
+impl Default for TemporalLayerFilter {
+    fn default() -> Self {
+        Self {
+            max_layer: u8::MAX,
+            top_layer: 0,
+            last_change: Instant::now(),
+        }
+    }
+}
+
+impl TemporalLayerFilter {
+    /// Whether `sample` should be forwarded to the client, in which case its
+    /// duration is extended to cover the frames dropped after it.
+    pub fn pass(&mut self, sample: &mut Sample) -> bool {
+        self.top_layer = self.top_layer.max(sample.temporal_layer);
+
+        if self.max_layer < self.top_layer && self.last_change.elapsed() >= LAYER_RESTORE_DELAY {
+            self.max_layer += 1;
+            self.last_change = Instant::now();
+            tracing::debug!(
+                max_layer = self.max_layer,
+                "Forwarding more temporal layers"
+            );
+        }
+
+        let max_layer = self.max_layer.min(self.top_layer);
+
+        if sample.temporal_layer > max_layer {
+            crate::metrics::get_metrics()
+                .dropped_frames
+                .with_label_values(&["temporal_layer"])
+                .inc();
+            return false;
+        }
+
+        // Frames forwarded for this one, including it
+        sample.duration *= 1 << (self.top_layer - max_layer);
+        true
+    }
+
+    /// The client fell behind, stop forwarding the highest layer it receives.
+    pub fn congested(&mut self) {
+        self.last_change = Instant::now();
+
+        let max_layer = self.max_layer.min(self.top_layer);
+        if max_layer > 0 {
+            self.max_layer = max_layer - 1;
+            tracing::debug!(max_layer = self.max_layer, "Dropping temporal layers");
+        }
+    }
+}
+
+/// Split an Annex B byte stream into NAL units without start codes, sharing the buffer.
+pub fn split_nal_units(data: &Bytes) -> Vec<Bytes> {
+    let mut nal_units = vec![];
+
+    let mut push = |start: usize, mut end: usize| {
+        // Leading zero of a 4-byte start code, or trailing zero bytes
+        while end > start && data[end - 1] == 0 {
+            end -= 1;
+        }
+        if end > start {
+            nal_units.push(data.slice(start..end));
+        }
+    };
+
+    let mut start = None;
+    for pos in memchr::memmem::find_iter(data, &[0, 0, 1]) {
+        if let Some(start) = start {
+            push(start, pos);
+        }
+        start = Some(pos + 3);
+    }
+    if let Some(start) = start {
+        push(start, data.len());
+    }
+
+    nal_units
+}
+
+pub const NAL_UNIT_TYPE_MASK: u8 = 0x1f;
+pub const NAL_UNIT_TYPE_IDR: u8 = 5;
+pub const NAL_UNIT_TYPE_SEI: u8 = 6;
+pub const NAL_UNIT_TYPE_SPS: u8 = 7;
+pub const NAL_UNIT_TYPE_PPS: u8 = 8;
+pub const NAL_UNIT_TYPE_PREFIX: u8 = 14;
+
+const SEI_RECOVERY_POINT: u32 = 6;
+
+/// Whether decoding can start at the access unit made of `nal_units`, because it
+/// contains an IDR slice or a recovery point SEI message.
+pub fn is_recovery_point(nal_units: &[Bytes]) -> bool {
+    nal_units.iter().any(|nal| {
+        let nal_type = nal[0] & NAL_UNIT_TYPE_MASK;
+        nal_type == NAL_UNIT_TYPE_IDR
+            || (nal_type == NAL_UNIT_TYPE_SEI
+                && sei_payload_types(&nal[1..]).contains(&SEI_RECOVERY_POINT))
+    })
+}
+
+/// Whether the access unit made of `nal_units` may be referenced by other frames.
+pub fn is_reference(nal_units: &[Bytes]) -> bool {
+    nal_units.iter().any(|nal| {
+        let nal_type = nal[0] & NAL_UNIT_TYPE_MASK;
+        // nal_ref_idc of coded slices
+        (1..=5).contains(&nal_type) && nal[0] & 0x60 != 0
+    })
+}
+
+/// `temporal_id` of the access unit made of `nal_units`, if the encoder signals it in
+/// SVC prefix NAL units (H.264 Annex G).
+pub fn svc_temporal_id(nal_units: &[Bytes]) -> Option<u8> {
+    nal_units.iter().find_map(|nal| {
+        if nal[0] & NAL_UNIT_TYPE_MASK != NAL_UNIT_TYPE_PREFIX || nal.len() < 4 {
+            return None;
+        }
+        // svc_extension_flag, then temporal_id in the top bits of the third byte
+        (nal[1] & 0x80 != 0).then_some(nal[3] >> 5)
+    })
+}
+
+/// Payload types of the messages in the payload of a SEI NAL unit.
+fn sei_payload_types(payload: &[u8]) -> Vec<u32> {
+    // Remove emulation prevention bytes
+    let mut rbsp = Vec::with_capacity(payload.len());
+    let mut zeros = 0;
+    for &b in payload {
+        if zeros >= 2 && b == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        rbsp.push(b);
+    }
+
+    let read_value = |pos: &mut usize| -> Option<u32> {
+        let mut value = 0u32;
+        loop {
+            let b = *rbsp.get(*pos)?;
+            *pos += 1;
+            value += b as u32;
+            if b != 0xff {
+                return Some(value);
+            }
+        }
+    };
+
+    let mut types = vec![];
+    let mut pos = 0;
+    // Stop at the RBSP trailing bits
+    while pos < rbsp.len() && rbsp[pos] != 0x80 {
+        let (payload_type, payload_size) = match (read_value(&mut pos), read_value(&mut pos)) {
+            (Some(payload_type), Some(payload_size)) => (payload_type, payload_size),
+            _ => break,
+        };
+        types.push(payload_type);
+        pos += payload_size as usize;
+    }
+
+    types
+}
+
 /// Set the thread characteristics to notify the system that this thread is
 /// a high priority thread.
+#[cfg(windows)]
 pub fn set_thread_characteristics() {
     let mut task_index = 0;
     let res = unsafe {
@@ -43,40 +288,143 @@ pub fn set_thread_characteristics() {
     }
 }
 
+/// Thread characteristics only exist on Windows.
+#[cfg(not(windows))]
+pub fn set_thread_characteristics() {}
 
+#[cfg(test)]
+mod test {
+    use super::*;
 
-pub fn bgra2nv12(
-    width: u32,
-    height: u32,
-    src: &[u8],
-    dst_stride: Option<usize>,
-    dst_y: &mut [u8],
-    dst_uv: &mut [u8],
-) -> Result<(), dcp::ErrorKind> {
-    let dcp_src_format = dcp::ImageFormat {
-        pixel_format: dcp::PixelFormat::Bgra,
-        color_space: dcp::ColorSpace::Rgb,
-        num_planes: 1,
-    };
+    /// Recovery point SEI message: recovery_frame_cnt 0, no flags.
+    const RECOVERY_POINT: [u8; 3] = [6, 1, 0x84];
 
-    let dcp_dst_format = dcp::ImageFormat {
-        pixel_format: dcp::PixelFormat::Nv12,
-        color_space: dcp::ColorSpace::Bt709,
-        num_planes: 2,
-    };
+    fn nal(data: &[u8]) -> Bytes {
+        Bytes::copy_from_slice(data)
+    }
+
+    #[test]
+    fn test_recovery_point_sei() {
+        let sei = [&[NAL_UNIT_TYPE_SEI][..], &RECOVERY_POINT, &[0x80]].concat();
+        assert!(is_recovery_point(&[
+            nal(&[0x09, 0xf0]),
+            nal(&sei),
+            nal(&[0x41, 0x9a])
+        ]));
+        assert!(is_recovery_point(&[nal(&[0x65, 0x88])]));
+        assert!(!is_recovery_point(&[nal(&[0x41, 0x9a])]));
+    }
+
+    #[test]
+    fn test_sei_emulation_prevention() {
+        // User data of 00 00 01, escaped to 00 00 03 01, then a recovery point
+        let sei = [
+            &[NAL_UNIT_TYPE_SEI, 5, 3, 0, 0, 3, 1][..],
+            &RECOVERY_POINT,
+            &[0x80],
+        ]
+        .concat();
+        assert_eq!(sei_payload_types(&sei[1..]), [5, SEI_RECOVERY_POINT]);
+        assert!(is_recovery_point(&[nal(&sei)]));
+    }
+
+    #[test]
+    fn test_sei_messages() {
+        // Picture timing, a type above 255 and a recovery point
+        let payload = [
+            &[1, 2, 0xaa, 0xbb, 0xff, 0x01, 1, 0xcc][..],
+            &RECOVERY_POINT,
+            &[0x80],
+        ]
+        .concat();
+        assert_eq!(sei_payload_types(&payload), [1, 256, SEI_RECOVERY_POINT]);
+
+        // Not a recovery point
+        let picture_timing = nal(&[NAL_UNIT_TYPE_SEI, 1, 2, 0xaa, 0xbb, 0x80]);
+        assert!(!is_recovery_point(&[picture_timing]));
+    }
+
+    #[test]
+    fn test_truncated_sei() {
+        assert_eq!(sei_payload_types(&[]), []);
+        assert_eq!(sei_payload_types(&[6]), []);
+        assert_eq!(sei_payload_types(&[0xff, 0xff]), []);
+        assert_eq!(sei_payload_types(&[6, 0xff]), []);
+        assert_eq!(sei_payload_types(&[1, 2, 0xaa, 0xbb, 6]), [1]);
+        // The payload is cut short
+        assert_eq!(sei_payload_types(&[1, 200, 0xaa]), [1]);
+        assert!(!is_recovery_point(&[nal(&[NAL_UNIT_TYPE_SEI])]));
+    }
 
-    let dst_stride = dst_stride.map(|x| [x, x]);
+    #[test]
+    fn test_svc_temporal_id() {
+        let slice = nal(&[0x41, 0x9a]);
+        // nal_ref_idc 3, svc_extension_flag, temporal_id 2
+        let prefix = nal(&[0x6e, 0xc0, 0x00, 0x47]);
+        assert_eq!(svc_temporal_id(&[prefix, slice.clone()]), Some(2));
+        let prefix = nal(&[0x6e, 0xc0, 0x00, 0x07]);
+        assert_eq!(svc_temporal_id(&[prefix, slice.clone()]), Some(0));
 
-    dcp::convert_image(
-        width,
-        height,
-        &dcp_src_format,
-        None,
-        &[src],
-        &dcp_dst_format,
-        dst_stride.as_ref().map(|x| &x[..]),
-        &mut [dst_y, dst_uv],
-    )?;
+        // MVC extension instead of SVC
+        let prefix = nal(&[0x6e, 0x40, 0x00, 0x47]);
+        assert_eq!(svc_temporal_id(&[prefix, slice.clone()]), None);
+        // Truncated
+        let prefix = nal(&[0x6e, 0xc0, 0x00]);
+        assert_eq!(svc_temporal_id(&[prefix, slice.clone()]), None);
+        assert_eq!(svc_temporal_id(&[slice]), None);
+    }
+
+    #[test]
+    fn test_is_reference() {
+        assert!(is_reference(&[nal(&[0x09, 0xf0]), nal(&[0x41, 0x9a])]));
+        assert!(is_reference(&[nal(&[0x65, 0x88])]));
+        assert!(!is_reference(&[nal(&[0x09, 0xf0]), nal(&[0x01, 0x9a])]));
+        // nal_ref_idc of non-slice NAL units does not count
+        assert!(!is_reference(&[nal(&[0x67, 0x42]), nal(&[0x01, 0x9a])]));
+    }
 
-    Ok(())
+    /// Durations of the samples of `layers` forwarded by `filter`, or `None` if dropped.
+    fn filter(filter: &mut TemporalLayerFilter, layers: &[u8]) -> Vec<Option<Duration>> {
+        layers
+            .iter()
+            .map(|&layer| {
+                let mut sample =
+                    Sample::new(Bytes::new(), Instant::now(), Duration::from_millis(10))
+                        .with_temporal_layer(layer);
+                filter.pass(&mut sample).then_some(sample.duration)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_temporal_layer_filter() {
+        crate::metrics::init_for_tests();
+        let ms = |ms| Some(Duration::from_millis(ms));
+        let mut layers = TemporalLayerFilter::default();
+        assert_eq!(
+            filter(&mut layers, &[0, 2, 1, 2]),
+            [ms(10), ms(10), ms(10), ms(10)]
+        );
+
+        // Every dropped layer doubles the duration of the forwarded frames
+        layers.congested();
+        assert_eq!(
+            filter(&mut layers, &[0, 2, 1, 2]),
+            [ms(20), None, ms(20), None]
+        );
+        layers.congested();
+        assert_eq!(
+            filter(&mut layers, &[0, 2, 1, 2]),
+            [ms(40), None, None, None]
+        );
+        layers.congested();
+        assert_eq!(filter(&mut layers, &[0, 2]), [ms(40), None]);
+
+        // Layers come back one by one once the client keeps up
+        layers.last_change -= LAYER_RESTORE_DELAY;
+        assert_eq!(
+            filter(&mut layers, &[0, 2, 1, 2]),
+            [ms(20), None, ms(20), None]
+        );
+    }
 }